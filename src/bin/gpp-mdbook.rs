@@ -0,0 +1,147 @@
+//! An mdBook preprocessor that runs every chapter of a book through gpp.
+//!
+//! mdBook first runs `gpp-mdbook supports <renderer>` to check whether the renderer is supported,
+//! which it always is as gpp only changes the chapters' Markdown. It then runs `gpp-mdbook` with
+//! the preprocessor context and the book as JSON on standard input, and reads the processed book
+//! from standard output.
+//!
+//! Markdown headings start with a hash followed by a space, so commands in a book are written
+//! without one, as in `#include example.rs`.
+
+use std::env;
+use std::error::Error;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde_json::Value;
+
+/// The settings from the `[preprocessor.gpp]` table of `book.toml` and the book's layout.
+struct Settings {
+    /// The book's source directory.
+    src: PathBuf,
+    /// The `include-paths`, resolved against the book's root.
+    include_paths: Vec<PathBuf>,
+}
+
+fn main() {
+    match env::args().nth(1).as_deref() {
+        Some("supports") => return,
+        Some(arg) => {
+            eprintln!("gpp-mdbook: unexpected argument '{}'", arg);
+            process::exit(1);
+        }
+        None => {}
+    }
+    if let Err(e) = run() {
+        eprintln!("gpp-mdbook: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let (book_context, mut book): (Value, Value) = serde_json::from_reader(io::stdin().lock())?;
+
+    let root = Path::new(book_context["root"].as_str().unwrap_or("."));
+    let config = &book_context["config"]["preprocessor"]["gpp"];
+    let settings = Settings {
+        src: root.join(
+            book_context["config"]["book"]["src"]
+                .as_str()
+                .unwrap_or("src"),
+        ),
+        include_paths: config["include-paths"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(|path| root.join(path))
+            .collect(),
+    };
+
+    let mut builder = gpp::Context::builder()
+        .exec(config["allow-exec"].as_bool().unwrap_or(false))
+        // Lines such as `#!/bin/sh` and `#[derive(Debug)]` in code blocks are left alone.
+        .pass_unknown_commands(true)
+        .pre_line(|line, context| {
+            // Lines in #raw blocks are output as they are, so they mustn't be escaped.
+            if context.state.in_raw {
+                Some(line.to_owned())
+            } else {
+                Some(escape_heading(line))
+            }
+        });
+    for (name, value) in config["defines"].as_object().into_iter().flatten() {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        builder = builder.define(name.clone(), value);
+    }
+    let mut context = builder.build()?;
+
+    // Older versions of mdBook call the book's items `sections`.
+    for key in ["items", "sections"] {
+        if let Some(items) = book.get_mut(key) {
+            process_items(items, &settings, &mut context)?;
+        }
+    }
+
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    serde_json::to_writer(&mut stdout, &book)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Processes every chapter in a list of book items, including nested chapters.
+fn process_items(
+    items: &mut Value,
+    settings: &Settings,
+    context: &mut gpp::Context,
+) -> Result<(), gpp::Error> {
+    for item in items.as_array_mut().into_iter().flatten() {
+        if let Some(chapter) = item.get_mut("Chapter") {
+            process_chapter(chapter, settings, context)?;
+            process_items(&mut chapter["sub_items"], settings, context)?;
+        }
+    }
+    Ok(())
+}
+
+fn process_chapter(
+    chapter: &mut Value,
+    settings: &Settings,
+    context: &mut gpp::Context,
+) -> Result<(), gpp::Error> {
+    // Draft chapters have no path or content.
+    let path = match chapter["source_path"].as_str().or(chapter["path"].as_str()) {
+        Some(path) => settings.src.join(path),
+        None => return Ok(()),
+    };
+    let content = match chapter["content"].as_str() {
+        Some(content) => content,
+        None => return Ok(()),
+    };
+
+    context.include_paths = path
+        .parent()
+        .map(Path::to_owned)
+        .into_iter()
+        .chain([settings.src.clone()])
+        .chain(settings.include_paths.iter().cloned())
+        .collect();
+    let output = gpp::process_buf(content.as_bytes(), &path.to_string_lossy(), context)?;
+    chapter["content"] = Value::String(output);
+    Ok(())
+}
+
+/// Doubles the first hash of a Markdown heading so that it is output as it is, instead of being
+/// treated as a command.
+fn escape_heading(line: &str) -> String {
+    let hashes = line.len() - line.trim_start_matches('#').len();
+    if hashes > 0 && line[hashes..].starts_with([' ', '\t']) {
+        format!("#{}", line)
+    } else {
+        line.to_owned()
+    }
+}
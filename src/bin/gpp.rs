@@ -1,56 +1,1735 @@
-use std::fs::File;
-use std::io::{self, BufWriter};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use clap::{App, Arg};
+use clap::{value_t, App, Arg, ErrorKind};
+use gpp::build::{make_depfile, ninja_dyndep, write_if_changed};
+use gpp::cache::Cache;
+use gpp::encoding::{process_encoded, process_file_encoded, Encoding};
+use serde::Deserialize;
 
-fn main() -> Result<(), gpp::Error> {
+/// The contents of a gpp.toml configuration file.
+///
+/// Relative paths in the file are relative to the directory containing it. Options given on the
+/// command line take precedence over the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    allow_exec: bool,
+    defines: HashMap<String, toml::Value>,
+    include_paths: Vec<PathBuf>,
+    include_aliases: BTreeMap<String, PathBuf>,
+    output: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
+    pattern: Option<String>,
+    template_extensions: Option<Vec<String>>,
+    lang: Option<String>,
+    auto_lang: bool,
+}
+
+impl Config {
+    fn load(path: &Path) -> Result<Self, gpp::Error> {
+        let mut config: Self = toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid config file {}: {}", path.display(), e),
+            )
+        })?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for path in config
+            .include_paths
+            .iter_mut()
+            .chain(config.include_aliases.values_mut())
+            .chain(&mut config.output)
+            .chain(&mut config.output_dir)
+        {
+            *path = dir.join(&*path);
+        }
+        Ok(config)
+    }
+
+    /// Search for a gpp.toml in the directory of the first input file (or the current directory if
+    /// there are no input files) and each of its ancestors.
+    fn discover(files: &[String]) -> Result<Option<PathBuf>, gpp::Error> {
+        Ok(first_input(files)?
+            .ancestors()
+            .map(|dir| dir.join("gpp.toml"))
+            .find(|path| path.is_file()))
+    }
+}
+
+/// The path of the first input file, or the current directory if there are no input files.
+fn first_input(files: &[String]) -> io::Result<PathBuf> {
+    let current_dir = env::current_dir()?;
+    Ok(
+        match files
+            .iter()
+            .find(|file| *file != "-" && !file.starts_with(':'))
+        {
+            Some(file) => current_dir.join(file),
+            None => current_dir,
+        },
+    )
+}
+
+fn process_input(
+    file: &str,
+    context: &mut gpp::Context,
+    encodings: &Encodings,
+) -> Result<Vec<u8>, gpp::Error> {
+    if let Some(text) = file.strip_prefix(':') {
+        return Ok(gpp::process_str(text, context)?.into_bytes());
+    }
+    let data = if file == "-" {
+        let name = context.stdin_name().to_owned();
+        context.encoding = encodings.for_file(&name);
+        // Standard input is only read all at once if it has to be decoded, so that the rest of
+        // it can still be included with `#include -` otherwise.
+        if context.encoding.is_none() && !is_utf16(io::stdin().lock().fill_buf()?) {
+            gpp::process_buf(StdinLines::default(), &name, context)?.into_bytes()
+        } else {
+            let mut input = Vec::new();
+            io::stdin().lock().read_to_end(&mut input)?;
+            process_encoded(&input, &name, context.encoding, context)?
+        }
+    } else {
+        context.encoding = encodings.for_file(file);
+        process_file_encoded(file, context.encoding, context)?
+    };
+    if context.dry_run {
+        return Ok(dry_run_report(file, context));
+    }
+    Ok(data)
+}
+
+/// Lists the commands that processing `name` would have run in a dry run, one per line, in place
+/// of its output.
+fn dry_run_report(name: &str, context: &mut gpp::Context) -> Vec<u8> {
+    context
+        .state
+        .dry_run_commands
+        .drain(..)
+        .map(|command| format!("{}: {}\n", name, command))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// The exit status gpp uses for an error, as documented in the help text.
+fn exit_code(error: &gpp::Error) -> i32 {
+    match error {
+        gpp::Error::FileError { error, .. } => exit_code(error),
+        gpp::Error::InvalidCommand { .. }
+        | gpp::Error::TooManyParameters { .. }
+        | gpp::Error::InvalidParameter { .. }
+        | gpp::Error::UnexpectedCommand { .. }
+        | gpp::Error::UnclosedBlock { .. }
+        | gpp::Error::InvalidExpression { .. }
+        | gpp::Error::AssertionFailed { .. } => 2,
+        gpp::Error::IoError(e) if e.kind() == io::ErrorKind::NotFound => 3,
+        gpp::Error::ChildFailed { .. }
+        | gpp::Error::PipeFailed
+        | gpp::Error::ExecOutputTooLarge { .. }
+        | gpp::Error::ChildTimeout { .. } => 4,
+        gpp::Error::ExecDisabled { .. } => 5,
+        _ => 1,
+    }
+}
+
+/// Tracks the inputs that failed to process.
+///
+/// Unless `keep_going` is set, the first failure aborts the run. Otherwise each error is reported
+/// to standard error and the input's output is skipped, and the run fails once all inputs have been
+/// processed.
+struct Failures {
+    keep_going: bool,
+    count: usize,
+    /// The number of errors that were replaced by --error-marker.
+    recovered: usize,
+    exit_code: i32,
+}
+
+impl Failures {
+    fn check<T>(
+        &mut self,
+        name: &str,
+        result: Result<T, gpp::Error>,
+    ) -> Result<Option<T>, gpp::Error> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if self.keep_going => {
+                if JSON_ERRORS.load(Ordering::Relaxed) {
+                    eprintln!("{}", error_json(&e, Some(name)));
+                } else {
+                    eprintln!("Error processing {}: {}", name, e);
+                }
+                if self.count == 0 {
+                    self.exit_code = exit_code(&e);
+                }
+                self.count += 1;
+                Ok(None)
+            }
+            Err(e) => {
+                *FAILED_INPUT.lock().unwrap_or_else(PoisonError::into_inner) =
+                    Some(name.to_owned());
+                Err(e)
+            }
+        }
+    }
+
+    /// Prints the errors that were replaced by --error-marker while processing an input, so that
+    /// gpp still fails once every input has been written.
+    fn recovered(&mut self, context: &mut gpp::Context) {
+        for e in context.state.recovered_errors.drain(..) {
+            report_error(&e, None);
+            if self.count == 0 && self.recovered == 0 {
+                self.exit_code = exit_code(&e);
+            }
+            self.recovered += 1;
+        }
+    }
+}
+
+/// The statistics of each input, which are printed at the end of the run with --stats.
+#[derive(Default)]
+struct Summary {
+    inputs: Vec<(String, gpp::Stats)>,
+}
+
+impl Summary {
+    /// Records the statistics collected in a context for an input, and resets them for the next
+    /// input. Nothing is recorded if the context isn't collecting statistics.
+    fn add(&mut self, name: &str, context: &mut gpp::Context) {
+        if let Some(stats) = context.stats.as_mut().map(mem::take) {
+            self.inputs.push((name.to_owned(), stats));
+        }
+    }
+    /// Prints a line for each input and a line with the totals to stderr.
+    fn print(&self, elapsed: Duration) {
+        let width = self
+            .inputs
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        eprintln!(
+            "{:width$}  {:>8}  {:>10}  {:>9}  {:>10}",
+            "FILE",
+            "INCLUDES",
+            "EXPANSIONS",
+            "PROCESSES",
+            "TIME",
+            width = width
+        );
+        let mut total = gpp::Stats::default();
+        for (name, stats) in &self.inputs {
+            // The input itself is the last file to finish processing.
+            let time = stats
+                .files
+                .last()
+                .map_or(Duration::ZERO, |file| file.elapsed);
+            eprintln!(
+                "{:width$}  {:>8}  {:>10}  {:>9}  {:>10}",
+                name,
+                stats.includes,
+                stats.expansions,
+                stats.child_processes,
+                format!("{:.2?}", time),
+                width = width
+            );
+            total.includes += stats.includes;
+            total.expansions += stats.expansions;
+            total.child_processes += stats.child_processes;
+        }
+        eprintln!(
+            "total: {} file(s), {} include(s), {} expansion(s), {} child process(es), {:.2?} wall time",
+            self.inputs.len(),
+            total.includes,
+            total.expansions,
+            total.child_processes,
+            elapsed
+        );
+    }
+}
+
+/// The fingerprint of each output, which are written at the end of the run with --fingerprint in
+/// the format of `sha256sum`.
+struct Fingerprints {
+    path: Option<PathBuf>,
+    lines: String,
+}
+
+impl Fingerprints {
+    fn add(&mut self, name: &str, output: &[u8]) {
+        if self.path.is_some() {
+            self.lines += &format!("{}  {}\n", gpp::cache::fingerprint(output), name);
+        }
+    }
+    /// Records the fingerprint of an output file that has been written.
+    fn add_file(&mut self, path: &Path) -> io::Result<()> {
+        if self.path.is_some() {
+            self.add(&path.display().to_string(), &fs::read(path)?);
+        }
+        Ok(())
+    }
+    /// Writes the fingerprints to their file, or to stdout if it is `-`.
+    fn write(&self) -> io::Result<()> {
+        match self.path.as_deref() {
+            Some(path) if path == Path::new("-") => io::stdout().write_all(self.lines.as_bytes()),
+            Some(path) => fs::write(path, &self.lines),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The files that each output was generated from, which are written at the end of the run with
+/// --depfile.
+struct Depfile {
+    path: Option<PathBuf>,
+    format: DepFormat,
+    rules: Vec<(PathBuf, Vec<PathBuf>)>,
+}
+
+impl Depfile {
+    /// Records the dependencies of a context as dependencies of `target`, adding them to its rule
+    /// if it already has one.
+    fn add(&mut self, target: &Path, context: &gpp::Context) {
+        if self.path.is_none() {
+            return;
+        }
+        let index = match self.rules.iter().position(|(rule, _)| rule == target) {
+            Some(index) => index,
+            None => {
+                self.rules.push((target.to_owned(), Vec::new()));
+                self.rules.len() - 1
+            }
+        };
+        let dependencies = &mut self.rules[index].1;
+        for dependency in &context.state.dependencies {
+            if !dependencies.contains(dependency) {
+                dependencies.push(dependency.clone());
+            }
+        }
+    }
+    fn write(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let contents = match self.format {
+            DepFormat::Ninja if self.rules.len() > 1 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a ninja depfile can only list the dependencies of one output; use --dep-format dyndep for more",
+                ))
+            }
+            DepFormat::Make | DepFormat::Ninja => self
+                .rules
+                .iter()
+                .map(|(target, dependencies)| make_depfile(target, dependencies))
+                .collect(),
+            DepFormat::Dyndep => ninja_dyndep(&self.rules),
+        };
+        fs::write(path, contents)
+    }
+}
+
+/// The format of the file written by --depfile.
+#[derive(Clone, Copy)]
+enum DepFormat {
+    /// Makefile rules.
+    Make,
+    /// A depfile for a single ninja build statement, which is a Makefile rule.
+    Ninja,
+    /// A ninja dyndep file.
+    Dyndep,
+}
+
+/// Everything recorded about the inputs as they are processed, which is reported at the end of
+/// the run.
+struct Reports {
+    summary: Summary,
+    fingerprints: Fingerprints,
+    depfile: Depfile,
+}
+
+/// Run `task` on every item on a pool of `jobs` threads, passing the items and their results to
+/// `handle` in input order as soon as they are available.
+fn run_parallel<T: Sync, R: Send>(
+    items: &[T],
+    jobs: usize,
+    task: impl Fn(&T) -> R + Sync,
+    mut handle: impl FnMut(&T, R) -> Result<(), gpp::Error>,
+) -> Result<(), gpp::Error> {
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            let sender = sender.clone();
+            let (next, task) = (&next, &task);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let item = match items.get(index) {
+                    Some(item) => item,
+                    None => break,
+                };
+                if sender.send((index, task(item))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut handled = 0;
+        for (index, result) in receiver {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&handled) {
+                handle(&items[handled], result)?;
+                handled += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Whether a file name matches a pattern, where `*` matches any sequence of characters and `?`
+/// matches any single character.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Read the inputs listed in a file, or stdin if it is `-`, one per line or separated by NUL bytes
+/// if `null` is set. Empty entries are skipped, and names are used exactly as they are written
+/// rather than being expanded as glob patterns.
+fn read_file_list(list: &str, null: bool) -> io::Result<Vec<String>> {
+    let contents = if list == "-" {
+        let mut contents = Vec::new();
+        io::stdin().lock().read_to_end(&mut contents)?;
+        contents
+    } else {
+        fs::read(list)?
+    };
+    let contents =
+        String::from_utf8(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let entries: Vec<&str> = if null {
+        contents.split('\0').collect()
+    } else {
+        contents.lines().collect()
+    };
+    Ok(entries
+        .into_iter()
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Whether an input should be expanded as a glob pattern.
+fn is_glob(file: &str) -> bool {
+    !file.starts_with(':') && file.contains(['*', '?'])
+}
+
+fn or_current_dir(path: &Path) -> &Path {
+    if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    }
+}
+
+/// Expand a glob pattern into the sorted list of files matching it. Each path component is matched
+/// with `matches_pattern`, and a component of `**` matches any number of directories. As in most
+/// shells, wildcards do not match hidden files unless the component itself starts with a dot.
+fn expand_glob(pattern: &str) -> Result<Vec<String>, gpp::Error> {
+    let mut paths = vec![PathBuf::new()];
+
+    for component in Path::new(pattern).components() {
+        let component_str = component.as_os_str().to_string_lossy();
+        let mut next = Vec::new();
+
+        for base in paths {
+            if !is_glob(&component_str) {
+                next.push(base.join(component));
+                continue;
+            }
+            if !or_current_dir(&base).is_dir() {
+                continue;
+            }
+            if component_str == "**" {
+                let mut dirs = vec![base];
+                let mut i = 0;
+                while i < dirs.len() {
+                    for entry in fs::read_dir(or_current_dir(&dirs[i]))? {
+                        let entry = entry?;
+                        if entry.file_type()?.is_dir()
+                            && !entry.file_name().to_string_lossy().starts_with('.')
+                        {
+                            dirs.push(dirs[i].join(entry.file_name()));
+                        }
+                    }
+                    i += 1;
+                }
+                next.extend(dirs);
+            } else {
+                for entry in fs::read_dir(or_current_dir(&base))? {
+                    let name = entry?.file_name();
+                    let name_str = name.to_string_lossy();
+                    if (!name_str.starts_with('.') || component_str.starts_with('.'))
+                        && matches_pattern(&component_str, &name_str)
+                    {
+                        next.push(base.join(&name));
+                    }
+                }
+            }
+        }
+        paths = next;
+    }
+
+    let mut files: Vec<String> = paths
+        .into_iter()
+        .filter(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    files.dedup();
+
+    if files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no files match the pattern '{}'", pattern),
+        )
+        .into());
+    }
+    Ok(files)
+}
+
+/// Recursively collect every file under `dir` whose name matches `pattern`, in sorted order.
+/// Symlinks to directories aren't followed, as they could form a cycle.
+fn walk_dir(dir: &Path, pattern: &str, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (path, file_type) in entries {
+        if file_type.is_dir() {
+            walk_dir(&path, pattern, files)?;
+        } else if file_type.is_symlink() && path.is_dir() {
+            continue;
+        } else if path
+            .file_name()
+            .is_some_and(|name| matches_pattern(pattern, &name.to_string_lossy()))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether input starts with a UTF-16 byte order mark.
+fn is_utf16(input: &[u8]) -> bool {
+    Encoding::for_bom(input).is_some_and(|(encoding, _)| encoding != encoding_rs::UTF_8)
+}
+
+/// Standard input, read one line at a time. It is only locked while a line is read, so that
+/// `#include -` can read the rest of it while it is being processed.
+#[derive(Default)]
+struct StdinLines {
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for StdinLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for StdinLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.line.len() {
+            self.line.clear();
+            self.pos = 0;
+            io::stdin().lock().read_until(b'\n', &mut self.line)?;
+        }
+        Ok(&self.line[self.pos..])
+    }
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// The encodings given with --encoding.
+#[derive(Default)]
+struct Encodings {
+    /// The encodings of inputs whose file names match a pattern, checked in order.
+    patterns: Vec<(String, &'static Encoding)>,
+    /// The encoding of inputs that don't match any pattern.
+    default: Option<&'static Encoding>,
+}
+
+impl Encodings {
+    /// Parses the values of --encoding, which are each an encoding or `PATTERN=ENCODING`.
+    fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut encodings = Self::default();
+        for value in values {
+            let (pattern, label) = match value.rsplit_once('=') {
+                Some((pattern, label)) => (Some(pattern), label),
+                None => (None, value),
+            };
+            let encoding = Encoding::for_label(label.as_bytes()).unwrap_or_else(|| {
+                clap::Error::with_description(
+                    &format!("unknown encoding '{}'", label),
+                    ErrorKind::InvalidValue,
+                )
+                .exit()
+            });
+            match pattern {
+                Some(pattern) => encodings.patterns.push((pattern.to_owned(), encoding)),
+                None => encodings.default = Some(encoding),
+            }
+        }
+        encodings
+    }
+
+    /// The encoding given for an input, or `None` if it is UTF-8 unless it has a byte order mark.
+    fn for_file(&self, file: &str) -> Option<&'static Encoding> {
+        let path = Path::new(file);
+        let name = path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy();
+        self.patterns
+            .iter()
+            .find(|(pattern, _)| matches_pattern(pattern, &name))
+            .map(|&(_, encoding)| encoding)
+            .or(self.default)
+    }
+}
+
+/// How files are read and written when each input has its own output file.
+struct FileOptions {
+    encodings: Encodings,
+    if_changed: bool,
+    cache: Option<Cache>,
+    auto_lang: Option<AutoLang>,
+    #[cfg(feature = "git")]
+    git_macros: Option<GitMacros>,
+}
+
+impl FileOptions {
+    /// Applies the settings that depend on the input to the context it will be processed with.
+    fn prepare(&self, input: &Path, context: &mut gpp::Context) -> Result<(), gpp::Error> {
+        if let Some(auto_lang) = &self.auto_lang {
+            auto_lang.apply(input, context);
+        }
+        #[cfg(feature = "git")]
+        if let Some(git_macros) = &self.git_macros {
+            git_macros.apply(input, context)?;
+        }
+        Ok(())
+    }
+}
+
+/// The settings for choosing the language of each input with --auto-lang.
+struct AutoLang {
+    /// The extensions to look past to find an input's language, so that `page.html.gpp` is HTML.
+    template_exts: Vec<String>,
+    /// The command delimiters used for inputs in languages without a preset.
+    default: Option<gpp::Delimiters>,
+}
+
+impl AutoLang {
+    /// Sets the command delimiters of a context to those of the language of `input`.
+    fn apply(&self, input: &Path, context: &mut gpp::Context) {
+        context.command_delimiters =
+            match gpp::lang::from_path(strip_template_ext(input, &self.template_exts)) {
+                Some(language) => Some(language.command_delimiters()),
+                None => self.default.clone(),
+            };
+    }
+}
+
+/// The macros describing the git repository of each input with --git-macros, remembered for each
+/// directory so that git is only run once per directory.
+#[cfg(feature = "git")]
+#[derive(Default)]
+struct GitMacros(std::sync::Mutex<HashMap<PathBuf, HashMap<String, String>>>);
+
+#[cfg(feature = "git")]
+impl GitMacros {
+    const NAMES: [&'static str; 4] = [
+        "__GIT_HASH__",
+        "__GIT_SHORT_HASH__",
+        "__GIT_BRANCH__",
+        "__GIT_DIRTY__",
+    ];
+
+    /// Defines the macros of the repository containing `input` in a context, replacing those of
+    /// the repository of any previous input. Standard input and strings use the current directory.
+    fn apply(&self, input: &Path, context: &mut gpp::Context) -> Result<(), gpp::Error> {
+        let dir = match input.to_str() {
+            Some(input) if input == "-" || input.starts_with(':') => Path::new("."),
+            _ => match input.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            },
+        };
+        let mut cache = self.0.lock().unwrap();
+        let macros = match cache.get(dir) {
+            Some(macros) => macros,
+            None => {
+                let macros = gpp::git::git_macros(dir)?;
+                cache.entry(dir.to_owned()).or_insert(macros)
+            }
+        };
+        for name in Self::NAMES {
+            context.macros.remove(name);
+        }
+        context.macros.extend(
+            macros
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        Ok(())
+    }
+}
+
+/// Preprocess a single file, writing the result to `output` and creating its parent directories if
+/// necessary. The file is skipped if the cache shows that its output is up to date. In a dry run,
+/// nothing is written and the report of commands that would have run is returned instead.
+fn process_to_file(
+    input: &Path,
+    output: &Path,
+    context: &mut gpp::Context,
+    options: &FileOptions,
+) -> Result<Vec<u8>, gpp::Error> {
+    let filename = input.to_string_lossy();
+    context.encoding = options.encodings.for_file(&filename);
+    let entry = match &options.cache {
+        Some(cache) if !context.dry_run => match cache.check(input, output, context) {
+            Some(entry) => Some(entry),
+            None => return Ok(Vec::new()),
+        },
+        _ => None,
+    };
+    let data = process_file_encoded(&filename, context.encoding, context)?;
+    if context.dry_run {
+        return Ok(dry_run_report(&filename, context));
+    }
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if options.if_changed {
+        write_if_changed(output, data)?;
+    } else {
+        fs::write(output, data)?;
+    }
+    if let Some(entry) = entry {
+        entry.record(context)?;
+    }
+    Ok(Vec::new())
+}
+
+/// An output file that is written to a temporary file next to it, which only replaces it once
+/// all of the output has been written. This stops a failed run from leaving a half-written
+/// output behind. The temporary file is removed if this is dropped before it is committed.
+struct AtomicFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: Option<BufWriter<File>>,
+}
+
+impl AtomicFile {
+    fn create(path: &Path) -> io::Result<Self> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            temp_path,
+            file: Some(BufWriter::new(file)),
+        })
+    }
+    /// Replaces the output file with everything that has been written. If `if_changed` is set
+    /// and the output file already has the same contents, it is left alone instead.
+    fn commit(mut self, if_changed: bool) -> io::Result<()> {
+        self.flush()?;
+        if if_changed
+            && fs::read(&self.path)
+                .is_ok_and(|old| fs::read(&self.temp_path).is_ok_and(|new| new == old))
+        {
+            return Ok(());
+        }
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            fs::set_permissions(&self.temp_path, metadata.permissions())?;
+        }
+        // The file is closed before it is renamed, as open files can't be renamed on Windows.
+        self.file = None;
+        fs::rename(&self.temp_path, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&self.temp_path);
+        })
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Find every file under each of the directories whose name matches `pattern`, along with the path
+/// that mirrors its path in the directory under `output_dir`.
+fn recursive_tasks(
+    dirs: &[String],
+    output_dir: &Path,
+    pattern: &str,
+    template_exts: &[impl AsRef<str>],
+) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut tasks = Vec::new();
+    for dir in dirs {
+        let mut files = Vec::new();
+        walk_dir(Path::new(dir), pattern, &mut files)?;
+        tasks.extend(files.into_iter().map(|file| {
+            let relative = file.strip_prefix(dir).unwrap();
+            let output = output_dir
+                .join(relative.parent().unwrap_or(Path::new("")))
+                .join(strip_template_ext(relative, template_exts));
+            (file, output)
+        }));
+    }
+    Ok(tasks)
+}
+
+/// The file name of an input with its template extension removed if it has one, so that
+/// `page.html.gpp` is written to `page.html`.
+fn strip_template_ext(input: &Path, template_exts: &[impl AsRef<str>]) -> PathBuf {
+    let name = input.file_name().unwrap_or_default();
+    match (input.file_stem(), input.extension()) {
+        (Some(stem), Some(ext))
+            if template_exts.iter().any(|template_ext| {
+                ext.eq_ignore_ascii_case(template_ext.as_ref().trim_start_matches('.'))
+            }) =>
+        {
+            PathBuf::from(stem)
+        }
+        _ => PathBuf::from(name),
+    }
+}
+
+/// The output path for an input given an --output-pattern, in which `{dir}`, `{name}`, `{stem}`
+/// and `{ext}` are replaced by the input's directory, file name, file name without its extension
+/// and extension.
+fn pattern_output(pattern: &str, input: &Path) -> PathBuf {
+    let dir = or_current_dir(input.parent().unwrap_or(Path::new(""))).to_string_lossy();
+    let name = input.file_name().unwrap_or_default().to_string_lossy();
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = input.extension().unwrap_or_default().to_string_lossy();
+    let placeholders = [
+        ("{dir}", dir),
+        ("{name}", name),
+        ("{stem}", stem),
+        ("{ext}", ext),
+    ];
+
+    let mut output = String::new();
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        match placeholders.iter().find(|(p, _)| rest.starts_with(p)) {
+            Some((placeholder, value)) => {
+                output.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                output.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    PathBuf::from(output)
+}
+
+/// Exit with an error if two inputs would be written to the same output, or an input would be
+/// overwritten by its own output.
+fn check_outputs(tasks: &[(PathBuf, PathBuf)]) {
+    // Paths are compared with their directories resolved, so that `a.txt` and `./a.txt` are the
+    // same file.
+    let resolve = |path: &Path| {
+        let dir = or_current_dir(path.parent().unwrap_or(Path::new("")));
+        match (fs::canonicalize(dir), path.file_name()) {
+            (Ok(dir), Some(name)) => dir.join(name),
+            _ => path.to_owned(),
+        }
+    };
+    let mut outputs = HashMap::new();
+    for (input, output) in tasks {
+        let resolved = resolve(output);
+        let message = if resolved == resolve(input) {
+            format!("{} would be overwritten by its own output", input.display())
+        } else if let Some(other) = outputs.insert(resolved, input) {
+            format!(
+                "{} and {} would both be written to {}",
+                other.display(),
+                input.display(),
+                output.display()
+            )
+        } else {
+            continue;
+        };
+        clap::Error::with_description(&message, ErrorKind::InvalidValue).exit();
+    }
+}
+
+/// Preprocess each input into its own output file, each with its own context.
+fn process_to_files(
+    tasks: &[(PathBuf, PathBuf)],
+    jobs: usize,
+    settings: &gpp::Context,
+    options: &FileOptions,
+    failures: &mut Failures,
+    reports: &mut Reports,
+) -> Result<(), gpp::Error> {
+    run_parallel(
+        tasks,
+        jobs.max(1),
+        |(input, output)| {
+            let mut context = settings.fork();
+            let result = options
+                .prepare(input, &mut context)
+                .and_then(|()| process_to_file(input, output, &mut context, options));
+            (result, context)
+        },
+        |(input, output), (result, mut context)| {
+            let name = input.display().to_string();
+            reports.summary.add(&name, &mut context);
+            failures.recovered(&mut context);
+            if let Some(report) = failures.check(&name, result)? {
+                io::stdout().write_all(&report)?;
+                if !context.dry_run {
+                    reports.fingerprints.add_file(output)?;
+                }
+                reports.depfile.add(output, &context);
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Kills the children of #exec and #in commands when gpp is interrupted, so that they don't keep
+/// running or hold on to the terminal after gpp exits.
+fn handle_interrupts() {
+    let result = ctrlc::set_handler(|| {
+        for id in gpp::running_children() {
+            kill_child(id);
+        }
+        // The conventional status for a program killed by SIGINT.
+        process::exit(130);
+    });
+    if let Err(e) = result {
+        eprintln!("Warning: failed to handle interrupts: {}", e);
+    }
+}
+
+/// Kills a child and every process it started, found by asking ps for the parent of every process.
+#[cfg(unix)]
+fn kill_child(id: u32) {
+    let mut tree = vec![id];
+    if let Ok(output) = process::Command::new("ps")
+        .args(["-A", "-o", "pid=", "-o", "ppid="])
+        .stderr(process::Stdio::null())
+        .output()
+    {
+        let processes: Vec<(u32, u32)> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut ids = line.split_whitespace().map(|id| id.parse().ok());
+                Some((ids.next()??, ids.next()??))
+            })
+            .collect();
+        let mut i = 0;
+        while let Some(&parent) = tree.get(i) {
+            tree.extend(
+                processes
+                    .iter()
+                    .filter(|&&(_, ppid)| ppid == parent)
+                    .map(|&(pid, _)| pid),
+            );
+            i += 1;
+        }
+    }
+    for id in tree {
+        // SAFETY: kill has no memory safety requirements.
+        unsafe {
+            libc::kill(id as libc::pid_t, libc::SIGTERM);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn kill_child(id: u32) {
+    // /T kills the processes the child started as well, such as the programs that cmd runs.
+    let _ = process::Command::new("taskkill")
+        .args(["/F", "/T", "/PID", &id.to_string()])
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status();
+}
+
+#[cfg(not(any(unix, windows)))]
+fn kill_child(_id: u32) {}
+
+fn main() {
+    handle_interrupts();
+    if let Err(e) = run() {
+        let input = FAILED_INPUT.lock().unwrap_or_else(PoisonError::into_inner);
+        report_error(&e, input.as_deref());
+        process::exit(exit_code(&e));
+    }
+}
+
+/// Whether errors are printed as JSON, which is set by --error-format json.
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// The input whose error stopped the run, for errors that don't name a file.
+static FAILED_INPUT: Mutex<Option<String>> = Mutex::new(None);
+
+/// Prints an error. `input` is the input that was being processed, if it is known.
+fn report_error(error: &gpp::Error, input: Option<&str>) {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        eprintln!("{}", error_json(error, input));
+    } else {
+        print_error(error);
+    }
+}
+
+/// Describes an error as a single line of JSON for --error-format json. `input` is the input that
+/// was being processed, which is used as the file if the error doesn't name one.
+///
+/// Lines count from 1. gpp reports errors for whole lines, so the column is always 1.
+fn error_json(error: &gpp::Error, input: Option<&str>) -> String {
+    let mut locations = Vec::new();
+    let mut inner = error;
+    while let gpp::Error::FileError {
+        filename,
+        line,
+        error,
+    } = inner
+    {
+        locations.push(serde_json::json!({ "file": filename.to_string_lossy(), "line": line + 1 }));
+        inner = error;
+    }
+    let (file, line) = match locations.pop() {
+        Some(location) => (location["file"].clone(), location["line"].clone()),
+        None => (input.into(), serde_json::Value::Null),
+    };
+    serde_json::json!({
+        "code": inner.code(),
+        "message": inner.to_string(),
+        "file": file,
+        "line": line,
+        "column": if line.is_null() { serde_json::Value::Null } else { 1.into() },
+        "include_chain": locations,
+    })
+    .to_string()
+}
+
+#[cfg(not(feature = "miette"))]
+fn print_error(error: &gpp::Error) {
+    eprintln!("Error: {}", error);
+}
+
+/// Prints an error with the lines of the files that caused it.
+#[cfg(feature = "miette")]
+fn print_error(error: &gpp::Error) {
+    let diagnostic =
+        gpp::diagnostic::SourceDiagnostic::new(error, |filename| fs::read_to_string(filename).ok());
+    let mut report = String::new();
+    match miette::GraphicalReportHandler::new().render_report(&mut report, &diagnostic) {
+        Ok(()) => eprint!("{}", report),
+        Err(_) => eprintln!("Error: {}", error),
+    }
+}
+
+fn run() -> Result<(), gpp::Error> {
     let matches = App::new("gpp")
         .version("0.6.2")
         .about("A Generic PreProcessor.")
         .author("Kestrer")
+        .after_help("EXIT STATUS:
+    0    Success
+    1    Any other error, such as an I/O or UTF-8 error
+    2    Invalid or malformed command
+    3    A file, such as an input or an #include, was not found
+    4    A child process for #exec or #in failed
+    5    #exec or #in was used without --allow-exec
+
+ENVIRONMENT:
+    SOURCE_DATE_EPOCH    A Unix timestamp for #date to output instead of the current time")
         .arg(Arg::with_name("allow_exec")
-            .help("Whether #exec and #in commands are allowed")
+            .help("Whether #exec and #in commands, and #include !command, are allowed")
             .short("-e")
             .long("--allow-exec")
         )
+        .arg(Arg::with_name("expand_exec")
+            .help("Expand macros in the commands run by #exec and #in. Only use this if every macro's value is trusted, as it can inject shell commands.")
+            .long("--expand-exec")
+        )
+        .arg(Arg::with_name("max_exec_output")
+            .help("The most bytes that the command run by an #exec or #in can output. A command that outputs more is killed, and processing fails.")
+            .long("--max-exec-output")
+            .takes_value(true)
+            .value_name("BYTES")
+        )
+        .arg(Arg::with_name("in_timeout")
+            .help("How long an #endin waits for its command to finish after the block's input has been written. A command that takes longer is killed, and processing fails.")
+            .long("--in-timeout")
+            .takes_value(true)
+            .value_name("SECONDS")
+        )
+        .arg(Arg::with_name("windows_shell")
+            .help("The shell that #exec and #in commands are run with on Windows. powershell is the Windows PowerShell that comes with Windows, and pwsh is PowerShell 7 and later. Other platforms always use sh.")
+            .long("--windows-shell")
+            .takes_value(true)
+            .possible_values(&["cmd", "powershell", "pwsh"])
+        )
+        .arg(Arg::with_name("c_compat")
+            .help("Process files written for the C preprocessor, with #include <file> searching the include paths and #if expressions evaluated as C does")
+            .long("--c-compat")
+        )
+        .arg(Arg::with_name("lang")
+            .help("Write commands in the comments of a language, such as html (<!--#include file -->), css, c (//#include file), python, shell, sql or tex. A file extension such as md can be given instead of the language's name.")
+            .long("--lang")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("auto_lang")
+            .help("Choose the language of each input from its extension, as with --lang, looking past template extensions such as .gpp and .in. Inputs in other languages use --lang, or the default of commands starting with #.")
+            .long("--auto-lang")
+        )
+        .arg(Arg::with_name("git_macros")
+            .help("Define __GIT_HASH__, __GIT_SHORT_HASH__, __GIT_BRANCH__ and __GIT_DIRTY__ from the git repository containing each input file")
+            .long("--git-macros")
+            .hidden(!cfg!(feature = "git"))
+        )
+        .arg(Arg::with_name("define")
+            .help("Define a macro, as NAME or NAME=VALUE.")
+            .short("-D")
+            .long("--define")
+            .takes_value(true)
+            .number_of_values(1)
+            .multiple(true)
+        )
+        .arg(Arg::with_name("include_path")
+            .help("Add a directory to search for #included files that aren't found in the current directory.")
+            .short("-I")
+            .long("--include-path")
+            .takes_value(true)
+            .number_of_values(1)
+            .multiple(true)
+        )
+        .arg(Arg::with_name("include_alias")
+            .help("Let #included paths start with NAME to refer to DIR, for example --include-alias @root=. to include @root/header.txt from anywhere.")
+            .long("--include-alias")
+            .takes_value(true)
+            .value_name("NAME=DIR")
+            .number_of_values(1)
+            .multiple(true)
+        )
+        .arg(Arg::with_name("config")
+            .help("The configuration file to use. Defaults to the first gpp.toml found in the directory of the first input file or any of its parents.")
+            .short("-c")
+            .long("--config")
+            .takes_value(true)
+        )
         .arg(Arg::with_name("files")
-            .help("The files to preprocess. - means stdin, and any 'filename' starting with a colon is treated as a literal string to preprocess. If no files are given, it will default to stdin.")
+            .help("The files to preprocess. - means stdin, and any 'filename' starting with a colon is treated as a literal string to preprocess. Filenames containing * or ? are expanded as glob patterns, where ** matches any number of directories, and the matches are processed in sorted order. If no files are given, it will default to stdin.")
             .default_value("-")
             .multiple(true)
         )
+        .arg(Arg::with_name("files_from")
+            .help("Read more inputs from this file, or from stdin if it is -, one per line. The names are used as they are written, without glob expansion. If no other files are given, stdin isn't used as an input.")
+            .long("--files-from")
+            .takes_value(true)
+            .value_name("FILE")
+        )
+        .arg(Arg::with_name("null")
+            .help("Separate the inputs in the --files-from list by NUL bytes instead of newlines, as output by find -print0.")
+            .short("-0")
+            .long("--null")
+            .requires("files_from")
+        )
         .arg(Arg::with_name("output")
-            .help("The output file. Defaults to stdout.")
+            .help("The output file. Defaults to stdout. The output is written to a temporary file next to it, which only replaces it once every input has been processed.")
             .short("-o")
             .long("--output")
             .takes_value(true)
         )
+        .arg(Arg::with_name("output_pattern")
+            .help("Write each input to its own output file, named by this pattern. {dir}, {name}, {stem} and {ext} are replaced by the input's directory, file name, file name without its extension, and extension, so {dir}/{stem}.html writes docs/index.md to docs/index.html. Each input is processed with its own context.")
+            .long("--output-pattern")
+            .takes_value(true)
+            .conflicts_with_all(&["output", "recursive"])
+        )
+        .arg(Arg::with_name("jobs")
+            .help("The number of files to process in parallel. When greater than 1, each file is processed with its own context, so macros do not carry over between files.")
+            .short("-j")
+            .long("--jobs")
+            .takes_value(true)
+            .default_value("1")
+        )
+        .arg(Arg::with_name("recursive")
+            .help("Treat the inputs as directories, and preprocess every file in them matching --pattern into --output-dir, mirroring the directory structure. Each file is processed with its own context.")
+            .short("-r")
+            .long("--recursive")
+            .conflicts_with("output")
+        )
+        .arg(Arg::with_name("output_dir")
+            .help("The directory to write the output files to. Each input is processed with its own context and written to a file in this directory with the same name, less any template extension such as .gpp or .in, so page.html.gpp is written to page.html. In recursive mode, the directory structure of the inputs is mirrored under it.")
+            .long("--output-dir")
+            .takes_value(true)
+            .conflicts_with_all(&["output", "output_pattern"])
+        )
+        .arg(Arg::with_name("template_ext")
+            .help("An extension that is removed from the names of inputs to name their output files with --output-dir, instead of gpp and in. It can be given more than once.")
+            .long("--template-ext")
+            .takes_value(true)
+            .value_name("EXT")
+            .number_of_values(1)
+            .multiple(true)
+        )
+        .arg(Arg::with_name("pattern")
+            .help("The pattern that file names must match to be preprocessed in recursive mode. * matches any sequence of characters and ? matches any single character. Defaults to *.")
+            .long("--pattern")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("encoding")
+            .help("The encoding of the input files, such as utf-16le, shift_jis or latin1, or PATTERN=ENCODING to only use it for inputs whose file names match PATTERN, such as *.txt=shift_jis. It can be given more than once, in which case the first matching pattern is used. The output is written in the same encoding, and files that an input #includes are read in it too. Files that start with a byte order mark use the encoding it indicates instead. Defaults to UTF-8.")
+            .long("--encoding")
+            .takes_value(true)
+            .value_name("[PATTERN=]ENCODING")
+            .number_of_values(1)
+            .multiple(true)
+        )
+        .arg(Arg::with_name("dry_run")
+            .help("Check the inputs without running #exec or #in commands or writing any output, and list the commands that would have run instead. Exec does not need to be allowed.")
+            .short("-n")
+            .long("--dry-run")
+        )
+        .arg(Arg::with_name("if_changed")
+            .help("Don't write output files that already have the right contents, so their modification times are left alone and build tools don't rebuild what depends on them.")
+            .long("--if-changed")
+        )
+        .arg(Arg::with_name("cache_dir")
+            .help("Record how each output file was generated in this directory, and skip inputs whose output is up to date because neither the input, the files it includes nor the options have changed since the last run. Only used when each input has its own output file.")
+            .long("--cache-dir")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("fingerprint")
+            .help("Write the SHA-256 hash of each output to this file, or to stdout if it is -, in the format of sha256sum. When each input has its own output file, each line names the output file; otherwise it names the input.")
+            .long("--fingerprint")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("depfile")
+            .help("Write the input and every file it included to this file for each output, as a Makefile rule unless --dep-format says otherwise, so that the build tool rebuilds the output when any of them change. This is written in a dry run as well.")
+            .long("--depfile")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("dep_target")
+            .help("The target of the rule written by --depfile, instead of the output file. Required when the output is written to stdout.")
+            .long("--dep-target")
+            .takes_value(true)
+            .requires("depfile")
+            .conflicts_with_all(&["output_pattern", "recursive"])
+        )
+        .arg(Arg::with_name("stdin_name")
+            .help("The name that standard input is given in errors, such as the name of the file that is being piped in. Defaults to <stdin>.")
+            .long("--stdin-name")
+            .takes_value(true)
+            .value_name("NAME")
+        )
+        .arg(Arg::with_name("error_format")
+            .help("How errors are printed. json prints each error as a line of JSON with its code, message, file, line and column, and the file and line of each #include that led to it, outermost first.")
+            .long("--error-format")
+            .takes_value(true)
+            .possible_values(&["human", "json"])
+        )
+        .arg(Arg::with_name("dep_format")
+            .help("The format of the file written by --depfile. make writes a Makefile rule for each output. ninja writes a depfile for a single output, to use with a build statement's depfile. dyndep writes a ninja dyndep file for any number of outputs, which is usually written by a separate --dry-run step so that ninja can read it before the outputs are built.")
+            .long("--dep-format")
+            .takes_value(true)
+            .possible_values(&["make", "ninja", "dyndep"])
+            .requires("depfile")
+        )
+        .arg(Arg::with_name("trace")
+            .help("Print every command to stderr as it runs with its file and line, along with whether each conditional's branch was taken and when each file is opened and closed.")
+            .long("--trace")
+        )
+        .arg(Arg::with_name("stats")
+            .help("Print a summary of each input's includes, macro expansions, child processes and processing time to stderr at the end of the run, along with the totals.")
+            .long("--stats")
+        )
+        .arg(Arg::with_name("keep_going")
+            .help("When an input fails to process, report the error and skip its output instead of stopping. gpp still exits with a nonzero status once all the inputs have been processed, without replacing the file given to -o or writing --fingerprint and --depfile.")
+            .short("-k")
+            .long("--keep-going")
+        )
+        .arg(Arg::with_name("error_marker")
+            .help("When a line fails to process, output MARKER in its place and carry on, with {error} in it replaced by the error. The errors are still reported, and gpp exits with a nonzero status once all the inputs have been written.")
+            .long("--error-marker")
+            .takes_value(true)
+            .value_name("MARKER")
+        )
         .get_matches();
 
-    let files = matches.values_of("files").unwrap();
-    let mut context = gpp::Context::new().exec(matches.is_present("allow_exec"));
+    let start = Instant::now();
+    let recursive = matches.is_present("recursive");
+    let jobs = value_t!(matches, "jobs", usize).unwrap_or_else(|e| e.exit());
+    JSON_ERRORS.store(
+        matches.value_of("error_format") == Some("json"),
+        Ordering::Relaxed,
+    );
+    let mut failures = Failures {
+        keep_going: matches.is_present("keep_going"),
+        count: 0,
+        recovered: 0,
+        exit_code: 0,
+    };
+
+    let mut files = Vec::new();
+    let files_from = matches.value_of("files_from");
+    // Only default to stdin if no inputs are listed in a file either.
+    if files_from.is_none() || matches.occurrences_of("files") > 0 {
+        for file in matches.values_of("files").unwrap() {
+            if !recursive && is_glob(file) {
+                files.extend(expand_glob(file)?);
+            } else {
+                files.push(file.to_owned());
+            }
+        }
+    }
+    if let Some(list) = files_from {
+        files.extend(read_file_list(list, matches.is_present("null"))?);
+    }
 
-    let (mut output_file, stdout, mut stdout_lock);
-    let output: &mut dyn io::Write = if let Some(filename) = matches.value_of("output") {
-        output_file = BufWriter::new(File::create(filename)?);
-        &mut output_file
-    } else {
-        stdout = io::stdout();
-        stdout_lock = stdout.lock();
-        &mut stdout_lock
+    let config_path = match matches.value_of("config") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Config::discover(&files)?,
+    };
+    let config = match config_path {
+        Some(path) => Config::load(&path)?,
+        None => Config::default(),
     };
 
-    let stdin = io::stdin();
-    let mut stdin = stdin.lock();
+    let dry_run = matches.is_present("dry_run");
+    let mut reports = Reports {
+        summary: Summary::default(),
+        fingerprints: Fingerprints {
+            path: matches
+                .value_of("fingerprint")
+                .filter(|_| !dry_run)
+                .map(PathBuf::from),
+            lines: String::new(),
+        },
+        depfile: Depfile {
+            path: matches.value_of("depfile").map(PathBuf::from),
+            format: match matches.value_of("dep_format") {
+                Some("ninja") => DepFormat::Ninja,
+                Some("dyndep") => DepFormat::Dyndep,
+                _ => DepFormat::Make,
+            },
+            rules: Vec::new(),
+        },
+    };
+    let if_changed = matches.is_present("if_changed");
+    let mut builder = gpp::Context::builder()
+        .exec(matches.is_present("allow_exec") || config.allow_exec)
+        .expand_exec(matches.is_present("expand_exec"))
+        .c_compat(matches.is_present("c_compat"))
+        .dry_run(dry_run)
+        .stats(matches.is_present("stats"));
+    for (name, value) in config.defines {
+        builder = match value {
+            toml::Value::String(value) => builder.define(name, value),
+            value => builder.define(name, value.to_string()),
+        };
+    }
+    #[cfg(not(feature = "git"))]
+    if matches.is_present("git_macros") {
+        clap::Error::with_description(
+            "--git-macros requires gpp to be built with the git feature",
+            ErrorKind::InvalidValue,
+        )
+        .exit();
+    }
+    let mut default_delimiters = None;
+    if let Some(name) = matches.value_of("lang").or(config.lang.as_deref()) {
+        let language = gpp::lang::from_name(name)
+            .or_else(|| gpp::lang::from_extension(name))
+            .unwrap_or_else(|| {
+                clap::Error::with_description(
+                    &format!("unknown language '{}'", name),
+                    ErrorKind::InvalidValue,
+                )
+                .exit()
+            });
+        builder = builder.command_delimiters(language.command_delimiters());
+        default_delimiters = Some(language.command_delimiters());
+    }
+    for define in matches.values_of("define").into_iter().flatten() {
+        let mut parts = define.splitn(2, '=');
+        builder = builder.define(parts.next().unwrap(), parts.next().unwrap_or(""));
+    }
+    for dir in matches.values_of("include_path").into_iter().flatten() {
+        builder = builder.include_dir(dir);
+    }
+    for dir in config.include_paths {
+        builder = builder.include_dir(dir);
+    }
+    for alias in matches.values_of("include_alias").into_iter().flatten() {
+        let (name, dir) = alias.split_once('=').unwrap_or_else(|| {
+            clap::Error::with_description(
+                &format!("'{}' isn't of the form NAME=DIR", alias),
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        });
+        builder = builder.include_alias(name, dir);
+    }
+    for (name, dir) in config.include_aliases {
+        builder = builder.include_alias(name, dir);
+    }
+    if matches.is_present("max_exec_output") {
+        builder = builder.max_exec_output(
+            value_t!(matches, "max_exec_output", usize).unwrap_or_else(|e| e.exit()),
+        );
+    }
+    if let Some(name) = matches.value_of("stdin_name") {
+        builder = builder.stdin_name(name);
+    }
+    if let Some(marker) = matches.value_of("error_marker") {
+        builder = builder.error_marker(marker);
+    }
+    if let Some(seconds) = matches.value_of("in_timeout") {
+        let timeout = seconds
+            .parse()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .unwrap_or_else(|| {
+                clap::Error::with_description(
+                    "--in-timeout must be a number of seconds",
+                    ErrorKind::InvalidValue,
+                )
+                .exit()
+            });
+        builder = builder.in_timeout(timeout);
+    }
+    match matches.value_of("windows_shell") {
+        Some("powershell") => builder = builder.windows_shell(gpp::WindowsShell::PowerShell),
+        Some("pwsh") => builder = builder.windows_shell(gpp::WindowsShell::Pwsh),
+        _ => {}
+    }
+    if let Some(epoch) = env::var_os("SOURCE_DATE_EPOCH") {
+        let seconds = epoch
+            .to_str()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_else(|| {
+                clap::Error::with_description(
+                    "SOURCE_DATE_EPOCH must be a Unix timestamp",
+                    ErrorKind::InvalidValue,
+                )
+                .exit()
+            });
+        builder = builder.date(UNIX_EPOCH + Duration::from_secs(seconds));
+    }
+    if matches.is_present("trace") {
+        builder = builder.on_trace(|event, _| eprintln!("{}", event));
+    }
+    // The settings are checked once, and each input is processed with a fork of the context.
+    let settings = builder.build()?;
+
+    let encodings = Encodings::parse(matches.values_of("encoding").into_iter().flatten());
+
+    let template_exts: Vec<String> = match matches.values_of("template_ext") {
+        Some(exts) => exts.map(str::to_owned).collect(),
+        None => config
+            .template_extensions
+            .unwrap_or_else(|| vec!["gpp".to_owned(), "in".to_owned()]),
+    };
+
+    let file_options = FileOptions {
+        encodings,
+        if_changed,
+        cache: matches.value_of("cache_dir").map(Cache::new),
+        auto_lang: (matches.is_present("auto_lang") || config.auto_lang).then(|| AutoLang {
+            template_exts: template_exts.clone(),
+            default: default_delimiters,
+        }),
+        #[cfg(feature = "git")]
+        git_macros: matches.is_present("git_macros").then(GitMacros::default),
+    };
 
-    for file in files {
-        let data = if file == "-" {
-            gpp::process_buf(&mut stdin, "<stdin>", &mut context)
-        } else if let Some(text) = file.strip_prefix(':') {
-            gpp::process_str(text, &mut context)
+    if recursive {
+        let output_dir = match matches.value_of("output_dir") {
+            Some(dir) => PathBuf::from(dir),
+            None => config.output_dir.unwrap_or_else(|| {
+                clap::Error::with_description(
+                    "--recursive requires --output-dir or output_dir in the config file",
+                    ErrorKind::MissingRequiredArgument,
+                )
+                .exit()
+            }),
+        };
+        let pattern = matches
+            .value_of("pattern")
+            .or(config.pattern.as_deref())
+            .unwrap_or("*");
+        let tasks = recursive_tasks(&files, &output_dir, pattern, &template_exts)?;
+        check_outputs(&tasks);
+        process_to_files(
+            &tasks,
+            jobs,
+            &settings,
+            &file_options,
+            &mut failures,
+            &mut reports,
+        )?;
+    } else if matches.is_present("output_pattern") || matches.is_present("output_dir") {
+        if files
+            .iter()
+            .any(|file| file == "-" || file.starts_with(':'))
+        {
+            clap::Error::with_description(
+                "--output-pattern and --output-dir can only be used with files, not stdin or strings",
+                ErrorKind::InvalidValue,
+            )
+            .exit();
+        }
+        let output_dir = Path::new(matches.value_of("output_dir").unwrap_or_default());
+        let tasks: Vec<(PathBuf, PathBuf)> = files
+            .iter()
+            .map(|file| {
+                let input = PathBuf::from(file);
+                let output = match matches.value_of("output_pattern") {
+                    Some(output_pattern) => pattern_output(output_pattern, &input),
+                    None => output_dir.join(strip_template_ext(&input, &template_exts)),
+                };
+                (input, output)
+            })
+            .collect();
+        check_outputs(&tasks);
+        process_to_files(
+            &tasks,
+            jobs,
+            &settings,
+            &file_options,
+            &mut failures,
+            &mut reports,
+        )?;
+    } else {
+        let output_path = matches
+            .value_of("output")
+            .map(PathBuf::from)
+            .or(config.output);
+        let dep_target = matches
+            .value_of("dep_target")
+            .map(PathBuf::from)
+            .or_else(|| output_path.clone())
+            .unwrap_or_else(|| {
+                if reports.depfile.path.is_some() {
+                    clap::Error::with_description(
+                        "--depfile requires --dep-target when the output is written to stdout",
+                        ErrorKind::MissingRequiredArgument,
+                    )
+                    .exit()
+                }
+                PathBuf::new()
+            });
+        let mut output_file = output_path
+            .as_deref()
+            .filter(|_| !dry_run)
+            .map(AtomicFile::create)
+            .transpose()?;
+        let (stdout, mut stdout_lock);
+        let output: &mut dyn io::Write = match &mut output_file {
+            Some(output_file) => output_file,
+            None => {
+                stdout = io::stdout();
+                stdout_lock = stdout.lock();
+                &mut stdout_lock
+            }
+        };
+
+        if jobs > 1 {
+            run_parallel(
+                &files,
+                jobs,
+                |file| {
+                    let mut context = settings.fork();
+                    let data = file_options
+                        .prepare(Path::new(file), &mut context)
+                        .and_then(|()| process_input(file, &mut context, &file_options.encodings));
+                    (data, context)
+                },
+                |file, (data, mut context)| {
+                    reports.summary.add(file, &mut context);
+                    failures.recovered(&mut context);
+                    if let Some(data) = failures.check(file, data)? {
+                        reports.fingerprints.add(file, &data);
+                        reports.depfile.add(&dep_target, &context);
+                        output.write_all(&data)?;
+                    }
+                    Ok(())
+                },
+            )?;
         } else {
-            gpp::process_file(file, &mut context)
-        }?;
-        output.write_all(data.as_bytes())?;
+            let mut context = settings.fork();
+            for file in &files {
+                let data = file_options
+                    .prepare(Path::new(file), &mut context)
+                    .and_then(|()| process_input(file, &mut context, &file_options.encodings));
+                reports.summary.add(file, &mut context);
+                failures.recovered(&mut context);
+                if let Some(data) = failures.check(file, data)? {
+                    reports.fingerprints.add(file, &data);
+                    reports.depfile.add(&dep_target, &context);
+                    output.write_all(&data)?;
+                }
+            }
+        }
+        output.flush()?;
+        // With --keep-going, the output of a run where an input failed is incomplete, so it is
+        // discarded rather than replacing the output file.
+        if let Some(output_file) = output_file.filter(|_| failures.count == 0) {
+            output_file.commit(if_changed)?;
+        }
+    }
+
+    if failures.count == 0 {
+        reports.fingerprints.write()?;
+        reports.depfile.write()?;
+    }
+    if matches.is_present("stats") {
+        reports.summary.print(start.elapsed());
+    }
+
+    // In JSON mode, stderr only has the errors themselves.
+    let json_errors = JSON_ERRORS.load(Ordering::Relaxed);
+    if failures.recovered > 0 && !json_errors {
+        eprintln!("{} error(s) were replaced by markers", failures.recovered);
+    }
+    if failures.count > 0 && !json_errors {
+        eprintln!("{} input(s) failed to process", failures.count);
+    }
+    if failures.count > 0 || failures.recovered > 0 {
+        process::exit(failures.exit_code);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{expand_glob, matches_pattern};
+
+    #[test]
+    fn wildcards() {
+        assert!(matches_pattern("*.txt", "page.txt"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("p?ge.*", "page.txt"));
+        assert!(!matches_pattern("p?ge.*", "pge.txt"));
+        assert!(!matches_pattern("*.txt", "page.txt.in"));
+        // A star has to give characters back when what follows it matches again later.
+        assert!(matches_pattern("*a*b", "aaxab"));
+        assert!(matches_pattern("a*b*c", "abbcbc"));
+        assert!(!matches_pattern("a*b*c", "abbcb"));
+        assert!(matches_pattern("**?", "x"));
+    }
+
+    #[test]
+    fn globs() {
+        let dir = std::env::temp_dir().join("gpp_glob_test");
+        let _ = fs::remove_dir_all(&dir);
+        for sub in ["a/b", ".hidden", "c"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for file in [
+            "top.txt",
+            "a/one.txt",
+            "a/b/two.txt",
+            ".hidden/three.txt",
+            ".dot.txt",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        fs::write(dir.join("c/other.md"), "").unwrap();
+        let glob = |pattern: &str| {
+            expand_glob(&dir.join(pattern).to_string_lossy())
+                .unwrap()
+                .into_iter()
+                .map(|file| file[dir.to_string_lossy().len() + 1..].to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(glob("*.txt"), ["top.txt"]);
+        assert_eq!(glob("?/*.txt"), ["a/one.txt"]);
+        // `**` matches no directories as well as any number of them, but not hidden ones.
+        assert_eq!(glob("**/*.txt"), ["a/b/two.txt", "a/one.txt", "top.txt"]);
+        // Wildcards only match hidden files when the pattern starts with a dot too.
+        assert_eq!(glob(".*.txt"), [".dot.txt"]);
+        assert_eq!(glob(".hidden/*"), [".hidden/three.txt"]);
+
+        let error = expand_glob(&dir.join("*.html").to_string_lossy()).unwrap_err();
+        assert!(error.to_string().contains("no files match the pattern"));
+    }
+}
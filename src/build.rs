@@ -0,0 +1,258 @@
+//! Helpers for using gpp from build scripts.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! // In build.rs
+//! let builder = gpp::Context::builder()
+//!     .define("VERSION", env!("CARGO_PKG_VERSION"))
+//!     .include_dir("templates");
+//! gpp::build::Gpp::from_builder(builder).process("src/config.rs.in", "config.rs");
+//! ```
+//!
+//! Then in your crate:
+//!
+//! ```ignore
+//! include!(concat!(env!("OUT_DIR"), "/config.rs"));
+//! ```
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::{process_buf, Context, ContextBuilder, Error};
+
+/// A builder for preprocessing files from a build script.
+///
+/// The settings of each context are given by a [`ContextBuilder`], so they are checked in the same
+/// way as when building a context directly. Each processed file gets a fresh context built from
+/// them, so macros defined in one file do not leak into the next.
+#[derive(Debug, Clone)]
+pub struct Gpp {
+    builder: ContextBuilder,
+    trace: bool,
+    rerun_if_changed: bool,
+}
+
+impl Default for Gpp {
+    fn default() -> Self {
+        Self::from_builder(ContextBuilder::default())
+    }
+}
+
+impl Gpp {
+    /// Create a new builder with no macros or include directories, exec commands disallowed and
+    /// `cargo:rerun-if-changed` output enabled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Create a new builder that processes files with contexts built by `builder`, with
+    /// `cargo:rerun-if-changed` output enabled.
+    pub fn from_builder(builder: ContextBuilder) -> Self {
+        Self {
+            builder,
+            trace: false,
+            rerun_if_changed: true,
+        }
+    }
+    /// Set whether to print a log of every command that is run and every file that is opened and
+    /// closed to stderr. See `Context::on_trace`.
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+    /// Set whether to print a `cargo:rerun-if-changed` line for every file that is read, so that
+    /// Cargo reruns the build script whenever a processed or included file changes.
+    pub fn rerun_if_changed(mut self, rerun_if_changed: bool) -> Self {
+        self.rerun_if_changed = rerun_if_changed;
+        self
+    }
+    /// Create a context from the builder's settings, returning an error if they conflict with each
+    /// other.
+    pub fn context(&self) -> Result<Context, Error> {
+        let mut builder = self.builder.clone();
+        if self.trace {
+            builder = builder.on_trace(|event, _| eprintln!("{}", event));
+        }
+        builder.build()
+    }
+    /// Preprocess `input` into `output`, returning every file that was read or any error that
+    /// occurs.
+    ///
+    /// A relative `output` is resolved against the `OUT_DIR` environment variable if it is set,
+    /// and the output's parent directories are created if they don't exist. The output isn't
+    /// written if it already has the right contents, so that anything depending on it isn't
+    /// rebuilt.
+    pub fn try_process(
+        &self,
+        input: impl AsRef<Path>,
+        output: impl AsRef<Path>,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let input = input.as_ref();
+        let output = match env::var_os("OUT_DIR") {
+            Some(out_dir) => Path::new(&out_dir).join(output),
+            None => output.as_ref().to_owned(),
+        };
+
+        let mut context = self.context()?;
+        context.state.dependencies.push(input.to_owned());
+        let result = File::open(input).map_err(Error::from).and_then(|file| {
+            process_buf(BufReader::new(file), &input.to_string_lossy(), &mut context)
+        });
+
+        if self.rerun_if_changed {
+            for dependency in &context.state.dependencies {
+                println!("cargo:rerun-if-changed={}", dependency.display());
+            }
+        }
+        // Errors replaced by the error marker are shown as warnings, as Cargo hides the rest of a
+        // build script's output.
+        for error in &context.state.recovered_errors {
+            println!("cargo:warning={}", error.to_string().replace('\n', " "));
+        }
+
+        let data = result?;
+        if context.dry_run {
+            return Ok(context.state.dependencies);
+        }
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_if_changed(output, data)?;
+        Ok(context.state.dependencies)
+    }
+    /// Preprocess `input` into `output`, panicking if an error occurs.
+    ///
+    /// This is the same as `try_process`, but panicking is usually the most useful way to report
+    /// an error from a build script.
+    pub fn process(&self, input: impl AsRef<Path>, output: impl AsRef<Path>) -> Vec<PathBuf> {
+        let input = input.as_ref();
+        self.try_process(input, output)
+            .unwrap_or_else(|e| panic!("gpp: failed to process {}: {}", input.display(), e))
+    }
+}
+
+/// Write `contents` to the file at `path`, unless the file already contains exactly that.
+///
+/// Skipping the write leaves the file's modification time alone, so tools like make and Cargo
+/// that compare modification times don't rebuild everything that depends on the file. Returns
+/// whether the file was written.
+///
+/// # Examples
+///
+/// ```no_run
+/// let output = gpp::process_file("index.html.in", &mut gpp::Context::new()).unwrap();
+/// gpp::build::write_if_changed("index.html", output).unwrap();
+/// ```
+pub fn write_if_changed(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<bool> {
+    let (path, contents) = (path.as_ref(), contents.as_ref());
+    let unchanged = fs::metadata(path)
+        .is_ok_and(|metadata| metadata.len() == contents.len() as u64)
+        && fs::read(path)? == contents;
+    if !unchanged {
+        fs::write(path, contents)?;
+    }
+    Ok(!unchanged)
+}
+
+/// Write a Makefile rule saying that `target` depends on each of `dependencies`, in the same form
+/// as the depfiles that C compilers write with `-MD`, so that make rebuilds the target when any
+/// file it was generated from changes.
+///
+/// Spaces, tabs, `#` and `$` in the paths are escaped the way GNU make expects, so paths containing
+/// them are read back correctly.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::PathBuf;
+///
+/// let dependencies = [PathBuf::from("index.html.in"), PathBuf::from("my header.html")];
+/// assert_eq!(
+///     gpp::build::make_depfile("index.html", &dependencies),
+///     "index.html: \\\n  index.html.in \\\n  my\\ header.html\n",
+/// );
+/// ```
+pub fn make_depfile(target: impl AsRef<Path>, dependencies: &[PathBuf]) -> String {
+    let mut rule = format!("{}:", escape_make(target.as_ref()));
+    for dependency in dependencies {
+        rule.push_str(" \\\n  ");
+        rule.push_str(&escape_make(dependency));
+    }
+    rule.push('\n');
+    rule
+}
+
+/// Escapes a path for use in a Makefile rule.
+fn escape_make(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let mut escaped = String::with_capacity(path.len());
+    let mut backslashes = 0;
+    for c in path.chars() {
+        match c {
+            ' ' | '\t' | '#' => {
+                // Backslashes before an escaped character would escape the escape instead, so
+                // each of them is doubled.
+                escaped.push_str(&"\\".repeat(backslashes + 1));
+            }
+            '$' => escaped.push('$'),
+            _ => {}
+        }
+        backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Write a [ninja dyndep file](https://ninja-build.org/manual.html#ref_dyndep) that adds the files
+/// each output depends on as implicit inputs of the build statement that generates it.
+///
+/// Unlike a depfile, which ninja reads after the output is built, a dyndep file is read before, so
+/// it lets ninja find out about #included files that are themselves generated by the build. Each
+/// output must be named the same way as in the build statement.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::PathBuf;
+///
+/// let outputs = [(
+///     PathBuf::from("index.html"),
+///     vec![PathBuf::from("index.html.in"), PathBuf::from("my header.html")],
+/// )];
+/// assert_eq!(
+///     gpp::build::ninja_dyndep(&outputs),
+///     "ninja_dyndep_version = 1\nbuild index.html: dyndep | index.html.in my$ header.html\n",
+/// );
+/// ```
+pub fn ninja_dyndep(outputs: &[(PathBuf, Vec<PathBuf>)]) -> String {
+    let mut file = String::from("ninja_dyndep_version = 1\n");
+    for (output, dependencies) in outputs {
+        file.push_str("build ");
+        file.push_str(&escape_ninja(output));
+        file.push_str(": dyndep");
+        if !dependencies.is_empty() {
+            file.push_str(" |");
+        }
+        for dependency in dependencies {
+            file.push(' ');
+            file.push_str(&escape_ninja(dependency));
+        }
+        file.push('\n');
+    }
+    file
+}
+
+/// Escapes a path for use in a ninja build statement.
+fn escape_ninja(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '$' | ' ' | ':' | '\n') {
+            escaped.push('$');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,251 @@
+//! Skipping files that haven't changed since they were last processed.
+//!
+//! A cache is a directory that records, for each output file, a hash of the context's settings and
+//! of every file that was read to produce it. When none of them have changed and the output is
+//! still as it was written, processing the file again would produce the same output, so it is
+//! skipped. This makes rebuilding a large tree of files where little has changed nearly instant.
+//!
+//! Only files are tracked, so a file is still considered unchanged if the output of an #exec or
+//! #in command, the contents provided by an `on_include` hook or the current time for #date would
+//! be different. Set the context's `date` if #date is used. As the `pre_line` and `post_line` hooks
+//! can change any line, a context with either of them set always processes its files again.
+//!
+//! Build systems that cache files by their contents can use [`fingerprint`] to hash each output.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! let cache = gpp::cache::Cache::new(".gpp-cache");
+//! let processed = cache
+//!     .process_file("index.html.in", "index.html", &mut gpp::Context::new())
+//!     .unwrap();
+//! if !processed {
+//!     println!("index.html is up to date");
+//! }
+//! ```
+
+use std::fmt::{self, Write as _};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::sha256::{self, Sha256};
+use crate::{process_buf, Context, Error, Precedence};
+
+/// A directory of records of how files were processed.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Use `dir` as the cache. It is created when the first record is written.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Check whether `output` needs to be generated from `input` again, using the settings and
+    /// macros of `context` as they are before processing starts.
+    ///
+    /// Returns `None` if it is up to date. Otherwise, the file should be processed and the
+    /// returned entry used to record the files that were read, which are taken from the
+    /// context's `state.dependencies` afterwards.
+    pub fn check(
+        &self,
+        input: impl AsRef<Path>,
+        output: impl AsRef<Path>,
+        context: &Context,
+    ) -> Option<Entry> {
+        let (input, output) = (input.as_ref(), output.as_ref());
+        let mut key = Sha256::new();
+        key.update(input.to_string_lossy().as_bytes());
+        key.update(b"\0");
+        key.update(output.to_string_lossy().as_bytes());
+
+        let entry = Entry {
+            path: self.dir.join(key.finish()),
+            output: output.to_owned(),
+            settings: settings_hash(context),
+        };
+        let has_line_hooks = context.pre_line.is_some() || context.post_line.is_some();
+        if !has_line_hooks && entry.is_fresh() {
+            None
+        } else {
+            Some(entry)
+        }
+    }
+
+    /// Preprocess `input` into `output` unless the cache shows that it is up to date, creating
+    /// the output's parent directories if necessary. Returns whether the file was processed.
+    pub fn process_file(
+        &self,
+        input: impl AsRef<Path>,
+        output: impl AsRef<Path>,
+        context: &mut Context,
+    ) -> Result<bool, Error> {
+        let (input, output) = (input.as_ref(), output.as_ref());
+        let entry = match self.check(input, output, context) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+
+        context.add_dependency(input);
+        let data = process_buf(
+            BufReader::new(File::open(input)?),
+            &input.to_string_lossy(),
+            context,
+        )?;
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(output, &data)?;
+        entry.record(context)?;
+        Ok(true)
+    }
+}
+
+/// The record of how one output file was generated, returned by `Cache::check` when the file
+/// needs to be processed again.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    path: PathBuf,
+    output: PathBuf,
+    settings: String,
+}
+
+impl Entry {
+    /// Record that the output has been written, after processing with `context`. Nothing is
+    /// recorded for a dry run, as no output was written.
+    pub fn record(self, context: &Context) -> io::Result<()> {
+        if context.dry_run {
+            return Ok(());
+        }
+        let mut record = format!(
+            "settings {}\noutput {}\n",
+            self.settings,
+            hash_file(&self.output)?
+        );
+        for dependency in &context.state.dependencies {
+            let _ = writeln!(
+                record,
+                "file {} {}",
+                hash_file(dependency)?,
+                dependency.display()
+            );
+        }
+        fs::create_dir_all(self.dir())?;
+        fs::write(&self.path, record)
+    }
+
+    fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new("."))
+    }
+
+    /// Whether the record exists and the settings, output and every file that was read are the
+    /// same as they were.
+    fn is_fresh(&self) -> bool {
+        let record = match fs::read_to_string(&self.path) {
+            Ok(record) => record,
+            Err(_) => return false,
+        };
+        let mut lines = record.lines();
+        if lines.next() != Some(&format!("settings {}", self.settings)) {
+            return false;
+        }
+        let output_matches = lines
+            .next()
+            .and_then(|line| line.strip_prefix("output "))
+            .is_some_and(|hash| hash_file(&self.output).is_ok_and(|current| current == hash));
+        output_matches
+            && lines.all(|line| {
+                line.strip_prefix("file ")
+                    .and_then(|line| line.split_once(' '))
+                    .is_some_and(|(hash, path)| {
+                        hash_file(Path::new(path)).is_ok_and(|current| current == hash)
+                    })
+            })
+    }
+}
+
+/// Hashes the output of a file with SHA-256, returning the hash as lowercase hexadecimal.
+///
+/// The hash only depends on the bytes of the output, so build systems can use it to tell whether an
+/// output has changed without comparing the files themselves. It is the same as the hash printed by
+/// `sha256sum`.
+///
+/// # Examples
+///
+/// ```
+/// let output = gpp::process_str("#define A b\nA\n", &mut gpp::Context::new()).unwrap();
+/// assert_eq!(
+///     gpp::cache::fingerprint(output),
+///     "0263829989b6fd954f72baaf2fc64bc2e2f01d692d4de72986ea808f6e99813f",
+/// );
+/// ```
+pub fn fingerprint(output: impl AsRef<[u8]>) -> String {
+    sha256::hash(output.as_ref())
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 8192];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.finish()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+/// Hashes everything about a context that can change the output of a file, other than the files
+/// that are read.
+fn settings_hash(context: &Context) -> String {
+    let mut settings = String::new();
+    for (name, value) in context.sorted_macros() {
+        let _ = write!(settings, "{:?}={:?},", name, value);
+    }
+    let precedence = match &context.precedence {
+        Precedence::Priority(priorities) => {
+            let mut priorities: Vec<_> = priorities.iter().collect();
+            priorities.sort();
+            format!("Priority({:?})", priorities)
+        }
+        precedence => format!("{:?}", precedence),
+    };
+    let fields: [&dyn fmt::Debug; 23] = [
+        &context.include_paths,
+        &context.include_aliases,
+        &precedence,
+        &context.definition_order,
+        &context.delimiters,
+        &context.command_delimiters,
+        &context.macro_escape,
+        &context.indented_commands,
+        &context.command_spacing,
+        &context.directive_comment,
+        &context.pass_unknown_commands,
+        &context.c_compat,
+        &context.line_continuation,
+        &context.reindent,
+        &context.state.trim_output,
+        &context.allow_exec,
+        &context.expand_exec,
+        &context.windows_shell,
+        &context.error_marker,
+        &context.preserve_line_endings,
+        &context.preserve_missing_newline,
+        &context.preserve_bom,
+        &context.date,
+    ];
+    for field in fields {
+        let _ = write!(settings, "{:?};", field);
+    }
+    #[cfg(feature = "encoding")]
+    let _ = write!(
+        settings,
+        "{:?};",
+        context.encoding.map(crate::encoding::Encoding::name)
+    );
+    sha256::hash(settings.as_bytes())
+}
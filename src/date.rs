@@ -0,0 +1,145 @@
+//! Formatting dates for #date.
+
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// A UTC date and time broken down into its parts.
+struct DateTime {
+    timestamp: i64,
+    year: i64,
+    /// From 1 to 12.
+    month: u32,
+    /// From 1 to 31.
+    day: u32,
+    /// From 1 to 366.
+    day_of_year: u32,
+    /// From 0 (Sunday) to 6.
+    weekday: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl DateTime {
+    fn new(time: SystemTime) -> Self {
+        let timestamp = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        let days = timestamp.div_euclid(86400);
+        let seconds = timestamp.rem_euclid(86400) as u32;
+
+        // Converts days since the epoch to a date in the proleptic Gregorian calendar, using
+        // Howard Hinnant's algorithm with years starting in March.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_march_year =
+            day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let march_month = (5 * day_of_march_year + 2) / 153;
+        let day = (day_of_march_year - (153 * march_month + 2) / 5 + 1) as u32;
+        let month = if march_month < 10 {
+            march_month + 3
+        } else {
+            march_month - 9
+        } as u32;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let day_of_year = if month <= 2 {
+            day_of_march_year - 305
+        } else {
+            day_of_march_year + 60 + i64::from(leap)
+        } as u32;
+
+        Self {
+            timestamp,
+            year,
+            month,
+            day,
+            day_of_year,
+            weekday: (days + 4).rem_euclid(7) as u32,
+            hour: seconds / 3600,
+            minute: seconds / 60 % 60,
+            second: seconds % 60,
+        }
+    }
+}
+
+/// Formats the time in UTC with a strftime-style format string, returning the first unsupported
+/// conversion specification if there is one.
+pub(crate) fn format(time: SystemTime, format: &str) -> Result<String, String> {
+    let date = DateTime::new(time);
+    let mut output = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            output.push(c);
+            continue;
+        }
+        let month_name = MONTHS[date.month as usize - 1];
+        let day_name = DAYS[date.weekday as usize];
+        // Writing to a String never fails.
+        let _ = match chars.next() {
+            Some('Y') => write!(output, "{}", date.year),
+            Some('y') => write!(output, "{:02}", date.year.rem_euclid(100)),
+            Some('m') => write!(output, "{:02}", date.month),
+            Some('d') => write!(output, "{:02}", date.day),
+            Some('e') => write!(output, "{:2}", date.day),
+            Some('j') => write!(output, "{:03}", date.day_of_year),
+            Some('H') => write!(output, "{:02}", date.hour),
+            Some('I') => write!(output, "{:02}", (date.hour + 11) % 12 + 1),
+            Some('p') => write!(output, "{}", if date.hour < 12 { "AM" } else { "PM" }),
+            Some('M') => write!(output, "{:02}", date.minute),
+            Some('S') => write!(output, "{:02}", date.second),
+            Some('s') => write!(output, "{}", date.timestamp),
+            Some('u') => write!(output, "{}", (date.weekday + 6) % 7 + 1),
+            Some('w') => write!(output, "{}", date.weekday),
+            Some('a') => write!(output, "{}", &day_name[..3]),
+            Some('A') => write!(output, "{}", day_name),
+            Some('b') | Some('h') => write!(output, "{}", &month_name[..3]),
+            Some('B') => write!(output, "{}", month_name),
+            Some('F') => write!(output, "{}-{:02}-{:02}", date.year, date.month, date.day),
+            Some('T') => write!(
+                output,
+                "{:02}:{:02}:{:02}",
+                date.hour, date.minute, date.second
+            ),
+            Some('R') => write!(output, "{:02}:{:02}", date.hour, date.minute),
+            Some('Z') => output.write_str("UTC"),
+            Some('z') => output.write_str("+0000"),
+            Some('n') => output.write_char('\n'),
+            Some('t') => output.write_char('\t'),
+            Some('%') => output.write_char('%'),
+            Some(c) => return Err(format!("%{}", c)),
+            None => return Err("%".to_owned()),
+        };
+    }
+    Ok(output)
+}
@@ -0,0 +1,181 @@
+//! Rendering errors as diagnostics with the [miette](https://docs.rs/miette) crate.
+//!
+//! This module requires the `miette` feature. [`Error`] implements `miette::Diagnostic` itself,
+//! giving each kind of error a code and, where there is something to suggest, help text. As an
+//! error only records the names of the files and the lines it happened on, [`SourceDiagnostic`]
+//! additionally reads the files so that the lines can be shown, along with each #include that led
+//! to the file.
+//!
+//! # Examples
+//!
+//! ```
+//! let error = gpp::process_str("#define A\n#bad\n", &mut gpp::Context::new()).unwrap_err();
+//! let diagnostic =
+//!     gpp::diagnostic::SourceDiagnostic::new(&error, |_| Some("#define A\n#bad\n".to_owned()));
+//!
+//! let mut rendered = String::new();
+//! miette::NarratableReportHandler::new()
+//!     .render_report(&mut rendered, &diagnostic)
+//!     .unwrap();
+//! assert!(rendered.contains("Invalid command 'bad'"));
+//! assert!(rendered.contains("the command is here"));
+//! ```
+
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+use miette::{Diagnostic, LabeledSpan, NamedSource, SourceCode};
+
+use crate::Error;
+
+impl Diagnostic for Error {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(format!("gpp::{}", Error::code(self))))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        let help = match self {
+            Error::FileError { error, .. } => return error.help(),
+            Error::InvalidCommand { .. } => {
+                "write a literal hash by doubling it, or set the context's `pass_unknown_commands` to output unknown commands as they are"
+            }
+            Error::ExecDisabled { .. } => {
+                "set the context's `allow_exec` flag if the input is trusted to run commands"
+            }
+            Error::UnclosedBlock { .. } => "blocks must be closed in the file that opens them",
+            Error::ExecOutputTooLarge { .. } => "raise the context's `max_exec_output`",
+            Error::ChildTimeout { .. } => "raise the context's `in_timeout`",
+            _ => return None,
+        };
+        Some(Box::new(help))
+    }
+}
+
+/// An error along with the lines of the files it happened in, which can be rendered by any miette
+/// report handler.
+///
+/// The line that caused the error is labelled in the file it is in, and each #include that led to
+/// that file is shown as a related diagnostic.
+#[derive(Debug)]
+pub struct SourceDiagnostic {
+    message: String,
+    code: &'static str,
+    help: Option<String>,
+    source: Option<NamedSource<String>>,
+    label: Option<LabeledSpan>,
+    related: Vec<SourceDiagnostic>,
+}
+
+impl SourceDiagnostic {
+    /// Create a diagnostic for an error, using `read` to get the contents of each file it names.
+    /// Lines in files that `read` returns `None` for aren't shown.
+    pub fn new(error: &Error, mut read: impl FnMut(&Path) -> Option<String>) -> Self {
+        // The outermost file comes first, down to the file the error happened in.
+        let mut locations = Vec::new();
+        let mut inner = error;
+        while let Error::FileError {
+            filename,
+            line,
+            error,
+        } = inner
+        {
+            locations.push((filename, *line));
+            inner = error;
+        }
+
+        let mut diagnostic = Self {
+            message: inner.to_string(),
+            code: inner.code(),
+            help: inner.help().map(|help| help.to_string()),
+            source: None,
+            label: None,
+            related: Vec::new(),
+        };
+        if let Some((filename, line)) = locations.pop() {
+            diagnostic.locate(filename, line, "the command is here", &mut read);
+        }
+        while let Some((filename, line)) = locations.pop() {
+            let mut included = Self {
+                message: format!("Included from {}", filename.display()),
+                code: "included_from",
+                help: None,
+                source: None,
+                label: None,
+                related: Vec::new(),
+            };
+            included.locate(filename, line, "included here", &mut read);
+            diagnostic.related.push(included);
+        }
+        diagnostic
+    }
+
+    /// Label line `line` of `filename`, counting from zero, if the file can be read.
+    fn locate(
+        &mut self,
+        filename: &Path,
+        line: usize,
+        label: &str,
+        read: &mut impl FnMut(&Path) -> Option<String>,
+    ) {
+        let contents = match read(filename) {
+            Some(contents) => contents,
+            None => return,
+        };
+        let start = contents
+            .split_inclusive('\n')
+            .take(line)
+            .map(str::len)
+            .sum::<usize>()
+            .min(contents.len());
+        let len = contents[start..]
+            .find('\n')
+            .unwrap_or(contents.len() - start);
+        let len = contents[start..start + len].trim_end_matches('\r').len();
+        self.label = Some(LabeledSpan::new_with_span(
+            Some(label.to_owned()),
+            (start, len),
+        ));
+        self.source = Some(NamedSource::new(filename.display().to_string(), contents));
+    }
+}
+
+impl Display for SourceDiagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for SourceDiagnostic {}
+
+impl Diagnostic for SourceDiagnostic {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(format!("gpp::{}", self.code)))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.help
+            .as_ref()
+            .map(|help| Box::new(help) as Box<dyn Display>)
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        self.source.as_ref().map(|source| source as &dyn SourceCode)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        self.label
+            .clone()
+            .map(|label| Box::new(std::iter::once(label)) as Box<dyn Iterator<Item = _>>)
+    }
+
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        if self.related.is_empty() {
+            return None;
+        }
+        Some(Box::new(
+            self.related
+                .iter()
+                .map(|diagnostic| diagnostic as &dyn Diagnostic),
+        ))
+    }
+}
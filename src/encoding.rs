@@ -0,0 +1,125 @@
+//! Processing input in encodings other than UTF-8, such as UTF-16, Shift-JIS or Latin-1.
+//!
+//! This module requires the `encoding` feature. With it, #included files are also decoded from
+//! the context's `encoding`, or the encoding that their byte order mark indicates.
+//!
+//! # Examples
+//!
+//! ```
+//! use gpp::encoding::{encoding_rs::WINDOWS_1252, process_encoded};
+//!
+//! let output = process_encoded(b"caf\xE9", "<latin1>", Some(WINDOWS_1252), &mut gpp::Context::new());
+//! assert_eq!(output.unwrap(), b"caf\xE9\n");
+//! ```
+
+use std::borrow::Cow;
+#[cfg(not(target_family = "wasm"))]
+use std::fs;
+use std::io;
+#[cfg(not(target_family = "wasm"))]
+use std::path::Path;
+
+pub use encoding_rs::{self, Encoding};
+use encoding_rs::{UTF_16BE, UTF_16LE, UTF_8};
+
+use crate::{process_buf, Context, Error};
+
+/// Process input in the given encoding, returning the output in the same encoding.
+///
+/// If the input starts with a byte order mark, the encoding it indicates is used instead of
+/// `encoding`, and the output starts with the same byte order mark. If neither is present, the
+/// input is assumed to be UTF-8. Input that isn't valid in its encoding causes an error.
+///
+/// UTF-8 input is processed just as `process_buf` would, so its byte order mark is only kept if
+/// the context's `preserve_bom` is set.
+pub fn process_encoded(
+    input: &[u8],
+    buf_name: &str,
+    encoding: Option<&'static Encoding>,
+    context: &mut Context,
+) -> Result<Vec<u8>, Error> {
+    let (encoding, bom) = match Encoding::for_bom(input) {
+        Some((encoding, bom_len)) => (encoding, &input[..bom_len]),
+        None => (encoding.unwrap_or(UTF_8), &[][..]),
+    };
+    if encoding == UTF_8 {
+        return Ok(process_buf(input, buf_name, context)?.into_bytes());
+    }
+
+    let text = decode(&input[bom.len()..], buf_name, encoding)?;
+    let output = process_buf(text.as_bytes(), buf_name, context)?;
+
+    let mut bytes = bom.to_vec();
+    if encoding == UTF_16LE {
+        bytes.extend(output.encode_utf16().flat_map(u16::to_le_bytes));
+    } else if encoding == UTF_16BE {
+        bytes.extend(output.encode_utf16().flat_map(u16::to_be_bytes));
+    } else {
+        let (encoded, _, unmappable) = encoding.encode(&output);
+        if unmappable {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the output of {} cannot be represented in {}",
+                    buf_name,
+                    encoding.name()
+                ),
+            )
+            .into());
+        }
+        bytes.extend_from_slice(&encoded);
+    }
+    Ok(bytes)
+}
+
+/// Decodes the contents of an #included file to UTF-8 from the context's `encoding`, or from the
+/// encoding its byte order mark indicates. UTF-8 contents are returned as they are.
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn decode_included<'a>(
+    contents: &'a [u8],
+    name: &str,
+    context: &Context,
+) -> Result<Cow<'a, [u8]>, Error> {
+    let (encoding, bom_len) = match Encoding::for_bom(contents) {
+        Some(found) => found,
+        None => (context.encoding.unwrap_or(UTF_8), 0),
+    };
+    if encoding == UTF_8 {
+        return Ok(Cow::Borrowed(contents));
+    }
+    let text = decode(&contents[bom_len..], name, encoding)?;
+    Ok(Cow::Owned(text.into_owned().into_bytes()))
+}
+
+/// Decodes input without a byte order mark, failing if it isn't valid in the encoding.
+fn decode<'a>(
+    input: &'a [u8],
+    buf_name: &str,
+    encoding: &'static Encoding,
+) -> Result<Cow<'a, str>, Error> {
+    Ok(encoding
+        .decode_without_bom_handling_and_without_replacement(input)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not valid {}", buf_name, encoding.name()),
+            )
+        })?)
+}
+
+/// Process a file in the given encoding.
+///
+/// See `process_encoded` for more details. It isn't available on WebAssembly, which has no
+/// filesystem.
+#[cfg(not(target_family = "wasm"))]
+pub fn process_file_encoded(
+    filename: &str,
+    encoding: Option<&'static Encoding>,
+    context: &mut Context,
+) -> Result<Vec<u8>, Error> {
+    let input = fs::read(filename)?;
+    context.add_dependency(Path::new(filename));
+    context.in_file(Path::new(filename), |context| {
+        process_encoded(&input, filename, encoding, context)
+    })
+}
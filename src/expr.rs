@@ -0,0 +1,480 @@
+//! Evaluating the expressions used by #if, #elif, #assert and #let.
+//!
+//! An expression is made of integers, which can be hexadecimal as in `0xff`, double-quoted strings,
+//! macro names and `defined(NAME)` or `defined NAME`, combined with parentheses and these
+//! operators, from the lowest to the highest precedence:
+//!
+//! - `||`
+//! - `&&`
+//! - `==`, `!=`, `<`, `<=`, `>` and `>=`
+//! - `+` and `-`
+//! - `*`, `/` and `%`
+//! - the unary operators `-` and `!`
+//!
+//! A macro whose value is an integer is used as an integer, and otherwise as a string. `+`
+//! concatenates its operands if either of them is a string, and comparisons compare integers
+//! numerically and anything else as text. The other arithmetic operators only work on integers.
+//! Comparisons and logical operators produce 1 or 0; the integer 0 and the empty string are false,
+//! and everything else is true.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use gpp::expr::{evaluate, Value};
+//!
+//! let mut macros = HashMap::new();
+//! macros.insert("WIDTH".to_owned(), "80".to_owned());
+//!
+//! assert_eq!(evaluate("WIDTH / 2 + 1", &macros), Ok(Value::Int(41)));
+//! assert_eq!(evaluate("\"w\" + WIDTH", &macros), Ok(Value::Str("w80".to_owned())));
+//! assert!(evaluate("defined(WIDTH) && WIDTH > 40", &macros).unwrap().is_true());
+//! ```
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+use crate::is_word_char;
+
+/// The result of evaluating an expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// An integer.
+    Int(i64),
+    /// A string.
+    Str(String),
+}
+
+impl Value {
+    /// Whether the value counts as true in a condition, which is when it isn't 0 or empty.
+    pub fn is_true(&self) -> bool {
+        match self {
+            Value::Int(n) => *n != 0,
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+    fn int(self, operator: &'static str) -> Result<i64, ExprError> {
+        match self {
+            Value::Int(n) => Ok(n),
+            Value::Str(_) => Err(ExprError::NotAnInteger { operator }),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Int(i64::from(b))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Str(s) => f.write_str(s),
+        }
+    }
+}
+
+/// An error in an expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExprError {
+    /// A character that can't start a token.
+    UnexpectedChar(char),
+    /// A string without a closing quote.
+    UnterminatedString,
+    /// A number that is malformed or too big.
+    InvalidNumber(String),
+    /// A token that doesn't fit the expression's grammar.
+    UnexpectedToken(String),
+    /// The expression ended too early.
+    UnexpectedEnd,
+    /// A macro that isn't defined was used.
+    UndefinedMacro(String),
+    /// An operator that only works on integers was given a string.
+    NotAnInteger { operator: &'static str },
+    /// A division or remainder by zero.
+    DivisionByZero,
+    /// An arithmetic operation overflowed.
+    Overflow,
+    /// Macros referred to each other too deeply, which usually means that a macro refers to
+    /// itself.
+    TooDeep,
+    /// The expression has too many nested parentheses or too many operators to be evaluated.
+    TooComplex,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExprError::UnexpectedChar(c) => write!(f, "Unexpected character '{}'", c),
+            ExprError::UnterminatedString => write!(f, "Unterminated string"),
+            ExprError::InvalidNumber(number) => write!(f, "Invalid number '{}'", number),
+            ExprError::UnexpectedToken(token) => write!(f, "Unexpected '{}'", token),
+            ExprError::UnexpectedEnd => write!(f, "Unexpected end of expression"),
+            ExprError::UndefinedMacro(name) => write!(f, "Macro '{}' is not defined", name),
+            ExprError::NotAnInteger { operator } => {
+                write!(f, "'{}' can only be used with integers", operator)
+            }
+            ExprError::DivisionByZero => write!(f, "Division by zero"),
+            ExprError::Overflow => write!(f, "Arithmetic overflow"),
+            ExprError::TooDeep => write!(f, "Macros are nested too deeply"),
+            ExprError::TooComplex => write!(f, "Expression is too complex"),
+        }
+    }
+}
+
+impl error::Error for ExprError {}
+
+/// Evaluate an expression, looking up the macros it uses in `macros`.
+pub fn evaluate(expression: &str, macros: &HashMap<String, String>) -> Result<Value, ExprError> {
+    evaluate_in(expression, macros, false)
+}
+
+/// Evaluate an expression as the C preprocessor's #if does. Macros that aren't defined are 0, and
+/// the value of every macro that is used is evaluated as an expression itself, so that
+/// `#define B A + 1` makes `B` one more than `A`. As in C, integers starting with `0` are octal.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use gpp::expr::{evaluate_c, Value};
+///
+/// let mut macros = HashMap::new();
+/// macros.insert("A".to_owned(), "0x10".to_owned());
+/// macros.insert("B".to_owned(), "(A + 1)".to_owned());
+///
+/// assert_eq!(evaluate_c("B * 2 + UNDEFINED", &macros), Ok(Value::Int(34)));
+/// ```
+pub fn evaluate_c(expression: &str, macros: &HashMap<String, String>) -> Result<Value, ExprError> {
+    evaluate_in(expression, macros, true)
+}
+
+fn evaluate_in(
+    expression: &str,
+    macros: &HashMap<String, String>,
+    c: bool,
+) -> Result<Value, ExprError> {
+    let expr = parse(&lex(expression, c)?)?;
+    Scope {
+        macros,
+        c,
+        depth: 0,
+    }
+    .eval(&expr)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Int(i64),
+    Str(String),
+    Ident(String),
+    Punct(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Int(n) => write!(f, "{}", n),
+            Token::Str(s) => write!(f, "\"{}\"", s),
+            Token::Ident(name) => f.write_str(name),
+            Token::Punct(punct) => f.write_str(punct),
+        }
+    }
+}
+
+/// Punctuation, with longer tokens first so that they are matched before their prefixes.
+const PUNCTUATION: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "(", ")", "+", "-", "*", "/", "%", "<", ">", "!",
+];
+
+/// Splits an expression into tokens. In the C dialect, integers starting with `0` are octal.
+fn lex(mut input: &str, c_dialect: bool) -> Result<Vec<Token>, ExprError> {
+    let mut tokens = Vec::new();
+    loop {
+        input = input.trim_start();
+        let c = match input.chars().next() {
+            Some(c) => c,
+            None => return Ok(tokens),
+        };
+        if let Some(&punct) = PUNCTUATION.iter().find(|&&p| input.starts_with(p)) {
+            tokens.push(Token::Punct(punct));
+            input = &input[punct.len()..];
+        } else if c == '"' {
+            let mut string = String::new();
+            let mut chars = input.char_indices().skip(1);
+            input = loop {
+                match chars.next().ok_or(ExprError::UnterminatedString)? {
+                    (i, '"') => break &input[i + 1..],
+                    (_, '\\') => string.push(chars.next().ok_or(ExprError::UnterminatedString)?.1),
+                    (_, c) => string.push(c),
+                }
+            };
+            tokens.push(Token::Str(string));
+        } else if is_word_char(c) {
+            let len = input
+                .find(|c: char| !is_word_char(c) && c != '.')
+                .unwrap_or(input.len());
+            let (word, rest) = input.split_at(len);
+            tokens.push(if c.is_ascii_digit() {
+                Token::Int(
+                    parse_int(word, c_dialect)
+                        .ok_or_else(|| ExprError::InvalidNumber(word.to_owned()))?,
+                )
+            } else {
+                Token::Ident(word.to_owned())
+            });
+            input = rest;
+        } else {
+            return Err(ExprError::UnexpectedChar(c));
+        }
+    }
+}
+
+/// Parses a decimal or `0x` hexadecimal integer, or in the C dialect an octal integer starting with
+/// `0`, ignoring any of C's `u` and `l` suffixes.
+fn parse_int(word: &str, c_dialect: bool) -> Option<i64> {
+    let word = word.trim_end_matches(['u', 'U', 'l', 'L']);
+    if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        return i64::from_str_radix(hex, 16).ok();
+    }
+    match word.strip_prefix('0') {
+        Some(octal) if c_dialect && !octal.is_empty() => i64::from_str_radix(octal, 8).ok(),
+        _ => word.parse().ok(),
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    Int(i64),
+    Str(String),
+    Macro(String),
+    Defined(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// The binary operators at each level of precedence, from the lowest to the highest.
+const BINARY_OPERATORS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["==", "!=", "<", "<=", ">", ">="],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+fn parse(tokens: &[Token]) -> Result<Expr, ExprError> {
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+        operators: 0,
+    };
+    let expr = parser.binary(0)?;
+    match parser.next() {
+        Some(token) => Err(ExprError::UnexpectedToken(token.to_string())),
+        None => Ok(expr),
+    }
+}
+
+/// A recursive descent parser over a list of tokens.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    /// How deeply parentheses and unary operators are nested at the current token.
+    depth: usize,
+    /// How many binary operators have been parsed.
+    operators: usize,
+}
+
+/// How deeply parentheses and unary operators can be nested in an expression, so that parsing it
+/// can't overflow the stack.
+const MAX_NESTING: usize = 64;
+
+/// How many binary operators an expression can have, so that evaluating it can't overflow the
+/// stack.
+const MAX_OPERATORS: usize = 256;
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos)?;
+        self.pos += 1;
+        Some(token)
+    }
+    /// Consumes the next token if it is the given punctuation.
+    fn eat(&mut self, punct: &'static str) -> bool {
+        let matches = self.tokens.get(self.pos) == Some(&Token::Punct(punct));
+        if matches {
+            self.pos += 1;
+        }
+        matches
+    }
+    /// Goes one level deeper into parentheses or unary operators, failing if they are nested too
+    /// deeply.
+    fn enter(&mut self) -> Result<(), ExprError> {
+        if self.depth == MAX_NESTING {
+            return Err(ExprError::TooComplex);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+    fn expect(&mut self, punct: &str) -> Result<(), ExprError> {
+        match self.next() {
+            Some(Token::Punct(p)) if *p == punct => Ok(()),
+            Some(token) => Err(ExprError::UnexpectedToken(token.to_string())),
+            None => Err(ExprError::UnexpectedEnd),
+        }
+    }
+    /// Parses operators of the given precedence level or higher.
+    fn binary(&mut self, level: usize) -> Result<Expr, ExprError> {
+        let operators = match BINARY_OPERATORS.get(level) {
+            Some(operators) => *operators,
+            None => return self.unary(),
+        };
+        let mut expr = self.binary(level + 1)?;
+        while let Some(&operator) = operators.iter().find(|&&operator| self.eat(operator)) {
+            self.operators += 1;
+            if self.operators > MAX_OPERATORS {
+                return Err(ExprError::TooComplex);
+            }
+            let rhs = self.binary(level + 1)?;
+            expr = Expr::Binary(operator, Box::new(expr), Box::new(rhs));
+        }
+        Ok(expr)
+    }
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        for operator in ["-", "!"] {
+            if self.eat(operator) {
+                self.enter()?;
+                let operand = self.unary()?;
+                self.depth -= 1;
+                return Ok(Expr::Unary(operator, Box::new(operand)));
+            }
+        }
+        match self.next().ok_or(ExprError::UnexpectedEnd)? {
+            Token::Int(n) => Ok(Expr::Int(*n)),
+            Token::Str(s) => Ok(Expr::Str(s.clone())),
+            Token::Ident(name) if name == "defined" => {
+                let parenthesized = self.eat("(");
+                let name = match self.next().ok_or(ExprError::UnexpectedEnd)? {
+                    Token::Ident(name) => name.clone(),
+                    token => return Err(ExprError::UnexpectedToken(token.to_string())),
+                };
+                if parenthesized {
+                    self.expect(")")?;
+                }
+                Ok(Expr::Defined(name))
+            }
+            Token::Ident(name) => Ok(Expr::Macro(name.clone())),
+            Token::Punct("(") => {
+                self.enter()?;
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                self.depth -= 1;
+                Ok(expr)
+            }
+            token => Err(ExprError::UnexpectedToken(token.to_string())),
+        }
+    }
+}
+
+/// The macros an expression is evaluated with and how they are used.
+#[derive(Clone, Copy)]
+struct Scope<'a> {
+    macros: &'a HashMap<String, String>,
+    c: bool,
+    /// How many macros' values are being evaluated, in the C dialect.
+    depth: usize,
+}
+
+/// How deeply macros can refer to each other in the C dialect, to stop recursive macros.
+const MAX_DEPTH: usize = 64;
+
+impl Scope<'_> {
+    fn eval(self, expr: &Expr) -> Result<Value, ExprError> {
+        Ok(match expr {
+            Expr::Int(n) => Value::Int(*n),
+            Expr::Str(s) => Value::Str(s.clone()),
+            Expr::Macro(name) => self.eval_macro(name)?,
+            Expr::Defined(name) => Value::from(self.macros.contains_key(name)),
+            Expr::Unary(operator, operand) => {
+                let operand = self.eval(operand)?;
+                match *operator {
+                    "!" => Value::from(!operand.is_true()),
+                    _ => Value::Int(
+                        operand
+                            .int(operator)?
+                            .checked_neg()
+                            .ok_or(ExprError::Overflow)?,
+                    ),
+                }
+            }
+            Expr::Binary("||", lhs, rhs) => {
+                Value::from(self.eval(lhs)?.is_true() || self.eval(rhs)?.is_true())
+            }
+            Expr::Binary("&&", lhs, rhs) => {
+                Value::from(self.eval(lhs)?.is_true() && self.eval(rhs)?.is_true())
+            }
+            Expr::Binary(operator, lhs, rhs) => binary(operator, self.eval(lhs)?, self.eval(rhs)?)?,
+        })
+    }
+    fn eval_macro(self, name: &str) -> Result<Value, ExprError> {
+        let value = match (self.macros.get(name), self.c) {
+            (Some(value), _) => value,
+            (None, true) => return Ok(Value::Int(0)),
+            (None, false) => return Err(ExprError::UndefinedMacro(name.to_owned())),
+        };
+        if !self.c {
+            return Ok(match value.trim().parse() {
+                Ok(n) => Value::Int(n),
+                Err(_) => Value::Str(value.clone()),
+            });
+        }
+        if value.trim().is_empty() {
+            return Ok(Value::Int(0));
+        }
+        if self.depth == MAX_DEPTH {
+            return Err(ExprError::TooDeep);
+        }
+        let scope = Scope {
+            depth: self.depth + 1,
+            ..self
+        };
+        scope.eval(&parse(&lex(value, true)?)?)
+    }
+}
+
+fn binary(operator: &'static str, lhs: Value, rhs: Value) -> Result<Value, ExprError> {
+    let ordering = || compare(&lhs, &rhs);
+    let (lhs, rhs) = match operator {
+        "==" => return Ok(Value::from(ordering().is_eq())),
+        "!=" => return Ok(Value::from(ordering().is_ne())),
+        "<" => return Ok(Value::from(ordering().is_lt())),
+        "<=" => return Ok(Value::from(ordering().is_le())),
+        ">" => return Ok(Value::from(ordering().is_gt())),
+        ">=" => return Ok(Value::from(ordering().is_ge())),
+        "+" if matches!((&lhs, &rhs), (Value::Str(_), _) | (_, Value::Str(_))) => {
+            return Ok(Value::Str(format!("{}{}", lhs, rhs)));
+        }
+        _ => (lhs.int(operator)?, rhs.int(operator)?),
+    };
+    let result = match operator {
+        "/" | "%" if rhs == 0 => return Err(ExprError::DivisionByZero),
+        "+" => lhs.checked_add(rhs),
+        "-" => lhs.checked_sub(rhs),
+        "*" => lhs.checked_mul(rhs),
+        "/" => lhs.checked_div(rhs),
+        _ => lhs.checked_rem(rhs),
+    };
+    result.map(Value::Int).ok_or(ExprError::Overflow)
+}
+
+fn compare(lhs: &Value, rhs: &Value) -> std::cmp::Ordering {
+    match (lhs, rhs) {
+        (Value::Int(a), Value::Int(b)) => a.cmp(b),
+        (a, b) => a.to_string().cmp(&b.to_string()),
+    }
+}
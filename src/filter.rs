@@ -0,0 +1,130 @@
+//! Filters that transform a macro's value where it is used, as in `${NAME|upper}`.
+
+/// A built-in filter.
+struct Filter {
+    name: &'static str,
+    apply: fn(&str) -> String,
+}
+
+const FILTERS: &[Filter] = &[
+    Filter {
+        name: "upper",
+        apply: str::to_uppercase,
+    },
+    Filter {
+        name: "lower",
+        apply: str::to_lowercase,
+    },
+    Filter {
+        name: "trim",
+        apply: |value| value.trim().to_owned(),
+    },
+    Filter {
+        name: "capitalize",
+        apply: capitalize,
+    },
+    Filter {
+        name: "escape_html",
+        apply: escape_html,
+    },
+    Filter {
+        name: "json",
+        apply: json,
+    },
+    Filter {
+        name: "shquote",
+        apply: shquote,
+    },
+    Filter {
+        name: "urlencode",
+        apply: urlencode,
+    },
+];
+
+/// Applies the filter with the given name to a value, returning `None` if there is no such filter.
+pub(crate) fn apply(name: &str, value: &str) -> Option<String> {
+    let filter = FILTERS.iter().find(|filter| filter.name == name)?;
+    Some((filter.apply)(value))
+}
+
+/// Gets the name of the filter that `#escape [format]` uses, which is either the filter with that
+/// name or the one called `escape_[format]`.
+pub(crate) fn escape_filter(format: &str) -> Option<&'static str> {
+    let escape_name = format!("escape_{}", format);
+    FILTERS
+        .iter()
+        .find(|filter| filter.name == format)
+        .or_else(|| FILTERS.iter().find(|filter| filter.name == escape_name))
+        .map(|filter| filter.name)
+}
+
+fn capitalize(value: &str) -> String {
+    let mut chars = value.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Replaces the characters that are special in HTML and XML with entities, so that the value can
+/// be used in text and in quoted attributes.
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes a value for use inside a JSON string, without adding the surrounding quotes.
+fn json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c < ' ' => {
+                escaped.push_str(&format!("\\u{:04x}", u32::from(c)));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quotes a value as a single argument for the shell that #exec and #in use.
+///
+/// For `sh`, the value is put in single quotes, in which nothing is special except the single
+/// quote itself. For `cmd`, the value is put in double quotes with any double quotes doubled, and
+/// `%` is escaped outside of the quotes so that it can't expand a variable.
+fn shquote(value: &str) -> String {
+    if cfg!(target_os = "windows") {
+        format!("\"{}\"", value.replace('"', "\"\"").replace('%', "\"^%\""))
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// Percent-encodes every byte of a value's UTF-8 except the unreserved characters of RFC 3986, so
+/// that it can be used as a part of a URL's path or query string.
+fn urlencode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for &b in value.as_bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(char::from(b));
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
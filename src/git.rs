@@ -0,0 +1,79 @@
+//! Macros describing the git repository that a file is in.
+//!
+//! This module requires the `git` feature. It runs the `git` program to find out about the
+//! repository, but as the commands it runs are fixed it doesn't require exec to be allowed.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! let mut context = gpp::Context::new();
+//! context.macros.extend(gpp::git::git_macros("docs/footer.html").unwrap());
+//!
+//! let footer = gpp::process_str("Built from __GIT_SHORT_HASH__", &mut context).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::Error;
+
+/// Runs git in the directory with the given arguments, returning its trimmed output or `None` if it
+/// fails.
+fn git(dir: &Path, args: &[&str]) -> Result<Option<String>, Error> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(
+        String::from_utf8(output.stdout)?.trim_end().to_owned(),
+    ))
+}
+
+/// Get macros describing the git repository that contains `path`, which can be a file or a
+/// directory.
+///
+/// The macros are:
+/// - `__GIT_HASH__`, the full hash of the commit that is checked out.
+/// - `__GIT_SHORT_HASH__`, the abbreviated hash of that commit.
+/// - `__GIT_BRANCH__`, the name of the branch that is checked out. This isn't defined if no branch
+///   is checked out.
+/// - `__GIT_DIRTY__`, which is defined as an empty string if there are uncommitted changes, so it
+///   can be tested with #ifdef.
+///
+/// If `path` isn't in a git repository, or the repository has no commits, no macros are returned.
+/// An error is returned if git cannot be run.
+pub fn git_macros(path: impl AsRef<Path>) -> Result<HashMap<String, String>, Error> {
+    let path = path.as_ref();
+    let dir = if path.is_dir() {
+        path
+    } else {
+        match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        }
+    };
+
+    let mut macros = HashMap::new();
+    let hash = match git(dir, &["rev-parse", "HEAD"])? {
+        Some(hash) => hash,
+        None => return Ok(macros),
+    };
+    if let Some(short_hash) = git(dir, &["rev-parse", "--short", "HEAD"])? {
+        macros.insert("__GIT_SHORT_HASH__".to_owned(), short_hash);
+    }
+    macros.insert("__GIT_HASH__".to_owned(), hash);
+    if let Some(branch) = git(dir, &["symbolic-ref", "--short", "-q", "HEAD"])? {
+        macros.insert("__GIT_BRANCH__".to_owned(), branch);
+    }
+    if git(dir, &["status", "--porcelain"])?.is_some_and(|status| !status.is_empty()) {
+        macros.insert("__GIT_DIRTY__".to_owned(), String::new());
+    }
+    Ok(macros)
+}
@@ -0,0 +1,126 @@
+//! Presets for writing commands inside the comments of other languages.
+//!
+//! Commands that start with a hash get in the way of languages where `#` means something, and make
+//! files invalid to tools that read them before they are processed. Each language here writes
+//! commands as comments instead, such as `<!--#include header.html -->` in HTML or
+//! `--#ifdef DEBUG` in SQL. A line of text that starts with the prefix can still be written by
+//! doubling the prefix.
+//!
+//! # Examples
+//!
+//! ```
+//! let language = gpp::lang::from_name("sql").unwrap();
+//! let mut context = gpp::Context::new();
+//! context.command_delimiters = Some(language.command_delimiters());
+//!
+//! assert_eq!(
+//!     gpp::process_str("--#define TABLE users\nSELECT * FROM TABLE;\n", &mut context).unwrap(),
+//!     "SELECT * FROM users;\n",
+//! );
+//! ```
+
+use std::path::Path;
+
+use crate::Delimiters;
+
+/// A language's syntax for commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Language {
+    /// The name of the language, such as `html`.
+    pub name: &'static str,
+    /// The file extensions that the language is used for, without the dot.
+    pub extensions: &'static [&'static str],
+    /// The text that commands start with.
+    pub prefix: &'static str,
+    /// The text that commands end with, which is empty for line comments.
+    pub suffix: &'static str,
+}
+
+impl Language {
+    /// The delimiters to set as a context's `command_delimiters` to write commands in this
+    /// language.
+    pub fn command_delimiters(&self) -> Delimiters {
+        Delimiters::new(self.prefix, self.suffix)
+    }
+}
+
+/// Every language that has a preset.
+pub const LANGUAGES: &[Language] = &[
+    Language {
+        name: "html",
+        extensions: &["html", "htm", "xhtml", "xml", "svg", "md", "markdown"],
+        prefix: "<!--#",
+        suffix: "-->",
+    },
+    Language {
+        name: "css",
+        extensions: &["css"],
+        prefix: "/*#",
+        suffix: "*/",
+    },
+    Language {
+        name: "c",
+        extensions: &[
+            "c", "h", "cc", "cpp", "cxx", "hpp", "hxx", "cs", "java", "js", "mjs", "ts", "go",
+            "rs", "swift", "kt", "scss", "less",
+        ],
+        prefix: "//#",
+        suffix: "",
+    },
+    Language {
+        name: "python",
+        extensions: &["py", "pyi", "rb", "pl", "r"],
+        prefix: "#@",
+        suffix: "",
+    },
+    Language {
+        name: "shell",
+        extensions: &[
+            "sh", "bash", "zsh", "fish", "ps1", "yaml", "yml", "toml", "conf", "cfg", "ini",
+        ],
+        prefix: "#@",
+        suffix: "",
+    },
+    Language {
+        name: "sql",
+        extensions: &["sql", "lua", "hs"],
+        prefix: "--#",
+        suffix: "",
+    },
+    Language {
+        name: "tex",
+        extensions: &["tex", "sty", "cls", "bib"],
+        prefix: "%#",
+        suffix: "",
+    },
+];
+
+/// Finds a language by its name.
+pub fn from_name(name: &str) -> Option<&'static Language> {
+    LANGUAGES
+        .iter()
+        .find(|language| language.name.eq_ignore_ascii_case(name))
+}
+
+/// Finds the language used for a file extension, with or without its leading dot.
+pub fn from_extension(extension: &str) -> Option<&'static Language> {
+    let extension = extension.strip_prefix('.').unwrap_or(extension);
+    LANGUAGES.iter().find(|language| {
+        language
+            .extensions
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(extension))
+    })
+}
+
+/// Finds the language of a file from its extension. A file ending in `.in`, such as
+/// `config.h.in`, is treated as having the extension before it.
+pub fn from_path(path: impl AsRef<Path>) -> Option<&'static Language> {
+    let path = path.as_ref();
+    let path = match path.extension() {
+        Some(extension) if extension == "in" => Path::new(path.file_stem()?),
+        _ => path,
+    };
+    from_extension(path.extension()?.to_str()?)
+}
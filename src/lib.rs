@@ -2,20 +2,78 @@
 //!
 //! It supports:
 //! - Simple macros, no function macros
-//! - #include
-//! - #define and #undef
+//! - #include, #include_isolated and #include_base64
+//! - #export and #import for sharing macros between isolated files
+//! - #define, #default and #undef
+//! - #let for defining a macro from an expression
+//! - Namespaced macros, and #using for bringing a namespace's macros into scope
+//! - #append and #prepend for building up a macro's value
+//! - #comment and #endcomment for comments that don't appear in the output
+//! - #raw and #endraw for text that is output exactly as it is
+//! - #capture and #endcapture for storing output in a macro
+//! - #escape and #endescape for escaping output, such as for HTML
+//! - #pragma for changing settings within a file
 //! - #ifdef, #ifndef, #elifdef, #elifndef, #else and #endif
-//! - #exec for running commands
+//! - #if, #elif and #assert with expressions
+//! - #switch, #case, #default and #endswitch
+//! - #date for outputting the current date
+//! - #exec for running commands, and #ifexec for checking whether they succeed
 //! - #in and #endin for giving input to commands
 //!
-//! #includes work differently from C, as they do not require (and do not work with) quotes or <>,
-//! so `#include file.txt` is the correct syntax. It does not support #if or #elif, and recursive
-//! macros will cause the library to get stuck.
+//! #includes work differently from C, as they do not require quotes and only work with <> in C
+//! compatibility mode, so `#include file.txt` is the correct syntax. Recursive macros will cause
+//! the library to get stuck.
 //!
 //! # About
 //!
 //! The hash in any command may be succeeded by optional whitespace, so for example `# undef Macro`
-//! is valid, but ` # undef Macro` is not.
+//! is valid, but ` # undef Macro` is not unless the context's `indented_commands` flag is set.
+//! That flag allows commands to be preceded by spaces and tabs, which is useful when the input
+//! must also be readable as nested markup before it is processed. The context's `command_spacing`
+//! can instead forbid whitespace after the hash, or allow a tab as well as a space between a
+//! command's name and its parameters, as in `#define\tNAME value`.
+//!
+//! To label commands, for example to say which #ifdef an #endif closes, set the context's
+//! `directive_comment` to a marker such as `//`. Then `#endif // FEATURE` is the same as `#endif`,
+//! as the marker and everything after it are ignored when it comes at the start of the parameters
+//! or after whitespace.
+//!
+//! ## #pragma
+//!
+//! `#pragma [option] [value]` changes one of the context's settings for the rest of the file, so a
+//! file can choose the syntax it is written in. At the end of the file the setting goes back to
+//! what it was, so #pragmas in an included file don't affect the file that includes it. The
+//! options are:
+//! - `delimiters [prefix] [suffix]` or `delimiters none`
+//! - `directive_comment [marker]` or `directive_comment none`
+//! - `macro_escape [marker]` or `macro_escape none`
+//! - `command_spacing strict`, `command_spacing spaces` or `command_spacing tabs`
+//! - `indented_commands`, `reindent`, `preserve_line_endings`, `pass_unknown_commands`,
+//!   `c_compat` and `line_continuation`, which take `on` or `off`
+//! ```text
+//! #pragma delimiters ${ }
+//! Version ${VERSION}
+//! ```
+//!
+//! ## #mode
+//!
+//! `#mode [prefix] [suffix]` changes how commands are written for the rest of the file, so that
+//! each part of a document can use a prefix that doesn't clash with its contents. After it, a line
+//! is a command if it starts with the prefix and ends with the suffix, and #mode itself must be
+//! written the new way. `#mode default` goes back to commands starting with a hash:
+//! ```text
+//! #mode %
+//! # A Markdown heading
+//! %include intro.md
+//! %mode <!--# -->
+//! <!--#include footer.html -->
+//! <!--#mode default-->
+//! ```
+//! As with #pragma, included files start in the mode of the file that includes them, and the mode
+//! goes back to what it was at the end of the file. To start a line of text with the prefix, write
+//! it twice. The context's `command_delimiters` sets the mode that the input starts in, and the
+//! [`lang`] module has presets for writing commands in the comments of languages such as HTML,
+//! SQL and TeX, which the command line tool selects with `--lang`.
 //!
 //! ## #define and #undef
 //!
@@ -24,32 +82,269 @@
 //! use A, gpp will run forever.
 //! If #define is not given a value, then it will default to an empty string.
 //!
+//! Macro names can contain dots to group them into namespaces, such as `theme.color`. When a
+//! namespaced macro like that is defined, a macro called `theme` or `color` isn't replaced where it
+//! is part of `theme.color`, so it can't clobber part of the longer name; elsewhere, such as in
+//! `shade.color`, it is still replaced. `#undef theme.*` undefines every macro in the `theme`
+//! namespace, and `#using theme` defines each of them without its namespace, so `theme.color` can
+//! also be written as `color`.
+//!
+//! To write a macro's name in text without it being replaced, set the context's `macro_escape` to
+//! a marker such as `\` and write the marker straight before the name. The marker is removed from
+//! the output, while markers that aren't before a macro are left alone:
+//! ```text
+//! #pragma macro_escape \
+//! #define Foo Bar
+//! Foo is \Foo, but \n is still \n
+//! ```
+//! This outputs `Bar is Foo, but \n is still \n`. With delimiters, the marker goes before the
+//! prefix, as in `\${Foo}`.
+//!
+//! `#default [name] [value]` is the same as #define, except that it does nothing if the macro is
+//! already defined. This lets a file provide defaults that can be overridden by macros defined
+//! before it is processed, such as those given on the command line.
+//!
+//! `#let [name] = [expression]` defines a macro as the result of an
+//! [expression](crate::expr), which can do arithmetic on integers and refer to other macros. A
+//! macro whose value is an integer is used as an integer, and `+` concatenates its operands when
+//! either of them isn't an integer:
+//! ```text
+//! #define WIDTH 80
+//! #let HALF = WIDTH / 2
+//! #let LABEL = "width: " + (HALF * 2) + "px"
+//! ```
+//!
+//! `#append [name] [value]` and `#prepend [name] [value]` add text to the end or the start of a
+//! macro's value, defining it if it isn't defined yet. The value can be surrounded in double quotes
+//! to keep the whitespace at its edges, which is useful for building up lists:
+//! ```text
+//! #define CLASSES page
+//! #ifdef DARK
+//! #append CLASSES " dark-mode"
+//! #endif
+//! ```
+//!
+//! By default a macro is replaced wherever its name appears as a whole word. Macros are replaced
+//! from left to right, and where several macro names start at the same place, such as `a` and
+//! `a-b`, the longest one is replaced unless the context's `precedence` chooses differently. To
+//! make macros stand out from the surrounding text, set the context's `delimiters` so that they
+//! must be written as `${NAME}`, `@NAME@` or with any other prefix and suffix.
+//!
+//! When macros are delimited by a prefix and a suffix, their values can be transformed by filters
+//! written after the name and separated by `|`, as in `${NAME|trim|upper}`. A macro with an
+//! unknown filter is left as it is, like an undefined macro. The filters are:
+//! - `upper` and `lower`, which change the value's case.
+//! - `capitalize`, which makes the first character uppercase.
+//! - `trim`, which removes whitespace from both ends.
+//! - `escape_html`, which replaces `&`, `<`, `>`, `"` and `'` with entities so that the value can
+//!   be put in HTML safely.
+//! - `json`, which escapes quotes, backslashes and control characters so that the value can be put
+//!   between the quotes of a JSON string.
+//! - `shquote`, which quotes the value as a single argument to the shell that #exec uses. Use it
+//!   for every macro in a command when `expand_exec` is set.
+//! - `urlencode`, which percent-encodes the value for use in a URL.
+//!
+//! ## #capture and #endcapture
+//!
+//! `#capture [name]` processes everything up to the matching #endcapture as usual, but instead of
+//! outputting it, stores it in the macro `name` so that it can be used several times later on. The
+//! final line ending of the captured output is removed. Capture blocks can be nested, and can
+//! contain or be inside #in blocks.
+//! ```text
+//! #capture NAV
+//! #include nav.html
+//! #endcapture
+//! ```
+//!
+//! `#escape [filter]` and #endescape work the same way, but pass the output through one of the
+//! filters described under #define and output it instead of storing it. `#escape html` is short
+//! for `#escape escape_html`, so this safely puts a file in a web page:
+//! ```text
+//! <pre>
+//! #escape html
+//! #include example.html
+//! #endescape
+//! </pre>
+//! ```
+//!
+//! ## #comment
+//!
+//! `#comment any text` and `#// any text` are removed from the output, so you can use them to
+//! annotate the input without the notes appearing in the processed text.
+//!
+//! A #comment with no text starts a block comment, and everything up to the matching #endcomment
+//! is removed, including any commands. Block comments can be nested, and are respected even inside
+//! an inactive #ifdef, so commenting out a block never changes how the surrounding #ifdefs are
+//! matched up.
+//! ```text
+//! #comment
+//! This text and this #ifdef are ignored.
+//! #ifdef A
+//! #endcomment
+//! ```
+//!
+//! ## #raw and #endraw
+//!
+//! Every line between #raw and #endraw is output exactly as it is written, without replacing
+//! macros or running commands. This is useful when the text you are generating contains commands
+//! or words that happen to be macro names. Raw blocks cannot be nested, as the first #endraw always
+//! ends the block.
+//!
 //! ## #include
 //!
-//! Includes, unlike C, do not require quotes or angle brackets, so this: `#include "file.txt"` or
-//! this: `#include <file.txt>` will not work; you must write `#include file.txt`.
+//! Includes, unlike C, do not require quotes or angle brackets, so you can write
+//! `#include file.txt`. You can optionally surround the path in double quotes, which is needed if
+//! it has leading or trailing whitespace: `#include "my file.txt "`. Angle brackets, like
+//! `#include <file.txt>`, only work in [C compatibility mode](#c-compatibility).
+//!
+//! To include only some of the lines of a file, add a `lines=START..END` parameter, for example
+//! `#include main.rs lines=10..40`. Lines are numbered from 1 and both ends of the range are
+//! included; either end can be omitted to include from the start or up to the end of the file.
 //!
 //! Also, unlike C the directory does not change when you #include; otherwise, gpp would change its
 //! current directory and wouldn't be thread safe. This means that if you `#include dir/file.txt`
 //! and in `dir/file.txt` it says `#include other_file.txt`, that would refer to `other_file.txt`,
 //! not `dir/other_file.txt`.
 //!
+//! If the file does not exist relative to the current directory, gpp looks for it in each of the
+//! directories in the context's `include_paths` in order, similar to C's `-I`.
+//!
+//! The context's `include_aliases` name directories that included paths can start with, so that
+//! deeply nested files don't need chains of `../`. With an alias named `@root` for `/project`,
+//! `#include @root/header.txt` includes `/project/header.txt` wherever it is written.
+//!
+//! `#include !command` runs a command and includes its output, which is processed like the
+//! contents of a file, so commands and macros in it take effect. As it runs a command, it is only
+//! allowed if #exec is (see below).
+//!
+//! `#include -` includes everything that can be read from standard input, so a template can wrap
+//! whatever is piped to it. Standard input can only be read once, so including it again includes
+//! nothing. A file that is named `-` can still be included as `./-`. When standard input is also
+//! the main input of the gpp binary, `#include -` includes the rest of it, which is then not
+//! processed again as the main input.
+//!
+//! Alternatively, you can set the context's `resolver` to read included files from somewhere other
+//! than the filesystem, such as a map of file names to their contents. To replace only some files,
+//! use `Context::on_include` instead.
+//!
+//! `#include_isolated file` is the same as #include, except that macros defined or undefined in the
+//! included file only last until the end of it, so the file can't change the macros of the file
+//! that includes it.
+//!
+//! To share some of its macros, an isolated file can list them with `#export [name...]`, and they
+//! are copied to the including file at the end of the #include_isolated. `#import file [name...]`
+//! is the same as #include_isolated, except that it only copies the macros named, which must have
+//! been exported. This gives a file of shared definitions a fixed set of macros that other files
+//! can use:
+//! ```text
+//! #import colors.txt PRIMARY SECONDARY
+//! ```
+//!
+//! `#include_base64 file` reads a file as bytes and outputs its base64 encoding on a single line,
+//! which is useful for embedding small binary files such as images into HTML or CSS.
+//!
+//! ## #date
+//!
+//! `#date [format]` outputs the current date and time in UTC, formatted with the same `%`
+//! conversions as C's strftime, such as `#date %Y-%m-%d %H:%M`. Without a format it outputs the
+//! date in the form `2024-01-31`. To get reproducible output, set the context's `date` to the time
+//! that should be used instead of the current time.
+//!
 //! ## Ifs
 //!
 //! The #ifdef, #ifndef, #elifdef, #elifndef, #else and #endif commands work exactly as you expect.
-//! I did not add generic #if commands to gpp, as it would make it much more complex and require a
-//! lot of parsing, and most of the time these are all you need anyway.
+//! Every #ifdef or #ifndef must be closed by an #endif in the same file, and likewise for the other
+//! blocks such as #in and #comment, otherwise processing the file causes an error. This isn't
+//! checked for a range of lines included with `lines=`, as it may start or end part way through a
+//! block.
+//!
+//! `#if [expression]` and `#elif [expression]` take their block if an [expression](crate::expr)
+//! is true, which is when it isn't 0 or an empty string, and can be mixed with the other ifs:
+//! ```text
+//! #if defined(WIDTH) && WIDTH >= 80
+//! wide
+//! #elifdef WIDTH
+//! narrow
+//! #endif
+//! ```
+//! `#assert [expression]` causes an error if the expression is false, which is useful for checking
+//! the macros a file expects, as in `#assert defined(VERSION)`.
+//!
+//! ### C compatibility
+//!
+//! Setting the context's `c_compat` flag, or running `#pragma c_compat on`, lets gpp process files
+//! written for the C preprocessor, such as `config.h.in`, without rewriting them:
+//! - `#include <file.h>` searches only the include paths, while `#include "file.h"` looks next to
+//!   the file that includes it first, and then works as usual.
+//! - `#pragma once` stops the file it is in from being #included again.
+//! - The expressions of #if, #elif and #assert are evaluated as C does with
+//!   [`expr::evaluate_c`]: macros that aren't defined or are empty are 0, integers starting with
+//!   `0` are octal, and macros whose values are expressions are evaluated, so `#if VERSION >=
+//!   0x0200` works when `VERSION` is defined as `(MAJOR * 256 + MINOR)`.
+//!
+//! `defined NAME` without parentheses and C's integer suffixes such as `10UL` are accepted in every
+//! mode.
+//!
+//! `#ifexec [command]` is like #ifdef, but runs the command as #exec does and takes the block if
+//! the command exits successfully, discarding its output. This lets input fall back to something
+//! else when an optional tool isn't installed, as in `#ifexec which sassc`. Like #exec, it causes
+//! an error unless exec is allowed, and in a dry run the block is always taken.
+//!
+//! To choose between several blocks based on the value of a macro, use #switch:
+//! ```text
+//! #switch TARGET
+//! #case dev staging
+//! debug = true
+//! #case prod
+//! debug = false
+//! #default
+//! debug = maybe
+//! #endswitch
+//! ```
+//! Each #case lists the values it matches, separated by whitespace, and only the first case that
+//! matches is output. #default matches any value, including when the macro isn't defined; without
+//! parameters, #default is part of the switch rather than defining a macro.
 //!
 //! ## #exec, #in and #endin
 //!
 //! The exec command executes the given command with `cmd /C` for Windows and `sh -c` for
 //! everything else, and captures the command's standard output. For example, `#exec echo Hi!` will
-//! output `Hi!`. It does not capture the command's standard error, and parsing stops if the
-//! command exits with a nonzero status.
+//! output `Hi!`. Parsing stops if the command exits with a nonzero status, and the error includes
+//! what the command wrote to its standard error, which is otherwise discarded.
+//!
+//! On Windows, commands can be run with PowerShell instead of `cmd` by setting the context's
+//! `windows_shell`, which makes pipelines of objects and most of the syntax of modern scripts
+//! available. It is never chosen automatically, as the same command can mean something different
+//! to each shell.
 //!
 //! Due to the security risk enabling #exec causes, by default exec is disabled, however you can
 //! enable it by changing the `allow_exec` flag in your context. If the input tries to `#exec` when
-//! exec is disabled, it will cause an error.
+//! exec is disabled, it will cause an error. To check input you don't trust before allowing exec,
+//! set the context's `dry_run` flag, which lists the commands that would be run without running
+//! them. To check or replace the commands that are run, for example in tests, use
+//! `Context::on_exec`.
+//!
+//! Macros aren't replaced in the commands that #exec and #in run unless the context's
+//! `expand_exec` flag is set, which allows commands like `#exec convert IMAGE -resize SIZE
+//! out.png`. Only set it if you trust where every macro's value comes from, as a value containing
+//! shell syntax such as `; rm -rf ~` is run as part of the command. With delimiters, the
+//! `shquote` filter described under #define makes a value safe to use as one argument, as in
+//! `#exec convert ${IMAGE|shquote} out.png`.
+//!
+//! A command that outputs far more than expected, such as an accidental `yes`, would otherwise use
+//! up all the memory it is collected into. Setting the context's `max_exec_output` kills a command
+//! that outputs more than that many bytes and fails with an error instead.
+//!
+//! Similarly, a command in an #in block that never finishes, perhaps because it is waiting for
+//! something else, would make processing hang. Setting the context's `in_timeout` limits how long
+//! #endin waits for the command after its input is written, and fails with an error if it takes
+//! longer.
+//!
+//! WebAssembly targets cannot spawn processes, so on them #exec and #in always cause an error, and
+//! the code that starts processes isn't compiled. Nor can they read files, so #included files must
+//! be provided by the context's `resolver` or `on_include` hook. For security-sensitive uses you
+//! can also disable the default `exec` feature, which removes #exec, #in and #endin from gpp
+//! entirely so that no input can ever spawn a process.
 //!
 //! The in command is similar to exec, but all text until the endin command is passed into the
 //! program's standard input. For example,
@@ -71,11 +366,165 @@
 //! This compiles your scss file into css using Sassc and includes in the HTML every time you
 //! generate your webpage with gpp.
 //!
+//! To run a script with a particular interpreter, name it after a colon, as in `#exec:python3` or
+//! `#in:node`. The program is run directly rather than by the shell, so the script needs no
+//! quoting: #exec feeds its parameters to the program's standard input, while #in passes its
+//! parameters to the program as arguments, split at whitespace, and feeds it the block.
+//! ```text
+//! #in:python3
+//! for i in range(3):
+//!     print(f"<li>{i}</li>")
+//! #endin
+//! ```
+//!
+//! ## Indentation
+//!
+//! If the context's `reindent` flag is set, commands may be indented with spaces and tabs as with
+//! `indented_commands`, and every line they output is indented to match. This is useful for
+//! including files in formats where indentation is significant, such as YAML or Python:
+//! ```text
+//! services:
+//!   #include service.yaml
+//! ```
+//!
+//! ## Whitespace control
+//!
+//! A `-` before a command's name, as in `#-endif`, removes the line ending before the command's
+//! line from the output, and a `-` after it, as in `#include- footer.txt`, removes the line
+//! ending at the end of the command's output. This keeps blocks of commands from leaving blank
+//! lines or stray newlines in tightly formatted output such as JSON:
+//! ```text
+//! {"name": "gpp"
+//! #-ifdef VERSION
+//! , "version": "VERSION"
+//! #-endif
+//! }
+//! ```
+//! A line ending is only removed if the command isn't in an inactive block.
+//! Line endings that have already been piped to an #in command's process can't be removed.
+//!
+//! ## Line continuation
+//!
+//! When the context's `line_continuation` flag is set, a text line that ends in a backslash is
+//! joined with the line after it before its macros are replaced, removing the backslash and the
+//! line ending. This lets a long line be wrapped in the input but output as one line:
+//! ```text
+//! #pragma line_continuation on
+//! This sentence is written on two lines, \
+//! but output on one.
+//! ```
+//! As in C, whitespace before the backslash is kept, and the next line is joined even if it looks
+//! like a command. Command lines and lines in #raw blocks that end in a backslash are left as
+//! they are.
+//!
 //! ## Literal hashes
 //!
 //! In order to insert literal hash symbols at the start of the line, simply use two hashes.
 //! `##some text` will convert into `#some text`, while `#some text` will throw an error as `some`
-//! is not a command.
+//! is not a command. When commands may be indented, the same applies after the indentation, so
+//! `  ##some text` will convert into `  #some text`.
+//!
+//! ## Classic GPP
+//!
+//! Templates written for the original [GPP](https://logological.org/gpp) tool that only use the
+//! features the two share can be read by building a context with `ContextBuilder::classic_gpp`,
+//! which takes the sequences that surround macros and commands, like GPP's `-U` and `-M` options.
+//! For example, GPP's HTML mode is:
+//! ```
+//! let mut context = gpp::Context::builder()
+//!     .classic_gpp(
+//!         Some(gpp::Delimiters::new("<#", ">")),
+//!         gpp::Delimiters::new("<#", ">"),
+//!     )
+//!     .build()
+//!     .unwrap();
+//!
+//! assert_eq!(
+//!     gpp::process_str("<#define TITLE Home>\n<h1><#TITLE></h1>\n", &mut context).unwrap(),
+//!     "<h1>Home</h1>\n",
+//! );
+//! ```
+//! Commands must still take up a whole line, and the [C compatibility](#c-compatibility) mode is
+//! turned on so that `#include <file>` and `#if` work as they do in GPP.
+//!
+//! This is not a full emulation of GPP. Only macros without arguments and the commands that both
+//! have, #define, #undef, #include, #exec, #if, #ifdef, #ifndef, #elif, #else and #endif, can be
+//! used. Templates that use macros with arguments or GPP's other commands, such as #ifeq, #ifneq,
+//! #defeval, #eval and #mode, must be rewritten first. As unknown commands are passed through as
+//! text in this mode, those commands are output as they are rather than causing an error.
+//!
+//! ## Encodings
+//!
+//! With the `encoding` feature, the [`encoding`](crate::encoding) module can process input in
+//! encodings other than UTF-8, detecting byte order marks automatically.
+//!
+//! ## Git
+//!
+//! With the `git` feature, the [`git`](crate::git) module provides macros such as `__GIT_HASH__`
+//! and `__GIT_BRANCH__` that describe the repository a file is in.
+//!
+//! ## Tracing
+//!
+//! With the `tracing` feature, gpp reports what it is doing through the
+//! [`tracing`](https://docs.rs/tracing) crate, so it shows up in whatever subscriber the
+//! application uses. Each file and #included file is processed inside a `file` span, and there are
+//! events for each command that is run, each child process that is started and, at the trace
+//! level, each macro that is expanded.
+//!
+//! ## Error recovery
+//!
+//! Normally the first error stops processing. If the context's `error_marker` is set, a line that
+//! fails is instead replaced by the marker, with `{error}` in it replaced by the error's message,
+//! and processing carries on. The errors are collected in the context's `state.recovered_errors`.
+//! This lets a document that mostly works be previewed while its errors are fixed:
+//! ```
+//! let mut context = gpp::Context::builder()
+//!     .error_marker("<GPP ERROR: {error}>")
+//!     .build()
+//!     .unwrap();
+//!
+//! assert_eq!(
+//!     gpp::process_str("Before\n#bad\nAfter\n", &mut context).unwrap(),
+//!     "Before\n<GPP ERROR: Error in <string>:1: Invalid command 'bad'>\nAfter\n",
+//! );
+//! assert_eq!(context.state.recovered_errors.len(), 1);
+//! ```
+//!
+//! ## Diagnostics
+//!
+//! With the `miette` feature, errors implement [miette](https://docs.rs/miette)'s `Diagnostic`
+//! trait, and the [`diagnostic`](crate::diagnostic) module can show the lines that caused an error
+//! along with the #includes that led to them. The `gpp` binary uses it to print errors when built
+//! with this feature.
+//!
+//! ## Build scripts
+//!
+//! The [`build`] module contains a small builder API for preprocessing files from a build script
+//! into `OUT_DIR`.
+//!
+//! ## Caching
+//!
+//! The [`cache`] module records the files that each output was generated from, so that outputs
+//! whose inputs, #included files and settings haven't changed can be skipped on the next run.
+//!
+//! ## mdBook
+//!
+//! With the `mdbook` feature, the `gpp-mdbook` binary is an
+//! [mdBook](https://rust-lang.github.io/mdBook/) preprocessor that runs every chapter of a book
+//! through gpp, sharing one context between them. #includes are found relative to the chapter's
+//! directory, then the book's source directory, then any `include-paths` given in `book.toml`.
+//! Lines that start with hashes and a space are Markdown headings rather than commands, and lines
+//! like `#[derive(Debug)]` that aren't known commands are left alone, but a line such as
+//! `#include <stdio.h>` in a code block must be written with two hashes.
+//! ```toml
+//! [preprocessor.gpp]
+//! command = "gpp-mdbook"
+//! allow-exec = false
+//! include-paths = ["snippets"]
+//!
+//! [preprocessor.gpp.defines]
+//! VERSION = "1.0"
+//! ```
 //!
 //! # Examples
 //!
@@ -118,13 +567,58 @@
 #[cfg(test)]
 mod tests;
 
+#[cfg(not(target_family = "wasm"))]
+pub mod build;
+#[cfg(not(target_family = "wasm"))]
+pub mod cache;
+mod date;
+#[cfg(feature = "miette")]
+pub mod diagnostic;
+#[cfg(feature = "encoding")]
+pub mod encoding;
+pub mod expr;
+mod filter;
+#[cfg(all(feature = "git", not(target_family = "wasm")))]
+pub mod git;
+pub mod lang;
+#[cfg(not(target_family = "wasm"))]
+mod sha256;
+
+use std::borrow::Cow;
+#[cfg(not(target_family = "wasm"))]
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::error;
 use std::fmt;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
-use std::process::{Child, Command as SystemCommand, ExitStatus, Stdio};
+#[cfg(not(target_family = "wasm"))]
+use std::fs::{self, File};
+use std::hash::Hash;
+#[cfg(not(target_family = "wasm"))]
+use std::hash::Hasher;
+#[cfg(not(target_family = "wasm"))]
+use std::io::BufReader;
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+use std::io::Write;
+use std::io::{self, BufRead, Read};
+use std::mem;
+use std::ops::{Deref, DerefMut, Range, RangeInclusive};
+#[cfg(not(target_family = "wasm"))]
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+use std::process::{Child, ChildStdout, Command as SystemCommand, Stdio};
 use std::string::FromUtf8Error;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use aho_corasick::AhoCorasick;
+
+#[cfg(all(feature = "encoding", not(target_family = "wasm")))]
+use crate::encoding::decode_included;
+use crate::expr::{ExprError, Value};
 
 /// Context of the current processing.
 ///
@@ -138,18 +632,180 @@ use std::string::FromUtf8Error;
 /// can set variable names not possible with #defines. However, when replacing variable names in
 /// text the variable name must be surrounded by two characters that are **not** alphanumeric or an
 /// underscore.
+///
+/// A context is made of its `settings`, which it dereferences to so that they can be used like
+/// `context.macros`, and the `state` of the input being processed.
 #[derive(Debug, Default)]
 pub struct Context {
+    /// The macros, settings and hooks that processing uses.
+    pub settings: Settings,
+    /// The state of the input being processed.
+    pub state: RunState,
+}
+
+/// The macros, settings and hooks of a context, which are kept when it is forked.
+#[derive(Debug, Default, Clone)]
+pub struct Settings {
     /// Map of all currently defined macros.
     pub macros: HashMap<String, String>,
+    /// A cache used to quickly skip text that doesn't contain any macros. It is rebuilt
+    /// automatically whenever `macros` changes.
+    macro_matcher: MacroMatcher,
+    /// Whether #exec and #in commands are allowed. This has no effect without the `exec` feature.
+    pub allow_exec: bool,
+    /// Whether macros in the commands run by #exec and #in are replaced before the commands are
+    /// run. This is off by default, because a macro whose value comes from outside the input,
+    /// such as one defined on the command line, could then inject arbitrary shell commands.
+    pub expand_exec: bool,
+    /// The most bytes that the command run by an #exec or #in can output. A command that outputs
+    /// more is killed, and processing fails. If this is `None`, there is no limit.
+    pub max_exec_output: Option<usize>,
+    /// How long an #endin waits for its command to finish after the block's input has been
+    /// written. A command that takes longer is killed, and processing fails. If this is `None`,
+    /// it waits for as long as the command takes.
+    pub in_timeout: Option<Duration>,
+    /// The shell that #exec and #in commands are run with on Windows. This has no effect on other
+    /// platforms, which always use `sh`.
+    pub windows_shell: WindowsShell,
+    /// Whether to check the input without running any commands. In a dry run, #exec and #in don't
+    /// run their commands or output anything, even if exec is not allowed; instead, their commands
+    /// are added to `state.dry_run_commands`.
+    pub dry_run: bool,
+    /// If this is set, a line that fails doesn't stop processing. Instead, the error is added to
+    /// `state.recovered_errors` and this marker is output in place of the line, with `{error}`
+    /// replaced by the error's message. This is useful for previewing a document while fixing its
+    /// errors.
+    pub error_marker: Option<String>,
+    /// Whether text lines that end in `\r\n` keep that line ending in the output, instead of
+    /// being normalized to `\n`.
+    pub preserve_line_endings: bool,
+    /// Whether a final text line without a line ending is output without one, instead of having a
+    /// `\n` added.
+    pub preserve_missing_newline: bool,
+    /// Whether a UTF-8 byte order mark at the start of the input is kept in the output. It is
+    /// always removed before processing so that it doesn't interfere with commands.
+    pub preserve_bom: bool,
+    /// The delimiters that macros must be surrounded by in text, such as `${` and `}`. If this is
+    /// `None`, macros are replaced wherever they appear as a whole word.
+    pub delimiters: Option<Delimiters>,
+    /// The delimiters that commands are written between, such as `<!--#` and `-->`. A line is a
+    /// command if it starts with the prefix and ends with the suffix, ignoring whitespace at the
+    /// end. If this is `None`, commands start with `#`.
+    pub command_delimiters: Option<Delimiters>,
+    /// The marker that stops the macro written straight after it from being replaced, such as `\`.
+    /// The marker is removed from the output, so `\Foo` is output as `Foo` when `Foo` is a macro.
+    pub macro_escape: Option<String>,
+    /// Which macro is replaced when the names of several macros start at the same place in text.
+    pub precedence: Precedence,
+    /// The names of the macros defined by commands, from the earliest defined to the latest. This
+    /// is only kept up to date while `precedence` is `Precedence::DefinitionOrder`.
+    definition_order: Vec<String>,
+    /// Whether commands may be preceded by spaces and tabs.
+    pub indented_commands: bool,
+    /// Where whitespace may be written in commands.
+    pub command_spacing: CommandSpacing,
+    /// The marker that starts a comment at the end of a command, such as `//`. The marker must be
+    /// at the start of the command's parameters or preceded by whitespace, and it and everything
+    /// after it are ignored.
+    pub directive_comment: Option<String>,
+    /// Whether lines that look like commands but whose command is unknown are treated as text,
+    /// instead of causing an error.
+    pub pass_unknown_commands: bool,
+    /// Whether files written for the C preprocessor can be processed, which makes `#include <file>`
+    /// search only the include paths and evaluates #if expressions as C does.
+    pub c_compat: bool,
+    /// Whether a text line that ends in a backslash is joined with the next line.
+    pub line_continuation: bool,
+    /// Whether commands may be preceded by spaces and tabs, in which case every line they output is
+    /// indented by the same whitespace.
+    pub reindent: bool,
+    /// The time that #date outputs. If this is `None`, the current time is used.
+    pub date: Option<SystemTime>,
+    /// Directories to search for #included files that aren't found in the current directory.
+    pub include_paths: Vec<PathBuf>,
+    /// Names that the paths of #included files can start with, each followed by the directory it
+    /// stands for. A path whose first component is the name of an alias is looked for in that
+    /// directory only.
+    pub include_aliases: Vec<(String, PathBuf)>,
+    /// Statistics about the processing done with this context. They are only collected if this is
+    /// `Some`.
+    pub stats: Option<Stats>,
+    /// The #included files that have already been read, so that files included many times are
+    /// only read once. Files are only cached if this is `Some`.
+    pub include_cache: Option<IncludeCache>,
+    /// Where to read #included files from. If this is `None`, they are read from the filesystem.
+    pub resolver: Option<Arc<Mutex<dyn Resolver>>>,
+    /// The name that standard input is given in errors when it is read by `#include -`, such as
+    /// the name of the file that is being piped in. If this is `None`, it is called `<stdin>`.
+    pub stdin_name: Option<String>,
+    /// The encoding that #included files are in, unless they start with a byte order mark. If this
+    /// is `None`, they are read as UTF-8.
+    #[cfg(feature = "encoding")]
+    pub encoding: Option<&'static encoding::Encoding>,
+    /// Called with every line of input before it is processed.
+    pub pre_line: Option<Hook<LineHook>>,
+    /// Called with the output of every line after it is processed.
+    pub post_line: Option<Hook<LineHook>>,
+    /// Called with the name of every #included file before it is read.
+    pub on_include: Option<Hook<IncludeHook>>,
+    /// Called with the command of every #exec and #in before it is run.
+    #[cfg(feature = "exec")]
+    pub on_exec: Option<Hook<ExecHook>>,
+    /// Called as each buffer is opened and closed and each command is run, for debugging.
+    pub on_trace: Option<Hook<TraceHook>>,
+}
+
+/// The state of the input that a context is processing, such as the blocks it is in and the
+/// processes it is running, which is left behind when the context is forked.
+#[derive(Debug, Default)]
+pub struct RunState {
+    /// The names of the macros that the current file has marked with #export.
+    pub exports: Vec<String>,
     /// Number of layers of inactive if statements.
     pub inactive_stack: u32,
+    /// Number of layers of if statements, whether they are active or not.
+    pub if_depth: u32,
     /// Whether the current if statement has been accepted.
     pub used_if: bool,
-    /// Whether #exec and #in commands are allowed.
-    pub allow_exec: bool,
+    /// The #switch blocks the parser is in, innermost last.
+    pub switch_stack: Vec<Switch>,
+    /// Whether a command written as `#-name` has asked for the line ending at the end of the
+    /// output so far to be removed. `process_line_into` and the functions that wrap it remove it
+    /// and clear this before adding the output of the line.
+    pub trim_output: bool,
+    /// Number of layers of #comment blocks the parser is in.
+    pub comment_depth: u32,
+    /// Whether the parser is in a #raw block.
+    pub in_raw: bool,
+    /// The commands that would have been run by #exec and #in during a dry run, in order.
+    pub dry_run_commands: Vec<String>,
+    /// The errors that have been replaced by `error_marker`, in order.
+    pub recovered_errors: Vec<Error>,
     /// The stack of processes that #in is piping to.
-    pub in_stack: Vec<Child>,
+    #[cfg(feature = "exec")]
+    pub in_stack: Vec<InBlock>,
+    /// The stack of #capture blocks that output is being stored for.
+    pub capture_stack: Vec<Capture>,
+    /// Every file that has been opened for processing, in the order they were first opened.
+    pub dependencies: Vec<PathBuf>,
+    /// The files that are being processed, from the input to the innermost #included file.
+    pub file_stack: Vec<PathBuf>,
+    /// The canonical paths of the files that have run `#pragma once`, which aren't #included again.
+    pub once_files: Vec<PathBuf>,
+}
+
+impl Deref for Context {
+    type Target = Settings;
+
+    fn deref(&self) -> &Settings {
+        &self.settings
+    }
+}
+
+impl DerefMut for Context {
+    fn deref_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
 }
 
 impl Context {
@@ -157,6 +813,10 @@ impl Context {
     pub fn new() -> Self {
         Self::default()
     }
+    /// Create a builder for a context, which checks that its settings are valid.
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::default()
+    }
     /// Create a new empty context with no macros or inactive stack and exec commands allowed.
     pub fn new_exec() -> Self {
         Self::new().exec(true)
@@ -164,7 +824,10 @@ impl Context {
     /// Create a context from a map of macros.
     pub fn from_macros(macros: impl Into<HashMap<String, String>>) -> Self {
         Self {
-            macros: macros.into(),
+            settings: Settings {
+                macros: macros.into(),
+                ..Default::default()
+            },
             ..Default::default()
         }
     }
@@ -172,208 +835,2891 @@ impl Context {
     pub fn from_macros_iter(macros: impl IntoIterator<Item = (String, String)>) -> Self {
         Self::from_macros(macros.into_iter().collect::<HashMap<_, _>>())
     }
+    /// Kill the processes of every #in block that is still open and wait for them to exit, closing
+    /// the blocks.
+    ///
+    /// This is done for the blocks opened by a file when processing it fails, so it is only needed
+    /// when lines are processed one at a time with `process_line` and processing is abandoned
+    /// part way through an #in.
+    #[cfg(feature = "exec")]
+    pub fn kill_children(&mut self) {
+        kill_in_blocks(self, 0);
+    }
+    /// The name that standard input is given in errors, which is the context's `stdin_name` or
+    /// `<stdin>`.
+    pub fn stdin_name(&self) -> &str {
+        self.stdin_name.as_deref().unwrap_or("<stdin>")
+    }
+    /// The names of the macros defined by commands, from the earliest defined to the latest. This
+    /// is only kept up to date while `precedence` is `Precedence::DefinitionOrder`.
+    pub fn definition_order(&self) -> &[String] {
+        &self.definition_order
+    }
+    /// Create a new context with the same macros, settings and hooks as this one, but none of the
+    /// state of the input being processed, such as open blocks, running #in commands,
+    /// dependencies and statistics. The statistics of the new context start empty if this one
+    /// collects them.
+    ///
+    /// As contexts are `Send` and `Sync`, a configured context can be shared between threads that
+    /// each fork it to process a different file.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// let context = Arc::new(gpp::Context::builder().define("NAME", "World").build()?);
+    /// let handles: Vec<_> = ["Hello NAME", "Bye NAME"]
+    ///     .into_iter()
+    ///     .map(|input| {
+    ///         let context = Arc::clone(&context);
+    ///         thread::spawn(move || gpp::process_str(input, &mut context.fork()))
+    ///     })
+    ///     .collect();
+    ///
+    /// let outputs: Vec<String> = handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Result<_, _>>()?;
+    /// assert_eq!(outputs, ["Hello World\n", "Bye World\n"]);
+    /// # Ok::<(), gpp::Error>(())
+    /// ```
+    pub fn fork(&self) -> Self {
+        Self {
+            settings: Settings {
+                stats: self.stats.as_ref().map(|_| Stats::default()),
+                include_cache: self.include_cache.as_ref().map(|_| IncludeCache::default()),
+                ..self.settings.clone()
+            },
+            state: RunState::default(),
+        }
+    }
+    /// Add the macros and include paths of another context to this one, using `policy` to decide
+    /// what happens to macros that both define differently. The other settings of this context are
+    /// kept. If the policy is `MergePolicy::Error` and there is a conflict, this context is left
+    /// unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use gpp::{Context, MergePolicy};
+    ///
+    /// let mut context = Context::builder().define("MODE", "dev").define("NAME", "app").build()?;
+    /// let overrides = Context::builder().define("MODE", "prod").build()?;
+    ///
+    /// context.merge(overrides, MergePolicy::Overwrite)?;
+    /// assert_eq!(context.macros["MODE"], "prod");
+    /// assert_eq!(context.macros["NAME"], "app");
+    /// # Ok::<(), gpp::Error>(())
+    /// ```
+    pub fn merge(&mut self, other: Context, policy: MergePolicy) -> Result<(), Error> {
+        if policy == MergePolicy::Error {
+            if let Some(name) = other.macros.iter().find_map(|(name, value)| {
+                let conflict = self.macros.get(name).is_some_and(|ours| ours != value);
+                conflict.then_some(name)
+            }) {
+                return Err(Error::MacroConflict { name: name.clone() });
+            }
+        }
+        for (name, value) in other.settings.macros {
+            if policy == MergePolicy::Keep && self.macros.contains_key(&name) {
+                continue;
+            }
+            define_macro(self, name, value);
+        }
+        for path in other.settings.include_paths {
+            if !self.include_paths.contains(&path) {
+                self.include_paths.push(path);
+            }
+        }
+        for alias in other.settings.include_aliases {
+            if !self
+                .include_aliases
+                .iter()
+                .any(|(name, _)| *name == alias.0)
+            {
+                self.include_aliases.push(alias);
+            }
+        }
+        Ok(())
+    }
+    /// Whether a macro is defined.
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+    }
+    /// Iterate over the names and values of the macros, sorted by name.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let mut context = gpp::Context::new();
+    /// gpp::process_str("#define B 2\n#define A 1\n", &mut context).unwrap();
+    ///
+    /// let macros: Vec<_> = context.sorted_macros().collect();
+    /// assert_eq!(macros, [("A", "1"), ("B", "2")]);
+    /// ```
+    pub fn sorted_macros(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.macros_with_prefix("")
+    }
+    /// Iterate over the names and values of the macros whose names start with `prefix`, sorted by
+    /// name. This is useful for finding the macros in a namespace, with a prefix such as `theme.`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let mut context = gpp::Context::new();
+    /// gpp::process_str("#define theme.fg black\n#define theme.bg white\n#define title Home\n", &mut context).unwrap();
+    ///
+    /// let theme: Vec<_> = context.macros_with_prefix("theme.").collect();
+    /// assert_eq!(theme, [("theme.bg", "white"), ("theme.fg", "black")]);
+    /// ```
+    pub fn macros_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> {
+        let mut macros: Vec<(&str, &str)> = self
+            .macros
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        macros.sort_unstable();
+        macros.into_iter()
+    }
     /// Set whther exec commands are allowed.
     pub fn exec(mut self, allow_exec: bool) -> Self {
         self.allow_exec = allow_exec;
         self
     }
+    /// Set a function to call with every line of input before it is processed, including the
+    /// lines of included files. It can return a different line to process instead, or `None` to
+    /// skip the line.
+    ///
+    /// Lines that aren't valid UTF-8 are not passed to the function.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // Treat lines starting with `%` as commands.
+    /// let mut context = gpp::Context::new().pre_line(|line, _| match line.strip_prefix('%') {
+    ///     Some(command) => Some(format!("#{}", command)),
+    ///     None => Some(line.to_owned()),
+    /// });
+    ///
+    /// assert_eq!(gpp::process_str("%define A B\nA\n", &mut context).unwrap(), "B\n");
+    /// ```
+    pub fn pre_line(
+        mut self,
+        hook: impl Fn(&str, &mut Context) -> Option<String> + Send + 'static,
+    ) -> Self {
+        self.pre_line = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Set a function to call with the output of every line after it is processed. It can return
+    /// different output to use instead, or `None` to remove the output.
+    ///
+    /// Lines with no output, such as #defines, and output that isn't valid UTF-8 are not passed to
+    /// the function. The lines of an included file are passed to it one by one, rather than the
+    /// output of the #include as a whole.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let mut context = gpp::Context::new().post_line(|output, _| Some(output.to_uppercase()));
+    ///
+    /// assert_eq!(gpp::process_str("#define A b\nA c\n", &mut context).unwrap(), "B C\n");
+    /// ```
+    pub fn post_line(
+        mut self,
+        hook: impl Fn(&str, &mut Context) -> Option<String> + Send + 'static,
+    ) -> Self {
+        self.post_line = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Set a function to call with the name of every #included file before it is read. It can
+    /// return the contents of the file, or `None` to read the file from the resolver or the
+    /// filesystem as usual.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let mut context = gpp::Context::new().on_include(|name, _| match name {
+    ///     "VERSION" => Some(Ok("1.0.0\n".to_owned())),
+    ///     _ => None,
+    /// });
+    ///
+    /// assert_eq!(gpp::process_str("#include VERSION", &mut context).unwrap(), "1.0.0\n");
+    /// ```
+    pub fn on_include(
+        mut self,
+        hook: impl Fn(&str, &mut Context) -> Option<Result<String, Error>> + Send + 'static,
+    ) -> Self {
+        self.on_include = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Set a function to call instead of running the command of every #exec and #in, which is
+    /// useful for testing and sandboxing. It is given the command and, for #in, the input to the
+    /// command. It can return the command's output, an error to stop the command from running, or
+    /// `None` to run the command as usual.
+    ///
+    /// Exec commands must still be allowed for the function to be called. While the function is
+    /// set, the input of an #in is collected and only given to the command at its #endin.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let mut context = gpp::Context::new_exec().on_exec(|command, input, _| match command {
+    ///     "date" => Some(Ok("Today\n".to_owned())),
+    ///     "wc -l" => Some(Ok(format!("{}\n", input.unwrap_or("").lines().count()))),
+    ///     _ => Some(Err(gpp::Error::ExecDisabled { command: "exec" })),
+    /// });
+    ///
+    /// assert_eq!(gpp::process_str("#exec date\n#in wc -l\na\nb\n#endin", &mut context).unwrap(), "Today\n2\n");
+    /// assert!(gpp::process_str("#exec rm -rf /", &mut context).is_err());
+    /// ```
+    #[cfg(feature = "exec")]
+    pub fn on_exec(
+        mut self,
+        hook: impl Fn(&str, Option<&str>, &mut Context) -> Option<Result<String, Error>>
+            + Send
+            + 'static,
+    ) -> Self {
+        self.on_exec = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Set a function to call as each file or other buffer is opened and closed, and with each
+    /// command that is run along with where it is. For conditionals, it is also told whether the
+    /// branch was taken. Commands inside inactive blocks aren't reported.
+    ///
+    /// Commands are only reported when they are processed by `process_buf` or one of its wrappers,
+    /// since `process_line` doesn't know where its line came from.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let log = Arc::new(Mutex::new(Vec::new()));
+    /// let hook_log = Arc::clone(&log);
+    /// let mut context = gpp::Context::new().on_trace(move |event, _| {
+    ///     hook_log.lock().unwrap().push(event.to_string());
+    /// });
+    ///
+    /// gpp::process_str("#ifdef A\n#define B\n#endif\n", &mut context).unwrap();
+    /// assert_eq!(
+    ///     *log.lock().unwrap(),
+    ///     ["open <string>", "<string>:0: #ifdef A (not taken)", "<string>:2: #endif", "close <string>"],
+    /// );
+    /// ```
+    pub fn on_trace(mut self, hook: impl Fn(&TraceEvent<'_>, &Context) + Send + 'static) -> Self {
+        self.on_trace = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Record that a file has been opened for processing.
+    #[cfg(not(target_family = "wasm"))]
+    fn add_dependency(&mut self, path: &Path) {
+        if !self
+            .state
+            .dependencies
+            .iter()
+            .any(|dependency| dependency == path)
+        {
+            self.state.dependencies.push(path.to_owned());
+        }
+    }
+    /// Processes a file with `process`, with the file on the context's `state.file_stack`.
+    #[cfg(not(target_family = "wasm"))]
+    pub(crate) fn in_file<T>(
+        &mut self,
+        path: &Path,
+        process: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.state.file_stack.push(path.to_owned());
+        let result = process(self);
+        self.state.file_stack.pop();
+        result
+    }
 }
 
-/// Error enum for parsing errors.
+/// Finds the names of a context's macros in text, so that lines without any can be output
+/// without searching them for each macro in turn.
+#[derive(Debug, Default, Clone)]
+struct MacroMatcher {
+    names: Vec<String>,
+    automaton: Option<AhoCorasick>,
+}
+
+impl MacroMatcher {
+    /// Whether the text might contain the name of one of the macros.
+    fn may_match(&mut self, text: &[u8], macros: &HashMap<String, String>) -> bool {
+        if macros.is_empty() {
+            return false;
+        }
+        if self.names.len() != macros.len()
+            || !self.names.iter().all(|name| macros.contains_key(name))
+        {
+            self.names = macros.keys().cloned().collect();
+            self.automaton = AhoCorasick::new(&self.names).ok();
+        }
+        self.automaton
+            .as_ref()
+            .is_none_or(|automaton| automaton.is_match(text))
+    }
+}
+
+/// A function stored in a context, such as its `pre_line` hook.
 ///
-/// # Examples
+/// Hooks are shared between a context and the contexts forked from it. As they only have to be
+/// `Send`, a hook is called by one thread at a time.
+pub struct Hook<F: ?Sized>(Arc<Mutex<F>>);
+
+impl<F: ?Sized> Hook<F> {
+    /// Locks the hook so that it can be called. A hook that panicked can still be called again.
+    fn lock(&self) -> MutexGuard<'_, F> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<F: ?Sized> Clone for Hook<F> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/// The type of the `pre_line` and `post_line` hooks, which rewrite a line or return `None` to
+/// remove it.
+pub type LineHook = dyn Fn(&str, &mut Context) -> Option<String> + Send;
+
+/// The type of the `on_include` hook, which returns the contents of an included file or `None` to
+/// read it as usual.
+pub type IncludeHook = dyn Fn(&str, &mut Context) -> Option<Result<String, Error>> + Send;
+
+/// The type of the `on_exec` hook, which returns the output of a command or `None` to run it as
+/// usual.
+#[cfg(feature = "exec")]
+pub type ExecHook =
+    dyn Fn(&str, Option<&str>, &mut Context) -> Option<Result<String, Error>> + Send;
+
+/// The type of the `on_trace` hook.
+pub type TraceHook = dyn Fn(&TraceEvent<'_>, &Context) + Send;
+
+/// Something that happened during processing, which is given to the `on_trace` hook.
 ///
-/// ```
-/// let error = gpp::Error::TooManyParameters { command: "my_command" };
-/// assert_eq!(format!("{}", error), "Too many parameters for #my_command");
-/// ```
-/// ```
-/// let error = gpp::Error::FileError {
-///     filename: "my_file".to_string(),
-///     line: 10,
-///     error: Box::new(gpp::Error::UnexpectedCommand {
-///         command: "this_command",
-///     }),
-/// };
-/// assert_eq!(format!("{}", error), "Error in my_file:10: Unexpected command #this_command");
-/// ```
-#[derive(Debug)]
-pub enum Error {
-    /// An unknown command was encountered.
-    InvalidCommand { command_name: String },
-    /// Too many parameters were given for a command (for example using #endif with parameters).
-    TooManyParameters { command: &'static str },
-    /// There was an unexpected command; currently only generated for unexpected #endins.
-    UnexpectedCommand { command: &'static str },
-    /// The child process for an #exec exited with a nonzero status.
-    ChildFailed { status: ExitStatus },
-    /// A pipe was unable to be set up to the child.
-    PipeFailed,
-    /// An error with I/O occurred.
-    IoError(io::Error),
-    /// An error occurred parsing a child's standard output as UTF-8.
-    FromUtf8Error(FromUtf8Error),
-    /// An error occurred in another file.
-    FileError {
-        filename: String,
+/// Its `Display` implementation describes it in a single line, with line numbers counted from zero
+/// in the same way as `Error::FileError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TraceEvent<'a> {
+    /// A file or other buffer, such as an #included file, is about to be processed.
+    Open { filename: &'a str },
+    /// A buffer has finished being processed, whether or not it succeeded.
+    Close { filename: &'a str },
+    /// A command was run.
+    Command {
+        filename: &'a str,
         line: usize,
-        error: Box<Error>,
+        /// The line of the command, without its indentation or line ending.
+        command: &'a str,
+        /// For conditionals, whether the branch they start was taken.
+        taken: Option<bool>,
     },
 }
 
-impl fmt::Display for Error {
+impl fmt::Display for TraceEvent<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Error::InvalidCommand { command_name } => {
-                write!(f, "Invalid command '{}'", command_name)
-            }
-            Error::TooManyParameters { command } => {
-                write!(f, "Too many parameters for #{}", command)
-            }
-            Error::UnexpectedCommand { command } => write!(f, "Unexpected command #{}", command),
-            Error::ChildFailed { status } => write!(f, "Child failed with exit code {}", status),
-            Error::PipeFailed => write!(f, "Pipe to child failed"),
-            Error::IoError(e) => write!(f, "I/O Error: {}", e),
-            Error::FromUtf8Error(e) => write!(f, "UTF-8 Error: {}", e),
-            Error::FileError {
+            TraceEvent::Open { filename } => write!(f, "open {}", filename),
+            TraceEvent::Close { filename } => write!(f, "close {}", filename),
+            TraceEvent::Command {
                 filename,
                 line,
-                error,
-            } => write!(f, "Error in {}:{}: {}", filename, line, error),
+                command,
+                taken,
+            } => {
+                write!(f, "{}:{}: {}", filename, line, command)?;
+                match taken {
+                    Some(true) => f.write_str(" (taken)"),
+                    Some(false) => f.write_str(" (not taken)"),
+                    None => Ok(()),
+                }
+            }
         }
     }
 }
 
-impl error::Error for Error {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match self {
-            Error::IoError(e) => Some(e),
-            Error::FromUtf8Error(e) => Some(e),
-            Error::FileError { error: e, .. } => Some(e),
-            _ => None,
-        }
+/// Gives an event to the context's `on_trace` hook if it has one.
+fn trace(event: TraceEvent<'_>, context: &mut Context) {
+    if let Some(hook) = context.on_trace.take() {
+        (hook.lock())(&event, context);
+        context.on_trace.get_or_insert(hook);
     }
 }
 
-impl From<io::Error> for Error {
-    fn from(e: io::Error) -> Self {
-        Error::IoError(e)
+/// Traces the command on a line that is about to be processed, if there is one and it will run.
+/// Returns whether the command is a conditional, which is instead traced by `trace_conditional`
+/// once the line has been processed and its branch has been decided.
+fn trace_command(context: &mut Context, buf_name: &str, line: &[u8], num: usize) -> bool {
+    if context.on_trace.is_none() || context.state.in_raw || context.state.comment_depth > 0 {
+        return false;
+    }
+    let name = match command_name(line, context) {
+        Some(name) => name,
+        None => return false,
+    };
+    let switch_default = name == "default"
+        && find_command(line, context).is_some_and(|(_, range)| {
+            !line[range].trim_ascii().iter().any(u8::is_ascii_whitespace)
+        });
+    let continues_if = switch_default
+        || matches!(
+            name,
+            "elif" | "elifdef" | "elifndef" | "else" | "endif" | "case" | "endswitch"
+        );
+    if context.state.inactive_stack > u32::from(continues_if) {
+        return false;
+    }
+    if switch_default
+        || matches!(
+            name,
+            "if" | "ifdef"
+                | "ifndef"
+                | "ifexec"
+                | "elif"
+                | "elifdef"
+                | "elifndef"
+                | "else"
+                | "case"
+        )
+    {
+        return true;
     }
+    trace_line(context, buf_name, line, num, None);
+    false
 }
 
-impl From<FromUtf8Error> for Error {
-    fn from(e: FromUtf8Error) -> Self {
-        Error::FromUtf8Error(e)
-    }
+/// Traces a conditional after its line has been processed, with whether its branch was taken.
+fn trace_conditional(context: &mut Context, buf_name: &str, line: &[u8], num: usize) {
+    let taken = context.state.inactive_stack == 0;
+    let line = &line[indent_len(line, context)..];
+    trace_line(context, buf_name, line, num, Some(taken));
 }
 
-fn shell(cmd: &str) -> SystemCommand {
-    let (shell, flag) = if cfg!(target_os = "windows") {
-        ("cmd", "/C")
-    } else {
-        ("/bin/sh", "-c")
-    };
-    let mut command = SystemCommand::new(shell);
-    command.args(&[flag, cmd]);
-    command
+fn trace_line(context: &mut Context, buf_name: &str, line: &[u8], num: usize, taken: Option<bool>) {
+    let command = String::from_utf8_lossy(line);
+    trace(
+        TraceEvent::Command {
+            filename: buf_name,
+            line: num,
+            command: command.trim_end_matches(['\r', '\n']),
+            taken,
+        },
+        context,
+    );
 }
 
-fn process_exec(line: &str, _: &mut Context) -> Result<String, Error> {
-    let output = shell(line).output()?;
-    if !output.status.success() {
-        return Err(Error::ChildFailed {
-            status: output.status,
+/// The threads that read the output and standard error of an #in block's child while its input is
+/// being written.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+#[derive(Debug)]
+pub struct OutputReader {
+    stdout: thread::JoinHandle<io::Result<Vec<u8>>>,
+    stderr: Option<thread::JoinHandle<String>>,
+}
+
+/// An #in block that is currently running.
+#[cfg(feature = "exec")]
+#[derive(Debug)]
+pub enum InBlock {
+    /// The command's process, which its input is piped to, the command it runs, and the thread
+    /// that reads its output while the input is being written.
+    #[cfg(not(target_family = "wasm"))]
+    Child {
+        child: Child,
+        command: String,
+        output: OutputReader,
+    },
+    /// The command and the input collected so far, for when the context has an `on_exec` hook.
+    /// `shell` is false if the command is an interpreter and its arguments from `#in:program`.
+    Buffered {
+        command: String,
+        input: Vec<u8>,
+        shell: bool,
+    },
+}
+
+/// A builder for a context.
+///
+/// Unlike setting the fields of a context directly, the builder checks that the settings make sense
+/// together when the context is built, and new settings can be added to it without breaking code
+/// that uses it. The context that is built is an ordinary `Context`, so its fields can still be
+/// changed afterwards; those changes aren't checked.
+///
+/// # Examples
+///
+/// ```
+/// let mut context = gpp::Context::builder()
+///     .define("Foo", "Bar")
+///     .delimiters(gpp::Delimiters::new("{{", "}}"))
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(gpp::process_str("Foo is {{Foo}}", &mut context).unwrap(), "Foo is Bar\n");
+/// ```
+/// ```
+/// let result = gpp::Context::builder()
+///     .delimiters(gpp::Delimiters::new("#", ""))
+///     .build();
+///
+/// assert!(matches!(result, Err(gpp::Error::InvalidSettings { .. })));
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct ContextBuilder {
+    settings: Settings,
+}
+
+impl ContextBuilder {
+    /// Define a macro.
+    pub fn define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        define_macro(&mut self.settings, name.into(), value.into());
+        self
+    }
+    /// Set whether exec commands are allowed.
+    pub fn exec(mut self, allow_exec: bool) -> Self {
+        self.settings.allow_exec = allow_exec;
+        self
+    }
+    /// Set whether macros are expanded in the commands run by #exec and #in. See
+    /// `Context::expand_exec`.
+    pub fn expand_exec(mut self, expand_exec: bool) -> Self {
+        self.settings.expand_exec = expand_exec;
+        self
+    }
+    /// Set the most bytes that the command run by an #exec or #in can output. See
+    /// `Context::max_exec_output`.
+    pub fn max_exec_output(mut self, limit: usize) -> Self {
+        self.settings.max_exec_output = Some(limit);
+        self
+    }
+    /// Set how long an #endin waits for its command to finish. See `Context::in_timeout`.
+    pub fn in_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.in_timeout = Some(timeout);
+        self
+    }
+    /// Output a marker in place of lines that fail instead of stopping. See
+    /// `Context::error_marker`.
+    pub fn error_marker(mut self, marker: impl Into<String>) -> Self {
+        self.settings.error_marker = Some(marker.into());
+        self
+    }
+    /// Set the shell that #exec and #in commands are run with on Windows. See
+    /// `Context::windows_shell`.
+    pub fn windows_shell(mut self, shell: WindowsShell) -> Self {
+        self.settings.windows_shell = shell;
+        self
+    }
+    /// Add a directory to search for #included files.
+    pub fn include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.settings.include_paths.push(dir.into());
+        self
+    }
+    /// Add an alias that #included paths can start with to refer to a directory, such as `@root`.
+    pub fn include_alias(mut self, name: impl Into<String>, dir: impl Into<PathBuf>) -> Self {
+        self.settings
+            .include_aliases
+            .push((name.into(), dir.into()));
+        self
+    }
+    /// Set where to read #included files from instead of the filesystem.
+    pub fn resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+        self.settings.resolver = Some(Arc::new(Mutex::new(resolver)));
+        self
+    }
+    /// Require macros to be written between delimiters.
+    pub fn delimiters(mut self, delimiters: Delimiters) -> Self {
+        self.settings.delimiters = Some(delimiters);
+        self
+    }
+    /// Set the delimiters that commands are written between, instead of starting with a hash. See
+    /// `Context::command_delimiters`.
+    pub fn command_delimiters(mut self, delimiters: Delimiters) -> Self {
+        self.settings.command_delimiters = Some(delimiters);
+        self
+    }
+    /// Set the marker that stops the macro after it from being replaced. See
+    /// `Context::macro_escape`.
+    pub fn macro_escape(mut self, marker: impl Into<String>) -> Self {
+        self.settings.macro_escape = Some(marker.into());
+        self
+    }
+    /// Set which macro is replaced when several macro names start at the same place.
+    pub fn precedence(mut self, precedence: Precedence) -> Self {
+        self.settings.precedence = precedence;
+        self
+    }
+    /// Set whether to check the input without running any commands. See `Context::dry_run`.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.settings.dry_run = dry_run;
+        self
+    }
+    /// Set whether commands may be preceded by spaces and tabs.
+    pub fn indented_commands(mut self, indented_commands: bool) -> Self {
+        self.settings.indented_commands = indented_commands;
+        self
+    }
+    /// Set where whitespace may be written in commands.
+    pub fn command_spacing(mut self, command_spacing: CommandSpacing) -> Self {
+        self.settings.command_spacing = command_spacing;
+        self
+    }
+    /// Set the marker that starts a comment at the end of a command. See
+    /// `Context::directive_comment`.
+    pub fn directive_comment(mut self, marker: impl Into<String>) -> Self {
+        self.settings.directive_comment = Some(marker.into());
+        self
+    }
+    /// Set whether lines with unknown commands are treated as text instead of causing an error.
+    pub fn pass_unknown_commands(mut self, pass_unknown_commands: bool) -> Self {
+        self.settings.pass_unknown_commands = pass_unknown_commands;
+        self
+    }
+    /// Set whether files written for the C preprocessor can be processed. See `Context::c_compat`.
+    pub fn c_compat(mut self, c_compat: bool) -> Self {
+        self.settings.c_compat = c_compat;
+        self
+    }
+    /// Set whether a text line that ends in a backslash is joined with the next line.
+    pub fn line_continuation(mut self, line_continuation: bool) -> Self {
+        self.settings.line_continuation = line_continuation;
+        self
+    }
+    /// Read templates written for the original GPP tool, in which macros are written between the
+    /// `macros` delimiters, or as whole words if they are `None`, and commands between the
+    /// `commands` delimiters, such as `<#` and `>`. Only the features that gpp shares with GPP
+    /// can be used; see [Classic GPP](crate#classic-gpp).
+    ///
+    /// This also turns on `c_compat`, `indented_commands` and `pass_unknown_commands`, so that a
+    /// macro on a line of its own, like `<#NAME>` in GPP's HTML mode, isn't read as a command.
+    pub fn classic_gpp(mut self, macros: Option<Delimiters>, commands: Delimiters) -> Self {
+        self.settings.delimiters = macros;
+        self.settings.command_delimiters = Some(commands);
+        self.settings.c_compat = true;
+        self.settings.indented_commands = true;
+        self.settings.pass_unknown_commands = true;
+        self
+    }
+    /// Set whether commands may be indented, with their output indented to match.
+    pub fn reindent(mut self, reindent: bool) -> Self {
+        self.settings.reindent = reindent;
+        self
+    }
+    /// Set whether `\r\n` line endings are kept in the output.
+    pub fn preserve_line_endings(mut self, preserve_line_endings: bool) -> Self {
+        self.settings.preserve_line_endings = preserve_line_endings;
+        self
+    }
+    /// Set whether a final line without a line ending is output without one.
+    pub fn preserve_missing_newline(mut self, preserve_missing_newline: bool) -> Self {
+        self.settings.preserve_missing_newline = preserve_missing_newline;
+        self
+    }
+    /// Set whether a UTF-8 byte order mark at the start of the input is kept in the output.
+    pub fn preserve_bom(mut self, preserve_bom: bool) -> Self {
+        self.settings.preserve_bom = preserve_bom;
+        self
+    }
+    /// Set the time that #date outputs, instead of the current time.
+    pub fn date(mut self, date: SystemTime) -> Self {
+        self.settings.date = Some(date);
+        self
+    }
+    /// Set whether to collect statistics about processing in the context's `stats`.
+    pub fn stats(mut self, stats: bool) -> Self {
+        self.settings.stats = stats.then(Stats::default);
+        self
+    }
+    /// Set whether #included files are cached in the context's `include_cache`.
+    pub fn include_cache(mut self, include_cache: bool) -> Self {
+        self.settings.include_cache = include_cache.then(IncludeCache::default);
+        self
+    }
+    /// Set the name that standard input is given in errors. See `Context::stdin_name`.
+    pub fn stdin_name(mut self, name: impl Into<String>) -> Self {
+        self.settings.stdin_name = Some(name.into());
+        self
+    }
+    /// Set the encoding that #included files are in. See `Context::encoding`.
+    #[cfg(feature = "encoding")]
+    pub fn encoding(mut self, encoding: &'static encoding::Encoding) -> Self {
+        self.settings.encoding = Some(encoding);
+        self
+    }
+    /// Set a function to call with every line of input before it is processed. See
+    /// `Context::pre_line`.
+    pub fn pre_line(
+        mut self,
+        hook: impl Fn(&str, &mut Context) -> Option<String> + Send + 'static,
+    ) -> Self {
+        self.settings.pre_line = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Set a function to call with the output of every line after it is processed. See
+    /// `Context::post_line`.
+    pub fn post_line(
+        mut self,
+        hook: impl Fn(&str, &mut Context) -> Option<String> + Send + 'static,
+    ) -> Self {
+        self.settings.post_line = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Set a function to call with the name of every #included file before it is read. See
+    /// `Context::on_include`.
+    pub fn on_include(
+        mut self,
+        hook: impl Fn(&str, &mut Context) -> Option<Result<String, Error>> + Send + 'static,
+    ) -> Self {
+        self.settings.on_include = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Set a function to call instead of running the command of every #exec and #in. See
+    /// `Context::on_exec`.
+    #[cfg(feature = "exec")]
+    pub fn on_exec(
+        mut self,
+        hook: impl Fn(&str, Option<&str>, &mut Context) -> Option<Result<String, Error>>
+            + Send
+            + 'static,
+    ) -> Self {
+        self.settings.on_exec = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Set a function to call as buffers are opened and closed and commands are run. See
+    /// `Context::on_trace`.
+    pub fn on_trace(mut self, hook: impl Fn(&TraceEvent<'_>, &Context) + Send + 'static) -> Self {
+        self.settings.on_trace = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+    /// Build the context, returning an error if the settings conflict with each other.
+    pub fn build(self) -> Result<Context, Error> {
+        let context = Context {
+            settings: self.settings,
+            state: RunState::default(),
+        };
+        check_settings(&context)?;
+        Ok(context)
+    }
+}
+
+/// Checks that the settings of a context don't conflict with each other.
+fn check_settings(context: &Context) -> Result<(), Error> {
+    if let Some(delimiters) = &context.delimiters {
+        if delimiters.prefix.is_empty() {
+            return Err(Error::InvalidSettings {
+                reason: "the macro delimiter prefix is empty".to_owned(),
+            });
+        }
+        if find_command(delimiters.prefix.as_bytes(), context).is_some() {
+            return Err(Error::InvalidSettings {
+                reason: format!(
+                    "the macro delimiter prefix '{}' would be read as a command",
+                    delimiters.prefix
+                ),
+            });
+        }
+    }
+    if context.expand_exec && !context.allow_exec {
+        return Err(Error::InvalidSettings {
+            reason: "macros in exec commands are expanded but exec commands aren't allowed"
+                .to_owned(),
+        });
+    }
+    if context.in_timeout == Some(Duration::ZERO) {
+        return Err(Error::InvalidSettings {
+            reason: "the #in timeout is zero".to_owned(),
+        });
+    }
+    if let Some((name, _)) = context
+        .include_aliases
+        .iter()
+        .find(|(name, _)| name.is_empty() || name.contains('/'))
+    {
+        return Err(Error::InvalidSettings {
+            reason: format!("the include alias '{}' isn't a single path component", name),
+        });
+    }
+    if context
+        .command_delimiters
+        .as_ref()
+        .is_some_and(|delimiters| delimiters.prefix.is_empty())
+    {
+        return Err(Error::InvalidSettings {
+            reason: "the command prefix is empty".to_owned(),
+        });
+    }
+    if context
+        .directive_comment
+        .as_ref()
+        .is_some_and(|marker| marker.is_empty())
+    {
+        return Err(Error::InvalidSettings {
+            reason: "the directive comment marker is empty".to_owned(),
+        });
+    }
+    if context
+        .macro_escape
+        .as_ref()
+        .is_some_and(|marker| marker.is_empty())
+    {
+        return Err(Error::InvalidSettings {
+            reason: "the macro escape marker is empty".to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// The delimiters that macros are written between in text, set with the context's `delimiters`.
+///
+/// When delimiters are set, macros are only replaced where they are written between the prefix and
+/// the suffix, like `${NAME}` or `@NAME@`, and bare macro names are left alone. If the suffix is
+/// empty, the name extends over as many letters, digits, underscores and non-ASCII characters as
+/// follow the prefix. Text between the delimiters that isn't a defined macro is left unchanged.
+///
+/// # Examples
+///
+/// ```
+/// let mut context = gpp::Context::new();
+/// context.delimiters = Some(gpp::Delimiters::new("${", "}"));
+///
+/// assert_eq!(gpp::process_str("#define Foo Bar\nFoo ${Foo} ${Baz}", &mut context).unwrap(), "Foo Bar ${Baz}\n");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Delimiters {
+    /// The text before a macro name. This must not be empty.
+    pub prefix: String,
+    /// The text after a macro name.
+    pub suffix: String,
+}
+
+impl Delimiters {
+    /// Create delimiters from a prefix and a suffix.
+    pub fn new(prefix: impl Into<String>, suffix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            suffix: suffix.into(),
+        }
+    }
+}
+
+/// How to choose between several macros whose names start at the same place in text, such as `a`
+/// and `a.b` in `a.b`. Whichever macro occurs first in the text is always replaced first.
+///
+/// # Examples
+///
+/// ```
+/// let mut context = gpp::Context::new();
+/// context.precedence = gpp::Precedence::DefinitionOrder;
+///
+/// assert_eq!(
+///     gpp::process_str("#define ID.x short\n#define ID.x.y long\nID.x.y\n", &mut context).unwrap(),
+///     "short.y\n",
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Precedence {
+    /// The macro with the longest name wins.
+    #[default]
+    LongestName,
+    /// The macro that was defined first wins, with macros added to `Context::macros` directly
+    /// counting as defined before any others. Redefining a macro makes it the latest defined.
+    DefinitionOrder,
+    /// The macro with the highest priority wins, with macros that aren't in the map having a
+    /// priority of 0. Macros with the same priority are chosen between by the length of their
+    /// names.
+    Priority(HashMap<String, i32>),
+}
+
+/// What `Context::merge` does with a macro that is defined differently in both contexts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Keep the value that the context being merged into has.
+    Keep,
+    /// Use the value from the context being merged in.
+    Overwrite,
+    /// Return an error.
+    Error,
+}
+
+/// Where whitespace may be written in commands, after the hash and between the command's name and
+/// its parameters. Whitespace at the start of the parameters is always ignored.
+///
+/// # Examples
+///
+/// ```
+/// let mut context = gpp::Context::new();
+/// context.command_spacing = gpp::CommandSpacing::Tabs;
+///
+/// assert_eq!(gpp::process_str("#  define\tA 1\nA\n", &mut context).unwrap(), "1\n");
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CommandSpacing {
+    /// The name must come straight after the hash, and is separated from the parameters by a
+    /// space.
+    Strict,
+    /// Spaces and tabs may come after the hash, and the name is separated from the parameters by a
+    /// space.
+    #[default]
+    Spaces,
+    /// Spaces and tabs may come after the hash, and the name is separated from the parameters by a
+    /// space or a tab.
+    Tabs,
+}
+
+/// The shell that #exec and #in commands are run with on Windows.
+///
+/// # Examples
+///
+/// ```
+/// let context = gpp::Context::builder()
+///     .windows_shell(gpp::WindowsShell::Pwsh)
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(context.windows_shell, gpp::WindowsShell::Pwsh);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WindowsShell {
+    /// `cmd /C`.
+    #[default]
+    Cmd,
+    /// `powershell -Command`, the Windows PowerShell that comes with Windows.
+    PowerShell,
+    /// `pwsh -Command`, PowerShell 7 and later, which must be installed separately.
+    Pwsh,
+}
+
+impl WindowsShell {
+    /// The program to run and the arguments that come before the command.
+    #[cfg(all(feature = "exec", not(target_family = "wasm")))]
+    fn program(self) -> (&'static str, &'static [&'static str]) {
+        // Profiles are skipped so that commands run the same way for everyone, and quickly.
+        match self {
+            WindowsShell::Cmd => ("cmd", &["/C"]),
+            WindowsShell::PowerShell => {
+                ("powershell", &["-NoProfile", "-NonInteractive", "-Command"])
+            }
+            WindowsShell::Pwsh => ("pwsh", &["-NoProfile", "-NonInteractive", "-Command"]),
+        }
+    }
+}
+
+/// Splits the text after a command prefix into the command's name and its parameters.
+fn split_command<'a>(text: &'a str, context: &Context) -> (&'a str, &'a str) {
+    let text = match context.command_spacing {
+        CommandSpacing::Strict => text,
+        CommandSpacing::Spaces | CommandSpacing::Tabs => text.trim_start(),
+    };
+    let parts = match context.command_spacing {
+        CommandSpacing::Tabs => text.split_once([' ', '\t']),
+        CommandSpacing::Strict | CommandSpacing::Spaces => text.split_once(' '),
+    };
+    let (name, parameters) = parts.unwrap_or((text, ""));
+    (name, parameters.trim_start())
+}
+
+/// Define a macro, keeping the context's `definition_order` up to date.
+fn define_macro(context: &mut Settings, name: String, value: String) {
+    if context.precedence == Precedence::DefinitionOrder {
+        context.definition_order.retain(|defined| *defined != name);
+        context.definition_order.push(name.clone());
+    }
+    context.macros.insert(name, value);
+}
+
+/// A #switch block that the parser is in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Switch {
+    /// The value of the macro being switched on, or `None` if it isn't defined.
+    pub value: Option<String>,
+    /// Whether one of the block's cases has already been output. This is also set for switches
+    /// inside inactive blocks, so that none of their cases are output.
+    pub matched: bool,
+}
+
+/// A #capture block whose output is being stored in a macro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// The name of the macro the output will be stored in.
+    pub name: String,
+    /// The output captured so far.
+    pub output: Vec<u8>,
+    /// The number of #in commands that were running when the block started.
+    pub in_depth: usize,
+    /// For an #escape block, the filter that the output is passed through before it is output
+    /// instead of being stored in a macro. `name` is empty for these blocks.
+    pub filter: Option<&'static str>,
+}
+
+/// Statistics about processing, collected in a context's `stats`.
+///
+/// The counts include the work done for #included files.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of lines read, including the lines of included files.
+    pub lines: u64,
+    /// The number of times a macro was replaced with its value.
+    pub expansions: u64,
+    /// The number of files that were #included.
+    pub includes: u64,
+    /// The number of commands that were run, not including those in inactive blocks.
+    pub commands: u64,
+    /// The number of child processes started for #exec and #in.
+    pub child_processes: u64,
+    /// The number of bytes output by `process_buf` and the functions that wrap it.
+    pub bytes: u64,
+    /// How long each file and included file took to process, in the order they finished.
+    pub files: Vec<FileTime>,
+}
+
+/// How long a file took to process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileTime {
+    /// The name of the file, as it appears in errors.
+    pub name: String,
+    /// The time it took, including the time taken by the files it included.
+    pub elapsed: Duration,
+}
+
+/// The #included files kept in a context's `include_cache`.
+///
+/// Each file is read once, the first time it is included by any path that leads to it. Its output
+/// is also kept if processing it had no effect on the context, such as defining a macro, opening
+/// an #if or recovering from an error, and no #exec or #in commands were run. Including it again
+/// with the same macros and settings then reuses that output instead of processing it again. Files
+/// are assumed not to change while they are cached, so the cache should be cleared between runs.
+///
+/// # Examples
+///
+/// ```
+/// let mut context = gpp::Context::builder().include_cache(true).build().unwrap();
+/// let output = gpp::process_str("#include tests/include.txt\n#include tests/include.txt\n", &mut context).unwrap();
+/// assert_eq!(output.lines().count(), 2);
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct IncludeCache {
+    files: HashMap<PathBuf, Arc<[u8]>>,
+    outputs: HashMap<(PathBuf, Option<RangeInclusive<usize>>, u64), String>,
+    #[cfg_attr(target_family = "wasm", allow(dead_code))]
+    commands: u64,
+}
+
+impl IncludeCache {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Forget every file and output in the cache.
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.outputs.clear();
+    }
+}
+
+/// A source of files for #include, in place of the filesystem.
+///
+/// This is useful on targets without a filesystem such as WebAssembly, or to serve included files
+/// from memory.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use std::sync::{Arc, Mutex};
+///
+/// let mut files = HashMap::new();
+/// files.insert("header.txt".to_owned(), "Title\n".to_owned());
+///
+/// let mut context = gpp::Context::new();
+/// context.resolver = Some(Arc::new(Mutex::new(files)));
+/// assert_eq!(gpp::process_str("#include header.txt", &mut context).unwrap(), "Title\n");
+/// ```
+pub trait Resolver: fmt::Debug + Send {
+    /// Read the contents of the file that `#include name` refers to.
+    fn resolve(&self, name: &str) -> Result<String, Error>;
+}
+
+impl Resolver for HashMap<String, String> {
+    fn resolve(&self, name: &str) -> Result<String, Error> {
+        self.get(name).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name)).into()
+        })
+    }
+}
+
+/// Error enum for parsing errors.
+///
+/// # Examples
+///
+/// ```
+/// let error = gpp::Error::TooManyParameters { command: "my_command" };
+/// assert_eq!(format!("{}", error), "Too many parameters for #my_command");
+/// ```
+/// ```
+/// let error = gpp::Error::FileError {
+///     filename: "my_file".into(),
+///     line: 10,
+///     error: Box::new(gpp::Error::UnexpectedCommand {
+///         command: "this_command",
+///     }),
+/// };
+/// assert_eq!(format!("{}", error), "Error in my_file:10: Unexpected command #this_command");
+/// ```
+/// ```
+/// let error = gpp::Error::FileError {
+///     filename: "a.txt".into(),
+///     line: 12,
+///     error: Box::new(gpp::Error::FileError {
+///         filename: "b.txt".into(),
+///         line: 3,
+///         error: Box::new(gpp::Error::FileError {
+///             filename: "c.txt".into(),
+///             line: 5,
+///             error: Box::new(gpp::Error::UnexpectedCommand { command: "endif" }),
+///         }),
+///     }),
+/// };
+/// assert_eq!(
+///     format!("{}", error),
+///     "In file included from a.txt:12, from b.txt:3: Error in c.txt:5: Unexpected command #endif",
+/// );
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    /// An unknown command was encountered.
+    InvalidCommand { command_name: String },
+    /// Too many parameters were given for a command (for example using #endif with parameters).
+    TooManyParameters { command: &'static str },
+    /// A command was given a parameter it doesn't accept.
+    InvalidParameter {
+        command: &'static str,
+        parameter: String,
+    },
+    /// There was an unexpected command; currently only generated for unexpected #endins.
+    UnexpectedCommand { command: &'static str },
+    /// A command that requires exec was used while exec is disabled.
+    ExecDisabled { command: &'static str },
+    /// A block such as #ifdef or #in was still open at the end of the file that opened it.
+    UnclosedBlock { command: &'static str },
+    /// A command was used that is not supported on the target platform, such as #exec on
+    /// WebAssembly.
+    Unsupported { command: &'static str },
+    /// The child process for an #exec or #in exited with a nonzero status. `command` is the
+    /// command that it ran, and `stderr` what it wrote to standard error, shortened to its last 4
+    /// KiB if it is longer.
+    ChildFailed {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// A pipe was unable to be set up to the child.
+    PipeFailed,
+    /// A child process for an #exec or #in output more than the context's `max_exec_output`.
+    ExecOutputTooLarge { limit: usize },
+    /// A child process for an #in didn't finish within the context's `in_timeout`.
+    ChildTimeout { timeout: Duration },
+    /// The expression given to a command such as #if or #let couldn't be evaluated.
+    InvalidExpression {
+        command: &'static str,
+        error: ExprError,
+    },
+    /// The expression given to an #assert was false.
+    AssertionFailed { expression: String },
+    /// The settings given to a `ContextBuilder` conflict with each other.
+    InvalidSettings { reason: String },
+    /// Two contexts being merged with `MergePolicy::Error` define a macro differently.
+    MacroConflict { name: String },
+    /// An error with I/O occurred.
+    IoError(io::Error),
+    /// An error occurred parsing a child's standard output as UTF-8.
+    FromUtf8Error(FromUtf8Error),
+    /// An error occurred in another file. If the error happened in a file that was #included,
+    /// `line` is the line of the #include and `error` is another `FileError` for the included
+    /// file.
+    FileError {
+        filename: PathBuf,
+        line: usize,
+        error: Box<Error>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidCommand { command_name } => {
+                write!(f, "Invalid command '{}'", command_name)
+            }
+            Error::TooManyParameters { command } => {
+                write!(f, "Too many parameters for #{}", command)
+            }
+            Error::InvalidParameter { command, parameter } => {
+                write!(f, "Invalid parameter '{}' for #{}", parameter, command)
+            }
+            Error::UnexpectedCommand { command } => write!(f, "Unexpected command #{}", command),
+            Error::ExecDisabled { command } => {
+                write!(f, "#{} is not allowed because exec is disabled", command)
+            }
+            Error::UnclosedBlock { command } => write!(f, "#{} is never closed", command),
+            Error::Unsupported { command } => {
+                write!(f, "#{} is not supported on this target", command)
+            }
+            Error::ChildFailed {
+                command,
+                status,
+                stderr,
+            } => {
+                write!(f, "Command `{}` failed with {}", command, status)?;
+                if !stderr.is_empty() {
+                    write!(f, ":\n{}", stderr)?;
+                }
+                Ok(())
+            }
+            Error::PipeFailed => write!(f, "Pipe to child failed"),
+            Error::ExecOutputTooLarge { limit } => {
+                write!(f, "Child output more than the limit of {} bytes", limit)
+            }
+            Error::ChildTimeout { timeout } => {
+                write!(f, "Child didn't finish within {:?}", timeout)
+            }
+            Error::InvalidExpression { command, error } => {
+                write!(f, "Invalid expression for #{}: {}", command, error)
+            }
+            Error::AssertionFailed { expression } => {
+                write!(f, "Assertion failed: {}", expression)
+            }
+            Error::InvalidSettings { reason } => write!(f, "Invalid settings: {}", reason),
+            Error::MacroConflict { name } => {
+                write!(
+                    f,
+                    "Macro '{}' is defined differently in both contexts",
+                    name
+                )
+            }
+            Error::IoError(e) => write!(f, "I/O Error: {}", e),
+            Error::FromUtf8Error(e) => write!(f, "UTF-8 Error: {}", e),
+            Error::FileError {
+                filename,
+                line,
+                error,
+            } => {
+                // Every file but the innermost one is where the next was #included from.
+                let (mut filename, mut line, mut error) = (filename, line, error);
+                let mut included = false;
+                while let Error::FileError {
+                    filename: inner_filename,
+                    line: inner_line,
+                    error: inner_error,
+                } = &**error
+                {
+                    let prefix = if included {
+                        ", from"
+                    } else {
+                        "In file included from"
+                    };
+                    write!(f, "{} {}:{}", prefix, filename.display(), line)?;
+                    included = true;
+                    (filename, line, error) = (inner_filename, inner_line, inner_error);
+                }
+                if included {
+                    f.write_str(": ")?;
+                }
+                write!(f, "Error in {}:{}: {}", filename.display(), line, error)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::IoError(e) => Some(e),
+            Error::FromUtf8Error(e) => Some(e),
+            Error::FileError { error: e, .. } => Some(e),
+            Error::InvalidExpression { error: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// A short name for the kind of error, such as `invalid_command`, which stays the same between
+    /// versions so that tools can tell errors apart. The code of a `FileError` is the code of the
+    /// error it contains.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidCommand { .. } => "invalid_command",
+            Error::TooManyParameters { .. } => "too_many_parameters",
+            Error::InvalidParameter { .. } => "invalid_parameter",
+            Error::UnexpectedCommand { .. } => "unexpected_command",
+            Error::ExecDisabled { .. } => "exec_disabled",
+            Error::UnclosedBlock { .. } => "unclosed_block",
+            Error::Unsupported { .. } => "unsupported",
+            Error::ChildFailed { .. } => "child_failed",
+            Error::PipeFailed => "pipe_failed",
+            Error::ExecOutputTooLarge { .. } => "exec_output_too_large",
+            Error::ChildTimeout { .. } => "child_timeout",
+            Error::InvalidExpression { .. } => "invalid_expression",
+            Error::AssertionFailed { .. } => "assertion_failed",
+            Error::InvalidSettings { .. } => "invalid_settings",
+            Error::MacroConflict { .. } => "macro_conflict",
+            Error::IoError(_) => "io_error",
+            Error::FromUtf8Error(_) => "from_utf8_error",
+            Error::FileError { error, .. } => error.code(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IoError(e)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Self {
+        Error::FromUtf8Error(e)
+    }
+}
+
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn shell(cmd: &str, context: &mut Context) -> SystemCommand {
+    if let Some(stats) = &mut context.stats {
+        stats.child_processes += 1;
+    }
+    let (shell, flags) = if cfg!(target_os = "windows") {
+        context.windows_shell.program()
+    } else {
+        ("/bin/sh", &["-c"][..])
+    };
+    #[cfg(feature = "tracing")]
+    tracing::debug!(command = cmd, "starting child process");
+    let mut command = SystemCommand::new(shell);
+    command.args(flags).arg(cmd);
+    command
+}
+
+/// Creates a command that runs `command` with the shell if `shell` is set, and otherwise runs its
+/// first word as a program with the rest as arguments, for `#exec:program` and `#in:program`.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn child_command(command: &str, shell: bool, context: &mut Context) -> SystemCommand {
+    if shell {
+        return self::shell(command, context);
+    }
+    if let Some(stats) = &mut context.stats {
+        stats.child_processes += 1;
+    }
+    #[cfg(feature = "tracing")]
+    tracing::debug!(command, "starting child process");
+    let mut words = command.split_whitespace();
+    let mut child = SystemCommand::new(words.next().unwrap_or_default());
+    child.args(words);
+    child
+}
+
+/// The ids of the child processes that are running, so that they can be killed if the program is
+/// interrupted.
+static RUNNING_CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Get the process ids of every child process started for #exec and #in by any context that
+/// hasn't yet exited and been waited for.
+///
+/// Child processes are normally killed when processing fails, but not if the program is
+/// interrupted by a signal. A program that handles signals itself can use this to kill them
+/// before it exits.
+pub fn running_children() -> Vec<u32> {
+    RUNNING_CHILDREN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Starts a child process, recording it as running until it is waited for with `wait_child`.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn spawn_child(command: &mut SystemCommand) -> io::Result<Child> {
+    let child = command.spawn()?;
+    RUNNING_CHILDREN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(child.id());
+    Ok(child)
+}
+
+/// Waits for a child process started with `spawn_child` to exit.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn wait_child(child: &mut Child) -> io::Result<ExitStatus> {
+    let status = child.wait();
+    RUNNING_CHILDREN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|&id| id != child.id());
+    status
+}
+
+/// Gets the output of a command from the context's `on_exec` hook, if it has one and the hook
+/// provides it.
+#[cfg(feature = "exec")]
+fn exec_from_hook(
+    command: &str,
+    input: Option<&str>,
+    context: &mut Context,
+) -> Option<Result<String, Error>> {
+    let hook = context.on_exec.take()?;
+    let result = (hook.lock())(command, input, context);
+    context.on_exec.get_or_insert(hook);
+    result
+}
+
+/// Reads the output of a child until it exits, then checks that it exited successfully and returns
+/// its output. The child is killed if it outputs more than `limit` bytes.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn child_output(mut child: Child, command: &str, limit: Option<usize>) -> Result<String, Error> {
+    // Close the child's input, if it has any, so that it doesn't wait for more.
+    drop(child.stdin.take());
+    let stdout = child.stdout.take().ok_or(Error::PipeFailed)?;
+    let stderr = capture_stderr(&mut child);
+    let output = read_output(stdout, limit)?;
+    finish_child(child, command, output, stderr, limit)
+}
+
+/// Reads the output of a child until it closes its output or has output more than `limit` bytes,
+/// in which case the pipe is closed without reading the rest.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn read_output(mut stdout: ChildStdout, limit: Option<usize>) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    match limit {
+        Some(limit) => stdout.take(limit as u64 + 1).read_to_end(&mut output)?,
+        None => stdout.read_to_end(&mut output)?,
+    };
+    Ok(output)
+}
+
+/// The most bytes of a child's standard error that are kept for `Error::ChildFailed`.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+const MAX_STDERR: usize = 4096;
+
+/// Starts a thread that reads the standard error of a child, if it is piped, and passes it on to
+/// gpp's own standard error as it is written. Its end is kept for `Error::ChildFailed`.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn capture_stderr(child: &mut Child) -> Option<thread::JoinHandle<String>> {
+    let mut stderr = child.stderr.take()?;
+    Some(thread::spawn(move || {
+        let mut kept = Vec::new();
+        let mut truncated = false;
+        let mut buf = [0; 4096];
+        // Everything is read, even what isn't kept, so that the child never waits to write more.
+        while let Ok(n @ 1..) = stderr.read(&mut buf) {
+            let _ = io::stderr().write_all(&buf[..n]);
+            kept.extend_from_slice(&buf[..n]);
+            if kept.len() > MAX_STDERR * 2 {
+                kept.drain(..kept.len() - MAX_STDERR);
+                truncated = true;
+            }
+        }
+        if kept.len() > MAX_STDERR {
+            kept.drain(..kept.len() - MAX_STDERR);
+            truncated = true;
+        }
+        let text = String::from_utf8_lossy(&kept);
+        let text = text.trim_end();
+        if truncated {
+            format!("...{}", text)
+        } else {
+            text.to_owned()
+        }
+    }))
+}
+
+/// Waits for a child whose output has been read, killing it if it output more than `limit` bytes,
+/// and returns its output if it exited successfully. `command` is the command it runs and
+/// `stderr` the thread reading its standard error, for errors.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn finish_child(
+    mut child: Child,
+    command: &str,
+    output: Vec<u8>,
+    stderr: Option<thread::JoinHandle<String>>,
+    limit: Option<usize>,
+) -> Result<String, Error> {
+    if let Some(limit) = limit.filter(|&limit| output.len() > limit) {
+        let _ = child.kill();
+        let _ = wait_child(&mut child);
+        return Err(Error::ExecOutputTooLarge { limit });
+    }
+    let status = wait_child(&mut child)?;
+    if !status.success() {
+        return Err(Error::ChildFailed {
+            command: command.to_owned(),
+            status,
+            stderr: stderr
+                .and_then(|stderr| stderr.join().ok())
+                .unwrap_or_default(),
+        });
+    }
+    Ok(String::from_utf8(output)?)
+}
+
+/// Gets the command that an #exec or #in runs, which has its macros expanded if the context's
+/// `expand_exec` is set.
+#[cfg(feature = "exec")]
+fn exec_command<'a>(line: &'a str, context: &mut Context) -> Cow<'a, str> {
+    if let Some(cache) = &mut context.include_cache {
+        cache.commands += 1;
+    }
+    if context.expand_exec {
+        expand_macros(Cow::Borrowed(line), context)
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+#[cfg(feature = "exec")]
+fn process_exec(line: &str, context: &mut Context) -> Result<String, Error> {
+    let line = &*exec_command(line, context);
+    if context.dry_run {
+        context.state.dry_run_commands.push(line.to_owned());
+        return Ok(String::new());
+    }
+    run_command(line, context)
+}
+
+/// Runs a command, or gets its output from the context's `on_exec` hook, returning its output.
+#[cfg(feature = "exec")]
+fn run_command(command: &str, context: &mut Context) -> Result<String, Error> {
+    if let Some(output) = exec_from_hook(command, None, context) {
+        return output;
+    }
+    #[cfg(not(target_family = "wasm"))]
+    {
+        let child = spawn_child(
+            shell(command, context)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )?;
+        child_output(child, command, context.max_exec_output)
+    }
+    #[cfg(target_family = "wasm")]
+    Err(Error::Unsupported { command: "exec" })
+}
+
+/// Includes the output of a command for `#include !command`, processing it as if it were the
+/// contents of a file.
+#[cfg(feature = "exec")]
+fn include_command(
+    command: &str,
+    range: Option<RangeInclusive<usize>>,
+    context: &mut Context,
+) -> Result<String, Error> {
+    if !context.allow_exec && !context.dry_run {
+        return Err(Error::ExecDisabled { command: "include" });
+    }
+    #[cfg(target_family = "wasm")]
+    if !context.dry_run {
+        return Err(Error::Unsupported { command: "include" });
+    }
+    let command = &*exec_command(command.trim_start(), context);
+    if context.dry_run {
+        context.state.dry_run_commands.push(command.to_owned());
+        return Ok(String::new());
+    }
+    let contents = run_command(command, context)?;
+    let name = format!("!{}", command);
+    match range {
+        Some(range) => process_excerpt(&contents, &range, &name, context),
+        None => process_included(contents.as_bytes(), &name, None, context),
+    }
+}
+
+#[cfg(not(feature = "exec"))]
+fn include_command(
+    _command: &str,
+    _range: Option<RangeInclusive<usize>>,
+    _context: &mut Context,
+) -> Result<String, Error> {
+    Err(Error::ExecDisabled { command: "include" })
+}
+
+#[cfg(feature = "exec")]
+fn process_in(line: &str, context: &mut Context) -> Result<String, Error> {
+    let line = &*exec_command(line, context);
+    start_in(line, true, context)
+}
+
+/// Starts an #in block whose input is piped to `command`, which is run by the shell if `shell` is
+/// set and is an interpreter and its arguments otherwise.
+#[cfg(feature = "exec")]
+fn start_in(command: &str, shell: bool, context: &mut Context) -> Result<String, Error> {
+    if context.dry_run {
+        context.state.dry_run_commands.push(command.to_owned());
+    }
+    if context.dry_run || context.on_exec.is_some() {
+        context.state.in_stack.push(InBlock::Buffered {
+            command: command.to_owned(),
+            input: Vec::new(),
+            shell,
+        });
+        return Ok(String::new());
+    }
+    #[cfg(not(target_family = "wasm"))]
+    {
+        let (child, output) = spawn_reading(command, shell, context)?;
+        context.state.in_stack.push(InBlock::Child {
+            child,
+            command: command.to_owned(),
+            output,
+        });
+        Ok(String::new())
+    }
+    #[cfg(target_family = "wasm")]
+    Err(Error::Unsupported { command: "in" })
+}
+
+/// Starts a child whose input is piped to it, with a thread that reads its output.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn spawn_reading(
+    command: &str,
+    shell: bool,
+    context: &mut Context,
+) -> Result<(Child, OutputReader), Error> {
+    let mut child = spawn_child(
+        child_command(command, shell, context)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+    let stdout = child.stdout.take().ok_or(Error::PipeFailed)?;
+    let limit = context.max_exec_output;
+    // The output is read as it is written, as a child that fills the pipe before its input ends
+    // would otherwise wait forever for it to be read.
+    let output = OutputReader {
+        stdout: thread::spawn(move || read_output(stdout, limit)),
+        stderr: capture_stderr(&mut child),
+    };
+    Ok((child, output))
+}
+
+/// Writes input to a child. A child that stops reading its input, such as `head`, isn't an error
+/// here, as its exit status is checked once it finishes.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn write_input(child: &mut Child, input: &[u8]) -> Result<(), Error> {
+    let stdin = child.stdin.as_mut().ok_or(Error::PipeFailed)?;
+    match stdin.write_all(input) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Closes the input of a child started by `spawn_reading` and waits for it to finish, returning
+/// its output. The child is killed if it takes longer than `timeout`.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn finish_in(
+    mut child: Child,
+    command: &str,
+    output: OutputReader,
+    timeout: Option<Duration>,
+    limit: Option<usize>,
+) -> Result<String, Error> {
+    drop(child.stdin.take());
+    let start = Instant::now();
+    if let Some(timeout) = timeout {
+        wait_for(&mut child, start, timeout, |_| {
+            Ok(output.stdout.is_finished())
+        })?;
+    }
+    let OutputReader { stdout, stderr } = output;
+    let output = stdout.join().map_err(|_| Error::PipeFailed)??;
+    // A child that output too much is killed straight away rather than waited for.
+    if let Some(timeout) = timeout.filter(|_| limit.is_none_or(|limit| output.len() <= limit)) {
+        wait_for(&mut child, start, timeout, |child| {
+            Ok(child.try_wait()?.is_some())
+        })?;
+    }
+    finish_child(child, command, output, stderr, limit)
+}
+
+/// Checks whether `done` every few milliseconds until it is, killing the child and failing if
+/// `timeout` has passed since `start` first.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn wait_for(
+    child: &mut Child,
+    start: Instant,
+    timeout: Duration,
+    mut done: impl FnMut(&mut Child) -> io::Result<bool>,
+) -> Result<(), Error> {
+    while !done(child)? {
+        if start.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = wait_child(child);
+            return Err(Error::ChildTimeout { timeout });
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+/// Runs `#exec:program` or `#in:program`. #exec feeds its parameters to the program as a script,
+/// while #in passes them to the program as arguments and feeds it the block.
+#[cfg(feature = "exec")]
+fn process_interpreter(
+    command: &'static str,
+    program: &str,
+    line: &str,
+    context: &mut Context,
+) -> Result<String, Error> {
+    if program.is_empty() || program.contains(char::is_whitespace) {
+        return Err(Error::InvalidCommand {
+            command_name: format!("{}:{}", command, program),
+        });
+    }
+    let line = &*exec_command(line, context);
+    if command == "in" {
+        return start_in(format!("{} {}", program, line).trim_end(), false, context);
+    }
+    if context.dry_run {
+        // The script is recorded after the program, as the arguments of #in:program are.
+        let command = format!("{} {}", program, line);
+        context
+            .state
+            .dry_run_commands
+            .push(command.trim_end().to_owned());
+        return Ok(String::new());
+    }
+    run_with_input(program, false, format!("{}\n", line), None, context)
+}
+
+#[cfg(not(feature = "exec"))]
+fn process_interpreter(
+    command: &'static str,
+    _program: &str,
+    _line: &str,
+    _context: &mut Context,
+) -> Result<String, Error> {
+    Err(Error::ExecDisabled { command })
+}
+
+#[cfg(feature = "exec")]
+fn process_ifexec(line: &str, context: &mut Context) -> Result<String, Error> {
+    process_if(context, |context| {
+        let line = &*exec_command(line, context);
+        if context.dry_run {
+            context.state.dry_run_commands.push(line.to_owned());
+            return Ok(true);
+        }
+        if let Some(output) = exec_from_hook(line, None, context) {
+            return Ok(output.is_ok());
+        }
+        #[cfg(not(target_family = "wasm"))]
+        {
+            let mut child = spawn_child(
+                shell(line, context)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null()),
+            )?;
+            Ok(wait_child(&mut child)?.success())
+        }
+        #[cfg(target_family = "wasm")]
+        Err(Error::Unsupported { command: "ifexec" })
+    })
+}
+
+/// Kills a child and waits for it to exit.
+#[cfg(all(feature = "exec", not(target_family = "wasm")))]
+fn kill_child(mut child: Child) {
+    drop(child.stdin.take());
+    let _ = child.kill();
+    let _ = wait_child(&mut child);
+}
+
+/// Kills the children of the #in blocks above `depth` in the stack and waits for them to exit,
+/// removing the blocks from the stack.
+#[cfg(feature = "exec")]
+fn kill_in_blocks(context: &mut Context, depth: usize) {
+    #[cfg(not(target_family = "wasm"))]
+    for block in context
+        .state
+        .in_stack
+        .drain(depth.min(context.state.in_stack.len())..)
+    {
+        if let InBlock::Child { child, .. } = block {
+            kill_child(child);
+        }
+    }
+    #[cfg(target_family = "wasm")]
+    context.state.in_stack.truncate(depth);
+}
+
+#[cfg(feature = "exec")]
+fn process_endin(line: &str, context: &mut Context) -> Result<String, Error> {
+    if !line.is_empty() {
+        return Err(Error::TooManyParameters { command: "endin" });
+    }
+    if context.state.in_stack.is_empty()
+        || context
+            .state
+            .capture_stack
+            .last()
+            .is_some_and(|capture| capture.in_depth == context.state.in_stack.len())
+    {
+        return Err(Error::UnexpectedCommand { command: "endin" });
+    }
+    match context.state.in_stack.pop().unwrap() {
+        #[cfg(not(target_family = "wasm"))]
+        InBlock::Child {
+            child,
+            command,
+            output,
+        } => finish_in(
+            child,
+            &command,
+            output,
+            context.in_timeout,
+            context.max_exec_output,
+        ),
+        InBlock::Buffered { .. } if context.dry_run => Ok(String::new()),
+        InBlock::Buffered {
+            command,
+            input,
+            shell,
+        } => {
+            let timeout = context.in_timeout;
+            run_with_input(&command, shell, String::from_utf8(input)?, timeout, context)
+        }
+    }
+}
+
+/// Runs a command with the given input, or gets its output from the context's `on_exec` hook,
+/// returning its output. The command is killed if it takes longer than `timeout` to finish after
+/// its input has been written.
+#[cfg(feature = "exec")]
+fn run_with_input(
+    command: &str,
+    shell: bool,
+    input: String,
+    timeout: Option<Duration>,
+    context: &mut Context,
+) -> Result<String, Error> {
+    if let Some(output) = exec_from_hook(command, Some(&input), context) {
+        return output;
+    }
+    #[cfg(not(target_family = "wasm"))]
+    {
+        let (mut child, output) = spawn_reading(command, shell, context)?;
+        if let Err(e) = write_input(&mut child, input.as_bytes()) {
+            kill_child(child);
+            return Err(e);
+        }
+        finish_in(child, command, output, timeout, context.max_exec_output)
+    }
+    #[cfg(target_family = "wasm")]
+    {
+        let _ = (shell, timeout);
+        Err(Error::Unsupported { command: "in" })
+    }
+}
+
+/// Removes the double quotes from a quoted path or value, or returns an unquoted one unchanged.
+fn unquote(path: &str) -> &str {
+    path.strip_prefix('"')
+        .and_then(|path| path.strip_suffix('"'))
+        .unwrap_or(path)
+}
+
+/// Splits an optional `lines=START..END` parameter off the end of an #include's parameters,
+/// returning the remaining parameters and the inclusive range of lines to include.
+fn split_line_range(line: &str) -> Result<(&str, Option<RangeInclusive<usize>>), Error> {
+    let (rest, range) = match line.rsplit_once(' ') {
+        Some((rest, param)) => match param.strip_prefix("lines=") {
+            Some(range) => (rest.trim_end(), range),
+            None => return Ok((line, None)),
+        },
+        None => return Ok((line, None)),
+    };
+
+    let invalid = || Error::InvalidParameter {
+        command: "include",
+        parameter: format!("lines={}", range),
+    };
+    let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+    let start = match start {
+        "" => 1,
+        start => start.parse().map_err(|_| invalid())?,
+    };
+    let end = match end {
+        "" => usize::MAX,
+        end => end.parse().map_err(|_| invalid())?,
+    };
+    if start == 0 || end < start {
+        return Err(invalid());
+    }
+    Ok((rest, Some(start..=end)))
+}
+
+/// Finds the file an #include refers to, searching the include paths if it isn't relative to the
+/// current directory.
+#[cfg(not(target_family = "wasm"))]
+fn find_include(name: &str, context: &Context) -> PathBuf {
+    find_include_in(name, context, false).unwrap_or_else(|_| PathBuf::from(name))
+}
+
+/// Finds the file an #include of `<name>` refers to in C compatibility mode, which is only looked
+/// for in the include paths.
+#[cfg(not(target_family = "wasm"))]
+fn find_system_include(name: &str, context: &Context) -> Result<PathBuf, Error> {
+    find_include_in(name, context, true)
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn find_include_in(name: &str, context: &Context, system: bool) -> Result<PathBuf, Error> {
+    if let Some(path) = expand_include_alias(name, context) {
+        return Ok(path);
+    }
+    // In C compatibility mode, a quoted #include is looked for next to the file that includes it
+    // first, as the C preprocessor does.
+    let including_dir = context
+        .state
+        .file_stack
+        .last()
+        .and_then(|file| file.parent())
+        .filter(|_| context.c_compat && !system);
+    if let Some(path) = including_dir
+        .map(|dir| dir.join(name))
+        .filter(|path| path.exists())
+    {
+        return Ok(path);
+    }
+    let path = PathBuf::from(name);
+    if !system && path.exists() {
+        return Ok(path);
+    }
+    context
+        .include_paths
+        .iter()
+        .map(|dir| dir.join(name))
+        .find(|path| path.exists())
+        .ok_or_else(|| {
+            Error::IoError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} was not found in the include paths", name),
+            ))
+        })
+}
+
+/// Replaces the alias that an included path starts with, if it starts with one, with its
+/// directory.
+#[cfg(not(target_family = "wasm"))]
+fn expand_include_alias(name: &str, context: &Context) -> Option<PathBuf> {
+    let (alias, rest) = name.split_once('/').unwrap_or((name, ""));
+    context
+        .include_aliases
+        .iter()
+        .find(|(name, _)| name == alias)
+        .map(|(_, dir)| dir.join(rest))
+}
+
+/// Gets the contents of an included file from the context's `on_include` hook, if it has one and
+/// the hook provides them.
+fn include_from_hook(name: &str, context: &mut Context) -> Option<Result<String, Error>> {
+    let hook = context.on_include.take()?;
+    let result = (hook.lock())(name, context);
+    context.on_include.get_or_insert(hook);
+    result
+}
+
+fn process_include(line: &str, context: &mut Context) -> Result<String, Error> {
+    let (line, range) = split_line_range(line)?;
+    if let Some(command) = line.strip_prefix('!') {
+        return include_command(command, range, context);
+    }
+    let (line, system) = match line
+        .strip_prefix('<')
+        .and_then(|line| line.strip_suffix('>'))
+    {
+        Some(name) if context.c_compat => (name, true),
+        _ => (unquote(line), false),
+    };
+
+    let contents = if let Some(contents) = include_from_hook(line, context) {
+        contents?
+    } else if line == "-" && !system {
+        let mut contents = String::new();
+        io::stdin().lock().read_to_string(&mut contents)?;
+        contents
+    } else if let Some(resolver) = &context.resolver {
+        resolver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .resolve(line)?
+    } else {
+        return include_file(line, system, range, context);
+    };
+
+    let name = if line == "-" {
+        context.stdin_name().to_owned()
+    } else {
+        line.to_owned()
+    };
+    match range {
+        Some(range) => process_excerpt(&contents, &range, &name, context),
+        None => process_included(contents.as_bytes(), &name, None, context),
+    }
+}
+
+/// Includes a file from the filesystem, found with `find_include` or, for `<name>` in C
+/// compatibility mode, `find_system_include`.
+#[cfg(not(target_family = "wasm"))]
+fn include_file(
+    line: &str,
+    system: bool,
+    range: Option<RangeInclusive<usize>>,
+    context: &mut Context,
+) -> Result<String, Error> {
+    let path = if system {
+        find_system_include(line, context)?
+    } else {
+        find_include(line, context)
+    };
+    if !context.state.once_files.is_empty()
+        && fs::canonicalize(&path).is_ok_and(|path| context.state.once_files.contains(&path))
+    {
+        return Ok(String::new());
+    }
+    context.in_file(&path, |context| include_path(line, &path, range, context))
+}
+
+/// Includes a file from the filesystem once it has been found.
+#[cfg(not(target_family = "wasm"))]
+fn include_path(
+    line: &str,
+    path: &Path,
+    range: Option<RangeInclusive<usize>>,
+    context: &mut Context,
+) -> Result<String, Error> {
+    if context.include_cache.is_some() {
+        return include_cached(line, path.to_owned(), range, context);
+    }
+    let contents = fs::read(path)?;
+    context.add_dependency(path);
+    let name = path.to_string_lossy();
+    let contents = decode_included(&contents, &name, context)?;
+    match range {
+        None => process_included(&*contents, &name, None, context),
+        Some(range) => process_excerpt(
+            &String::from_utf8(contents.into_owned())?,
+            &range,
+            line,
+            context,
+        ),
+    }
+}
+
+/// Decodes an #included file to UTF-8. Without the `encoding` feature, files are always UTF-8.
+#[cfg(all(not(feature = "encoding"), not(target_family = "wasm")))]
+fn decode_included<'a>(
+    contents: &'a [u8],
+    _name: &str,
+    _context: &Context,
+) -> Result<Cow<'a, [u8]>, Error> {
+    Ok(Cow::Borrowed(contents))
+}
+
+/// WebAssembly has no filesystem, so included files can only come from the context's `resolver`
+/// or `on_include` hook.
+#[cfg(target_family = "wasm")]
+fn include_file(
+    _line: &str,
+    _system: bool,
+    _range: Option<RangeInclusive<usize>>,
+    _context: &mut Context,
+) -> Result<String, Error> {
+    Err(Error::Unsupported { command: "include" })
+}
+
+/// Processes the lines of included contents within an inclusive range of line numbers, numbering
+/// them as they are numbered in the contents.
+fn process_excerpt(
+    contents: &str,
+    range: &RangeInclusive<usize>,
+    buf_name: &str,
+    context: &mut Context,
+) -> Result<String, Error> {
+    let excerpt = select_lines(contents, range);
+    process_included(
+        excerpt.as_bytes(),
+        buf_name,
+        Some(range.start() - 1),
+        context,
+    )
+}
+
+/// Gets the lines of a file within an inclusive range of line numbers.
+fn select_lines(contents: &str, range: &RangeInclusive<usize>) -> String {
+    contents
+        .split_inclusive('\n')
+        .skip(range.start() - 1)
+        .take(range.end() - range.start() + 1)
+        .collect()
+}
+
+/// Includes a file using the context's `include_cache`, reading the file only if it hasn't been
+/// read before and reusing its output if it has been included in the same state before.
+#[cfg(not(target_family = "wasm"))]
+fn include_cached(
+    name: &str,
+    path: PathBuf,
+    range: Option<RangeInclusive<usize>>,
+    context: &mut Context,
+) -> Result<String, Error> {
+    context.add_dependency(&path);
+    let state = include_state(context);
+    let cache = context.include_cache.as_mut().unwrap();
+    // The same file can be reached through different paths, such as `a/../b.txt` and `b.txt` or
+    // through a symlink, so it is cached under its canonical path.
+    let canonical = fs::canonicalize(&path)?;
+    let key = (canonical, range, state);
+    if let Some(output) = cache.outputs.get(&key) {
+        let output = output.clone();
+        if let Some(stats) = &mut context.stats {
+            stats.includes += 1;
+        }
+        return Ok(output);
+    }
+    let contents = match cache.files.get(&key.0) {
+        Some(contents) => Arc::clone(contents),
+        None => {
+            let contents: Arc<[u8]> = fs::read(&key.0)?.into();
+            cache.files.insert(key.0.clone(), Arc::clone(&contents));
+            contents
+        }
+    };
+    let commands = cache.commands;
+    let recovered_errors = context.state.recovered_errors.len();
+
+    let filename = path.to_string_lossy();
+    let contents = decode_included(&contents, &filename, context)?;
+    let output = match &key.1 {
+        Some(range) => {
+            let contents = String::from_utf8(contents.into_owned())?;
+            process_excerpt(&contents, range, name, context)?
+        }
+        None => process_included(&*contents, &filename, None, context)?,
+    };
+    // Errors recovered from with `error_marker` would not be recorded again if the output were
+    // reused, so it isn't kept.
+    let unchanged =
+        include_state(context) == state && context.state.recovered_errors.len() == recovered_errors;
+    if let Some(cache) = &mut context.include_cache {
+        if unchanged && cache.commands == commands {
+            cache.outputs.insert(key, output.clone());
+        }
+    }
+    Ok(output)
+}
+
+/// Hashes everything about a context that can change the output of an included file, or that
+/// processing it can change.
+#[cfg(not(target_family = "wasm"))]
+fn include_state(context: &Context) -> u64 {
+    let mut macros: Vec<_> = context.macros.iter().collect();
+    macros.sort();
+    let state = format!(
+        "{:?}{:?}",
+        (
+            macros,
+            &context.state.exports,
+            &context.definition_order,
+            &context.include_paths,
+            &context.include_aliases,
+            context.state.inactive_stack,
+            context.state.if_depth,
+            context.state.used_if,
+            context.state.switch_stack.len(),
+            context.state.comment_depth,
+            context.state.in_raw,
+            context.state.capture_stack.len(),
+        ),
+        (
+            &context.delimiters,
+            &context.command_delimiters,
+            &context.macro_escape,
+            &context.precedence,
+            context.indented_commands,
+            context.command_spacing,
+            &context.directive_comment,
+            context.pass_unknown_commands,
+            context.c_compat,
+            context.line_continuation,
+            context.reindent,
+            context.preserve_line_endings,
+        ),
+    );
+    let mut hasher = DefaultHasher::new();
+    state.hash(&mut hasher);
+    context.state.once_files.hash(&mut hasher);
+    #[cfg(feature = "encoding")]
+    context
+        .encoding
+        .map(encoding::Encoding::name)
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Includes a file with its own copy of the macros, then copies the macros that it exports back,
+/// or only those in `imports` if it is given.
+fn include_isolated(
+    line: &str,
+    context: &mut Context,
+    imports: Option<&[&str]>,
+) -> Result<String, Error> {
+    let macros = context.macros.clone();
+    let exports = mem::take(&mut context.state.exports);
+    let result = process_include(line, context);
+    let exported = mem::replace(&mut context.state.exports, exports);
+    let included_macros = mem::replace(&mut context.macros, macros);
+    let output = result?;
+
+    let names: Vec<&str> = match imports {
+        Some(imports) => {
+            if let Some(name) = imports
+                .iter()
+                .find(|name| !exported.iter().any(|export| export == *name))
+            {
+                return Err(Error::InvalidParameter {
+                    command: "import",
+                    parameter: (*name).to_owned(),
+                });
+            }
+            imports.to_vec()
+        }
+        None => exported.iter().map(String::as_str).collect(),
+    };
+    for name in names {
+        match included_macros.get(name) {
+            Some(value) => define_macro(context, name.to_owned(), value.clone()),
+            None => {
+                context.macros.remove(name);
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn process_include_isolated(line: &str, context: &mut Context) -> Result<String, Error> {
+    include_isolated(line, context, None)
+}
+
+fn process_import(line: &str, context: &mut Context) -> Result<String, Error> {
+    let (file, names) = match line.strip_prefix('"').and_then(|rest| rest.split_once('"')) {
+        Some((file, names)) => (file, names),
+        None => line.split_once(' ').unwrap_or((line, "")),
+    };
+    let names: Vec<&str> = names.split_whitespace().collect();
+    if names.is_empty() {
+        return Err(Error::InvalidParameter {
+            command: "import",
+            parameter: line.to_owned(),
+        });
+    }
+    include_isolated(&format!("\"{}\"", file), context, Some(&names))
+}
+
+fn process_export(line: &str, context: &mut Context) -> Result<String, Error> {
+    if line.is_empty() {
+        return Err(Error::InvalidParameter {
+            command: "export",
+            parameter: String::new(),
+        });
+    }
+    context
+        .state
+        .exports
+        .extend(line.split_whitespace().map(str::to_owned));
+    Ok(String::new())
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+fn process_include_base64(line: &str, context: &mut Context) -> Result<String, Error> {
+    let line = unquote(line);
+    let data = if let Some(contents) = include_from_hook(line, context) {
+        contents?.into_bytes()
+    } else if let Some(resolver) = &context.resolver {
+        resolver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .resolve(line)?
+            .into_bytes()
+    } else {
+        #[cfg(not(target_family = "wasm"))]
+        {
+            let path = find_include(line, context);
+            let data = fs::read(&path)?;
+            context.add_dependency(&path);
+            data
+        }
+        #[cfg(target_family = "wasm")]
+        return Err(Error::Unsupported {
+            command: "include_base64",
+        });
+    };
+    Ok(format!("{}\n", base64(&data)))
+}
+
+/// Process an included file. Unlike `process_buf`, this never outputs a byte order mark, as the
+/// output does not start at the beginning of the file.
+///
+/// If the buffer is an excerpt of the file, `excerpt` is the line it starts at, counting from
+/// zero, so that lines are numbered as they are in the file.
+fn process_included<T: BufRead>(
+    mut buf: T,
+    buf_name: &str,
+    excerpt: Option<usize>,
+    context: &mut Context,
+) -> Result<String, Error> {
+    if let Some(stats) = &mut context.stats {
+        stats.includes += 1;
+    }
+    skip_bom(&mut buf)?;
+    let mut output = String::new();
+    process_lines(buf, buf_name, excerpt, context, &mut output)?;
+    Ok(output)
+}
+
+fn process_define(line: &str, context: &mut Context) -> Result<String, Error> {
+    let mut parts = line.splitn(2, ' ');
+    let name = parts.next().unwrap();
+    let value = parts.next().unwrap_or("");
+
+    define_macro(context, name.to_owned(), value.to_owned());
+    Ok(String::new())
+}
+
+fn process_default(line: &str, context: &mut Context) -> Result<String, Error> {
+    if line.is_empty() && !context.state.switch_stack.is_empty() {
+        return process_case(None, context);
+    }
+    // This command runs in inactive blocks so that it can end a case, but defining a macro
+    // shouldn't.
+    if context.state.inactive_stack > 0 {
+        return Ok(String::new());
+    }
+    let mut parts = line.splitn(2, ' ');
+    let name = parts.next().unwrap();
+    let value = parts.next().unwrap_or("");
+
+    if !context.macros.contains_key(name) {
+        define_macro(context, name.to_owned(), value.to_owned());
+    }
+    Ok(String::new())
+}
+
+fn process_append(line: &str, context: &mut Context, prepend: bool) -> Result<String, Error> {
+    let mut parts = line.splitn(2, ' ');
+    let name = parts.next().unwrap();
+    let value = unquote(parts.next().unwrap_or(""));
+
+    if !context.macros.contains_key(name) {
+        define_macro(context, name.to_owned(), String::new());
+    }
+    let macro_value = context.macros.get_mut(name).unwrap();
+    if prepend {
+        macro_value.insert_str(0, value);
+    } else {
+        macro_value.push_str(value);
+    }
+    Ok(String::new())
+}
+
+fn process_undef(line: &str, context: &mut Context) -> Result<String, Error> {
+    if let Some(namespace) = line.strip_suffix(".*") {
+        context.macros.retain(|name, _| {
+            name.strip_prefix(namespace)
+                .is_none_or(|rest| !rest.starts_with('.'))
         });
+    } else {
+        context.macros.remove(line);
     }
-    Ok(String::from_utf8(output.stdout)?)
+    Ok(String::new())
 }
 
-fn process_in(line: &str, context: &mut Context) -> Result<String, Error> {
-    let child = shell(line)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-    context.in_stack.push(child);
+fn process_let(line: &str, context: &mut Context) -> Result<String, Error> {
+    let (name, expression) = line
+        .split_once('=')
+        .map(|(name, expression)| (name.trim_end(), expression))
+        .filter(|(name, _)| !name.is_empty() && !name.contains(char::is_whitespace))
+        .ok_or_else(|| Error::InvalidParameter {
+            command: "let",
+            parameter: line.to_owned(),
+        })?;
+    let value = evaluate(expression, "let", context)?;
+    define_macro(context, name.to_owned(), value.to_string());
     Ok(String::new())
 }
 
-fn process_endin(line: &str, context: &mut Context) -> Result<String, Error> {
+/// Evaluates the expression given to a command.
+fn evaluate(expression: &str, command: &'static str, context: &Context) -> Result<Value, Error> {
+    let evaluate = if context.c_compat {
+        expr::evaluate_c
+    } else {
+        expr::evaluate
+    };
+    evaluate(expression, &context.macros)
+        .map_err(|error| Error::InvalidExpression { command, error })
+}
+
+fn process_assert(line: &str, context: &mut Context) -> Result<String, Error> {
+    if !evaluate(line, "assert", context)?.is_true() {
+        return Err(Error::AssertionFailed {
+            expression: line.to_owned(),
+        });
+    }
+    Ok(String::new())
+}
+
+fn process_using(line: &str, context: &mut Context) -> Result<String, Error> {
+    let prefix = format!("{}.", line);
+    let macros: Vec<(String, String)> = context
+        .macros
+        .iter()
+        .filter_map(|(name, value)| Some((name.strip_prefix(&prefix)?.to_owned(), value.clone())))
+        .collect();
+    for (name, value) in macros {
+        define_macro(context, name, value);
+    }
+    Ok(String::new())
+}
+
+fn process_comment(line: &str, context: &mut Context) -> Result<String, Error> {
+    if line.is_empty() {
+        context.state.comment_depth += 1;
+    }
+    Ok(String::new())
+}
+
+fn process_endcomment(line: &str, _: &mut Context) -> Result<String, Error> {
     if !line.is_empty() {
-        return Err(Error::TooManyParameters { command: "endin" });
+        return Err(Error::TooManyParameters {
+            command: "endcomment",
+        });
     }
-    if context.in_stack.is_empty() {
-        return Err(Error::UnexpectedCommand { command: "endin" });
+    Err(Error::UnexpectedCommand {
+        command: "endcomment",
+    })
+}
+
+fn process_raw(line: &str, context: &mut Context) -> Result<String, Error> {
+    if !line.is_empty() {
+        return Err(Error::TooManyParameters { command: "raw" });
     }
-    let child = context.in_stack.pop().unwrap();
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        return Err(Error::ChildFailed {
-            status: output.status,
+    context.state.in_raw = true;
+    Ok(String::new())
+}
+
+fn process_endraw(line: &str, _: &mut Context) -> Result<String, Error> {
+    if !line.is_empty() {
+        return Err(Error::TooManyParameters { command: "endraw" });
+    }
+    Err(Error::UnexpectedCommand { command: "endraw" })
+}
+
+fn process_capture(line: &str, context: &mut Context) -> Result<String, Error> {
+    if line.is_empty() {
+        return Err(Error::InvalidParameter {
+            command: "capture",
+            parameter: String::new(),
         });
     }
-    Ok(String::from_utf8(output.stdout)?)
+    context.state.capture_stack.push(Capture {
+        name: line.to_owned(),
+        output: Vec::new(),
+        in_depth: in_depth(context),
+        filter: None,
+    });
+    Ok(String::new())
 }
 
-fn process_include(line: &str, context: &mut Context) -> Result<String, Error> {
-    process_file(line, context)
+fn process_endcapture(line: &str, context: &mut Context) -> Result<String, Error> {
+    if !line.is_empty() {
+        return Err(Error::TooManyParameters {
+            command: "endcapture",
+        });
+    }
+    let capture = pop_capture(context, "endcapture", false)?;
+    let mut value = String::from_utf8(capture.output)?;
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    define_macro(context, capture.name, value);
+    Ok(String::new())
 }
 
-fn process_define(line: &str, context: &mut Context) -> Result<String, Error> {
-    let mut parts = line.splitn(2, ' ');
-    let name = parts.next().unwrap();
-    let value = parts.next().unwrap_or("");
+/// Ends the innermost #capture or #escape block, which must be of the kind that `command` ends and
+/// must have been started inside the current #in block.
+fn pop_capture(
+    context: &mut Context,
+    command: &'static str,
+    escape: bool,
+) -> Result<Capture, Error> {
+    match context.state.capture_stack.last() {
+        Some(capture)
+            if capture.in_depth == in_depth(context) && capture.filter.is_some() == escape =>
+        {
+            Ok(context.state.capture_stack.pop().unwrap())
+        }
+        _ => Err(Error::UnexpectedCommand { command }),
+    }
+}
 
-    context.macros.insert(name.to_owned(), value.to_owned());
+fn process_escape(line: &str, context: &mut Context) -> Result<String, Error> {
+    let filter = filter::escape_filter(line).ok_or_else(|| Error::InvalidParameter {
+        command: "escape",
+        parameter: line.to_owned(),
+    })?;
+    context.state.capture_stack.push(Capture {
+        name: String::new(),
+        output: Vec::new(),
+        in_depth: in_depth(context),
+        filter: Some(filter),
+    });
     Ok(String::new())
 }
 
-fn process_undef(line: &str, context: &mut Context) -> Result<String, Error> {
-    context.macros.remove(line);
+fn process_endescape(line: &str, context: &mut Context) -> Result<String, Error> {
+    if !line.is_empty() {
+        return Err(Error::TooManyParameters {
+            command: "endescape",
+        });
+    }
+    let capture = pop_capture(context, "endescape", true)?;
+    let output = String::from_utf8(capture.output)?;
+    Ok(filter::apply(capture.filter.unwrap(), &output).unwrap())
+}
+
+fn process_date(line: &str, context: &mut Context) -> Result<String, Error> {
+    let format = if line.is_empty() { "%Y-%m-%d" } else { line };
+    let mut date = date::format(context.date.unwrap_or_else(SystemTime::now), format).map_err(
+        |parameter| Error::InvalidParameter {
+            command: "date",
+            parameter,
+        },
+    )?;
+    date.push('\n');
+    Ok(date)
+}
+
+/// The settings that #pragma can change, which are restored at the end of the file that changed
+/// them.
+struct Pragmas {
+    delimiters: Option<Delimiters>,
+    command_delimiters: Option<Delimiters>,
+    directive_comment: Option<String>,
+    macro_escape: Option<String>,
+    indented_commands: bool,
+    command_spacing: CommandSpacing,
+    reindent: bool,
+    preserve_line_endings: bool,
+    pass_unknown_commands: bool,
+    c_compat: bool,
+    line_continuation: bool,
+}
+
+impl Pragmas {
+    fn save(context: &Context) -> Self {
+        Self {
+            delimiters: context.delimiters.clone(),
+            command_delimiters: context.command_delimiters.clone(),
+            directive_comment: context.directive_comment.clone(),
+            macro_escape: context.macro_escape.clone(),
+            indented_commands: context.indented_commands,
+            command_spacing: context.command_spacing,
+            reindent: context.reindent,
+            preserve_line_endings: context.preserve_line_endings,
+            pass_unknown_commands: context.pass_unknown_commands,
+            c_compat: context.c_compat,
+            line_continuation: context.line_continuation,
+        }
+    }
+    fn restore(self, context: &mut Context) {
+        context.delimiters = self.delimiters;
+        context.command_delimiters = self.command_delimiters;
+        context.directive_comment = self.directive_comment;
+        context.macro_escape = self.macro_escape;
+        context.indented_commands = self.indented_commands;
+        context.command_spacing = self.command_spacing;
+        context.reindent = self.reindent;
+        context.preserve_line_endings = self.preserve_line_endings;
+        context.pass_unknown_commands = self.pass_unknown_commands;
+        context.c_compat = self.c_compat;
+        context.line_continuation = self.line_continuation;
+    }
+}
+
+fn process_pragma(line: &str, context: &mut Context) -> Result<String, Error> {
+    let (option, value) = line.split_once(' ').unwrap_or((line, ""));
+    let value = value.trim();
+    let invalid = || Error::InvalidParameter {
+        command: "pragma",
+        parameter: line.to_owned(),
+    };
+    let flag = || match value {
+        "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        _ => Err(invalid()),
+    };
+
+    if option == "once" && context.c_compat && value.is_empty() {
+        #[cfg(not(target_family = "wasm"))]
+        if let Some(file) = context.state.file_stack.last() {
+            let file = fs::canonicalize(file)?;
+            if !context.state.once_files.contains(&file) {
+                context.state.once_files.push(file);
+            }
+        }
+        return Ok(String::new());
+    }
+
+    let previous = Pragmas::save(context);
+    match option {
+        "delimiters" => {
+            context.delimiters = match value.split_once(' ') {
+                _ if value == "none" => None,
+                Some((prefix, suffix)) => Some(Delimiters::new(prefix, suffix.trim_start())),
+                None if !value.is_empty() => Some(Delimiters::new(value, "")),
+                None => return Err(invalid()),
+            }
+        }
+        "directive_comment" => {
+            context.directive_comment = match value {
+                "none" => None,
+                marker => Some(marker.to_owned()),
+            }
+        }
+        "macro_escape" => {
+            context.macro_escape = match value {
+                "none" => None,
+                marker => Some(marker.to_owned()),
+            }
+        }
+        "command_spacing" => {
+            context.command_spacing = match value {
+                "strict" => CommandSpacing::Strict,
+                "spaces" => CommandSpacing::Spaces,
+                "tabs" => CommandSpacing::Tabs,
+                _ => return Err(invalid()),
+            }
+        }
+        "indented_commands" => context.indented_commands = flag()?,
+        "reindent" => context.reindent = flag()?,
+        "preserve_line_endings" => context.preserve_line_endings = flag()?,
+        "pass_unknown_commands" => context.pass_unknown_commands = flag()?,
+        "c_compat" => context.c_compat = flag()?,
+        "line_continuation" => context.line_continuation = flag()?,
+        _ => return Err(invalid()),
+    }
+    if let Err(e) = check_settings(context) {
+        previous.restore(context);
+        return Err(e);
+    }
+    Ok(String::new())
+}
+
+fn process_mode(line: &str, context: &mut Context) -> Result<String, Error> {
+    let previous = Pragmas::save(context);
+    context.command_delimiters = match line.split_once(' ') {
+        _ if line == "default" => None,
+        Some((prefix, suffix)) => Some(Delimiters::new(prefix, suffix.trim_start())),
+        None if !line.is_empty() => Some(Delimiters::new(line, "")),
+        None => {
+            return Err(Error::InvalidParameter {
+                command: "mode",
+                parameter: line.to_owned(),
+            })
+        }
+    };
+    if let Err(e) = check_settings(context) {
+        previous.restore(context);
+        return Err(e);
+    }
     Ok(String::new())
 }
 
 fn process_ifdef(line: &str, context: &mut Context, inverted: bool) -> Result<String, Error> {
-    if context.inactive_stack > 0 {
-        context.inactive_stack += 1;
-    } else if context.macros.contains_key(line) == inverted {
-        context.inactive_stack = 1;
-        context.used_if = false;
+    process_if(context, |context| {
+        Ok(context.macros.contains_key(line) != inverted)
+    })
+}
+
+/// Starts an if block, which is taken if `condition` returns true. The condition isn't checked
+/// inside an inactive block.
+fn process_if(
+    context: &mut Context,
+    condition: impl FnOnce(&mut Context) -> Result<bool, Error>,
+) -> Result<String, Error> {
+    let active = context.state.inactive_stack == 0;
+    let taken = active && condition(context)?;
+    context.state.if_depth += 1;
+    if !active {
+        context.state.inactive_stack += 1;
+    } else if !taken {
+        context.state.inactive_stack = 1;
+        context.state.used_if = false;
     } else {
-        context.used_if = true;
+        context.state.used_if = true;
     }
     Ok(String::new())
 }
 
 fn process_elifdef(line: &str, context: &mut Context, inverted: bool) -> Result<String, Error> {
-    if context.inactive_stack == 0 {
-        context.inactive_stack = 1;
-    } else if context.inactive_stack == 1
-        && !context.used_if
-        && context.macros.contains_key(line) != inverted
-    {
-        context.inactive_stack = 0;
+    process_elif(context, |context| {
+        Ok(context.macros.contains_key(line) != inverted)
+    })
+}
+
+/// Continues an if block with another branch, which is taken if no earlier branch was and
+/// `condition` returns true. The condition is only checked if it could decide the branch.
+fn process_elif(
+    context: &mut Context,
+    condition: impl FnOnce(&mut Context) -> Result<bool, Error>,
+) -> Result<String, Error> {
+    if context.state.inactive_stack == 0 {
+        context.state.inactive_stack = 1;
+    } else if context.state.inactive_stack == 1 && !context.state.used_if && condition(context)? {
+        context.state.inactive_stack = 0;
+        context.state.used_if = true;
     }
     Ok(String::new())
 }
 
+fn process_if_expression(line: &str, context: &mut Context) -> Result<String, Error> {
+    process_if(context, |context| {
+        Ok(evaluate(line, "if", context)?.is_true())
+    })
+}
+
+fn process_elif_expression(line: &str, context: &mut Context) -> Result<String, Error> {
+    process_elif(context, |context| {
+        Ok(evaluate(line, "elif", context)?.is_true())
+    })
+}
+
 fn process_else(line: &str, context: &mut Context) -> Result<String, Error> {
     if !line.is_empty() {
         return Err(Error::TooManyParameters { command: "else" });
     }
-    context.inactive_stack = match context.inactive_stack {
+    context.state.inactive_stack = match context.state.inactive_stack {
         0 => 1,
-        1 if !context.used_if => 0,
+        1 if !context.state.used_if => 0,
         val => val,
     };
     Ok(String::new())
 }
 
+fn process_switch(line: &str, context: &mut Context) -> Result<String, Error> {
+    context.state.if_depth += 1;
+    let switch = if context.state.inactive_stack > 0 {
+        context.state.inactive_stack += 1;
+        Switch {
+            value: None,
+            matched: true,
+        }
+    } else {
+        // Nothing is output until a case matches.
+        context.state.inactive_stack = 1;
+        Switch {
+            value: context.macros.get(line).cloned(),
+            matched: false,
+        }
+    };
+    context.state.switch_stack.push(switch);
+    Ok(String::new())
+}
+
+/// Starts a #case that matches any of `values`, or a #default if it is `None`.
+fn process_case(values: Option<&str>, context: &mut Context) -> Result<String, Error> {
+    let command = if values.is_some() { "case" } else { "default" };
+    let switch = context
+        .state
+        .switch_stack
+        .last_mut()
+        .ok_or(Error::UnexpectedCommand { command })?;
+    if context.state.inactive_stack == 0 {
+        context.state.inactive_stack = 1;
+    } else if context.state.inactive_stack == 1 && !switch.matched {
+        let matches = match (values, &switch.value) {
+            (None, _) => true,
+            (Some(values), Some(value)) => values.split_whitespace().any(|v| v == value),
+            (Some(_), None) => false,
+        };
+        if matches {
+            context.state.inactive_stack = 0;
+            switch.matched = true;
+        }
+    }
+    Ok(String::new())
+}
+
+fn process_endswitch(line: &str, context: &mut Context) -> Result<String, Error> {
+    if !line.is_empty() {
+        return Err(Error::TooManyParameters {
+            command: "endswitch",
+        });
+    }
+    if context.state.switch_stack.pop().is_none() {
+        return Err(Error::UnexpectedCommand {
+            command: "endswitch",
+        });
+    }
+    context.state.if_depth = context.state.if_depth.saturating_sub(1);
+    if context.state.inactive_stack != 0 {
+        context.state.inactive_stack -= 1;
+    }
+    Ok(String::new())
+}
+
 fn process_endif(line: &str, context: &mut Context) -> Result<String, Error> {
     if !line.is_empty() {
         return Err(Error::TooManyParameters { command: "endif" });
     }
-    if context.inactive_stack != 0 {
-        context.inactive_stack -= 1;
+    context.state.if_depth = context.state.if_depth.saturating_sub(1);
+    if context.state.inactive_stack != 0 {
+        context.state.inactive_stack -= 1;
     }
     Ok(String::new())
 }
@@ -386,7 +3732,8 @@ struct Command {
     execute: fn(&str, &mut Context) -> Result<String, Error>,
 }
 
-const COMMANDS: &[Command] = &[
+#[cfg(feature = "exec")]
+const EXEC_COMMANDS: &[Command] = &[
     Command {
         name: "exec",
         requires_exec: true,
@@ -405,24 +3752,180 @@ const COMMANDS: &[Command] = &[
         ignored_by_if: false,
         execute: process_endin,
     },
+    Command {
+        name: "ifexec",
+        requires_exec: true,
+        ignored_by_if: true,
+        execute: process_ifexec,
+    },
+];
+
+#[cfg(not(feature = "exec"))]
+const EXEC_COMMANDS: &[Command] = &[];
+
+const COMMANDS: &[Command] = &[
     Command {
         name: "include",
         requires_exec: false,
         ignored_by_if: false,
         execute: process_include,
     },
+    Command {
+        name: "include_isolated",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_include_isolated,
+    },
+    Command {
+        name: "import",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_import,
+    },
+    Command {
+        name: "export",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_export,
+    },
+    Command {
+        name: "include_base64",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_include_base64,
+    },
     Command {
         name: "define",
         requires_exec: false,
         ignored_by_if: false,
         execute: process_define,
     },
+    Command {
+        name: "using",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_using,
+    },
+    Command {
+        name: "let",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_let,
+    },
+    Command {
+        name: "assert",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_assert,
+    },
+    Command {
+        name: "default",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: process_default,
+    },
+    Command {
+        name: "append",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: |line, context| process_append(line, context, false),
+    },
+    Command {
+        name: "prepend",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: |line, context| process_append(line, context, true),
+    },
     Command {
         name: "undef",
         requires_exec: false,
         ignored_by_if: false,
         execute: process_undef,
     },
+    Command {
+        name: "comment",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: process_comment,
+    },
+    Command {
+        name: "endcomment",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: process_endcomment,
+    },
+    Command {
+        name: "//",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_comment,
+    },
+    Command {
+        name: "raw",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: process_raw,
+    },
+    Command {
+        name: "endraw",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: process_endraw,
+    },
+    Command {
+        name: "capture",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_capture,
+    },
+    Command {
+        name: "endcapture",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_endcapture,
+    },
+    Command {
+        name: "escape",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_escape,
+    },
+    Command {
+        name: "endescape",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_endescape,
+    },
+    Command {
+        name: "date",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_date,
+    },
+    Command {
+        name: "pragma",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_pragma,
+    },
+    Command {
+        name: "mode",
+        requires_exec: false,
+        ignored_by_if: false,
+        execute: process_mode,
+    },
+    Command {
+        name: "if",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: process_if_expression,
+    },
+    Command {
+        name: "elif",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: process_elif_expression,
+    },
     Command {
         name: "ifdef",
         requires_exec: false,
@@ -454,10 +3957,28 @@ const COMMANDS: &[Command] = &[
         execute: process_else,
     },
     Command {
-        name: "endif",
+        name: "endif",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: process_endif,
+    },
+    Command {
+        name: "switch",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: process_switch,
+    },
+    Command {
+        name: "case",
+        requires_exec: false,
+        ignored_by_if: true,
+        execute: |line, context| process_case(Some(line), context),
+    },
+    Command {
+        name: "endswitch",
         requires_exec: false,
         ignored_by_if: true,
-        execute: process_endif,
+        execute: process_endswitch,
     },
 ];
 
@@ -466,24 +3987,91 @@ fn is_word_char(c: char) -> bool {
 }
 
 /// Finds the next macro name word in the line, and replaces it with its value, returning None when
-/// it can't find a macro.
-fn replace_next_macro(line: &str, macros: &HashMap<String, String>) -> Option<String> {
-    macros.iter().find_map(|(name, value)| {
-        let mut parts = line.splitn(2, name);
-        let before = parts.next().unwrap();
-        let after = parts.next()?;
-
-        if before.chars().next_back().map_or(false, is_word_char)
-            || after.chars().next().map_or(false, is_word_char)
-        {
-            return None;
+/// it can't find a macro. See `find_macro` for which macro is replaced.
+fn replace_next_macro(line: &str, context: &Context) -> Option<String> {
+    let (start, name, value) = find_macro(line.as_bytes(), context, |start, end| {
+        !line[..start].chars().next_back().is_some_and(is_word_char)
+            && !line[end..].chars().next().is_some_and(is_word_char)
+            && !is_escaped(&line.as_bytes()[..start], context)
+    })?;
+    let (before, after) = (&line[..start], &line[start + name.len()..]);
+    #[cfg(feature = "tracing")]
+    tracing::trace!(name, "expanding macro");
+    let mut new_line = String::with_capacity(before.len() + value.len() + after.len());
+    new_line.push_str(before);
+    new_line.push_str(value);
+    new_line.push_str(after);
+    Some(new_line)
+}
+
+/// Finds the macro to replace next in the line, returning its position, name and value.
+///
+/// This is the leftmost occurrence of a macro name that `is_word` accepts as a whole word and that
+/// isn't part of a namespaced name. If several names occur at that position, the context's
+/// `precedence` decides between them, so the result doesn't depend on the order of the macros in
+/// the map.
+fn find_macro<'a>(
+    line: &[u8],
+    context: &'a Context,
+    is_word: impl Fn(usize, usize) -> bool,
+) -> Option<(usize, &'a str, &'a str)> {
+    let macros = &context.macros;
+    let mut candidates: Vec<(usize, &str, &str)> = macros
+        .iter()
+        .filter(|(name, _)| !name.is_empty())
+        .filter_map(|(name, value)| {
+            let start = memchr::memmem::find_iter(line, name.as_bytes()).find(|&start| {
+                let end = start + name.len();
+                is_word(start, end)
+                    && !in_namespaced_name(&line[..start], &line[end..], name, macros)
+            })?;
+            Some((start, name.as_str(), value.as_str()))
+        })
+        .collect();
+    let first = candidates.iter().map(|&(start, ..)| start).min()?;
+    candidates.retain(|&(start, ..)| start == first);
+    let chosen = match &context.precedence {
+        Precedence::LongestName => candidates.iter().max_by_key(|(_, name, _)| name.len()),
+        Precedence::DefinitionOrder => candidates.iter().min_by_key(|(_, name, _)| {
+            let defined = context
+                .definition_order
+                .iter()
+                .position(|defined| defined == name);
+            (defined, std::cmp::Reverse(name.len()))
+        }),
+        Precedence::Priority(priorities) => candidates
+            .iter()
+            .max_by_key(|(_, name, _)| (priorities.get(*name).copied().unwrap_or(0), name.len())),
+    };
+    chosen.copied()
+}
+
+/// Replaces every macro in some text, as is done to text lines.
+fn expand_macros<'a>(mut text: Cow<'a, str>, context: &mut Context) -> Cow<'a, str> {
+    if let Some(delimiters) = &context.settings.delimiters {
+        while let Some(s) = replace_next_delimited(text.as_bytes(), context, delimiters) {
+            // Delimiters and names are only ever matched on character boundaries.
+            text = Cow::Owned(String::from_utf8(s).unwrap());
+            if let Some(stats) = &mut context.settings.stats {
+                stats.expansions += 1;
+            }
         }
-        let mut new_line = String::with_capacity(before.len() + value.len() + after.len());
-        new_line.push_str(before);
-        new_line.push_str(value);
-        new_line.push_str(after);
-        Some(new_line)
-    })
+    } else if context
+        .settings
+        .macro_matcher
+        .may_match(text.as_bytes(), &context.settings.macros)
+    {
+        while let Some(s) = replace_next_macro(&text, context) {
+            text = Cow::Owned(s);
+            if let Some(stats) = &mut context.stats {
+                stats.expansions += 1;
+            }
+        }
+    }
+    if let Some(s) = remove_macro_escapes(text.as_bytes(), context) {
+        text = Cow::Owned(String::from_utf8(s).unwrap());
+    }
+    text
 }
 
 /// Process a string line of input.
@@ -494,7 +4082,7 @@ fn replace_next_macro(line: &str, macros: &HashMap<String, String>) -> Option<St
 ///
 /// It returns a Result<String, Error>. If an error occurs, then the Result will be that error.
 /// Otherwise, the returned string is the output. If the input did not contain a newline at the
-/// end, then this function will add it.
+/// end, then this function will add it, unless the context's `preserve_missing_newline` is set.
 ///
 /// # Examples
 ///
@@ -511,68 +4099,621 @@ fn replace_next_macro(line: &str, macros: &HashMap<String, String>) -> Option<St
 /// assert_eq!(context.macros.get("Foo").unwrap(), "Bar");
 /// ```
 pub fn process_line(line: &str, context: &mut Context) -> Result<String, Error> {
-    let line = line
-        .strip_suffix("\r\n")
-        .or_else(|| line.strip_suffix('\n'))
-        .unwrap_or(line);
+    process_line_cow(line, context).map(Cow::into_owned)
+}
+
+/// Process a string line of input, appending the output to `output`.
+///
+/// This is the same as `process_line`, but lets one buffer be reused for many lines instead of
+/// allocating a new string for each. If an error occurs, nothing is appended.
+///
+/// # Examples
+///
+/// ```
+/// let mut context = gpp::Context::new();
+/// let mut output = String::new();
+///
+/// for line in ["#define Foo Bar\n", "Foo\n", "Baz\n"] {
+///     gpp::process_line_into(line, &mut context, &mut output).unwrap();
+/// }
+/// assert_eq!(output, "Bar\nBaz\n");
+/// ```
+pub fn process_line_into(
+    line: &str,
+    context: &mut Context,
+    output: &mut String,
+) -> Result<(), Error> {
+    // A command that trims the output of an enclosing line, such as `#-include`, must not have
+    // its request taken by the lines of the file it processes.
+    let outer_trim = mem::take(&mut context.state.trim_output);
+    let line = process_line_cow(line, context)?;
+    if mem::replace(&mut context.state.trim_output, outer_trim) {
+        output.truncate(output.len() - line_ending_len(output.as_bytes()));
+    }
+    output.push_str(&line);
+    Ok(())
+}
+
+/// Processes a line in the same way as `process_line`, but borrows the output from the input when
+/// the line is output unchanged.
+fn process_line_cow<'a>(line: &'a str, context: &mut Context) -> Result<Cow<'a, str>, Error> {
+    match context.pre_line.take() {
+        Some(hook) => {
+            let result = (hook.lock())(line, context);
+            context.pre_line.get_or_insert(hook);
+            match result {
+                Some(line) => process_hooked_line(&line, context)
+                    .map(|output| Cow::Owned(output.into_owned())),
+                None => Ok(Cow::Borrowed("")),
+            }
+        }
+        None => process_hooked_line(line, context),
+    }
+}
+
+/// Processes a line that has already been given to the `pre_line` hook.
+fn process_hooked_line<'a>(line: &'a str, context: &mut Context) -> Result<Cow<'a, str>, Error> {
+    if context.state.in_raw {
+        return process_raw_line(Cow::Borrowed(line), context);
+    }
+    let input = line;
+
+    let (line, ending) = if let Some(line) = line.strip_suffix("\r\n") {
+        (line, "\r\n")
+    } else if let Some(line) = line.strip_suffix('\n') {
+        (line, "\n")
+    } else {
+        (line, "")
+    };
 
     enum Line<'a> {
-        Text(&'a str),
-        Command(Command, &'a str),
+        Text(&'a str, &'a str),
+        /// A command, the program given after its name by `#exec:program` or `#in:program`, its
+        /// parameters, and whether to trim the line ending before and after it.
+        Command(Command, Option<&'a str>, &'a str, bool, bool),
     }
 
-    let line = if let Some(rest) = line.strip_prefix('#') {
-        if rest.starts_with('#') {
-            Line::Text(rest)
+    let indent = &line[..indent_len(line.as_bytes(), context)];
+    let line = if let Some((_, range)) = find_command(line.as_bytes(), context) {
+        let rest = &line[range];
+        let prefix = command_prefix(context);
+        if rest.starts_with(prefix) {
+            Line::Text(indent, &line[indent.len() + prefix.len()..])
         } else {
-            let mut parts = rest.trim_start().splitn(2, ' ');
-            let command_name = parts.next().unwrap();
-            let content = parts.next().unwrap_or("").trim_start();
-
-            Line::Command(
-                COMMANDS
-                    .iter()
-                    .copied()
-                    .filter(|command| context.allow_exec || !command.requires_exec)
-                    .find(|command| command.name == command_name)
-                    .ok_or_else(|| Error::InvalidCommand {
+            let (command_name, content) = split_command(rest, context);
+            let (trim_before, command_name) = match command_name.strip_prefix('-') {
+                Some(name) => (true, name),
+                None => (false, command_name),
+            };
+            let (trim_after, command_name) = match command_name.strip_suffix('-') {
+                Some(name) => (true, name),
+                None => (false, command_name),
+            };
+            let (command_name, interpreter) = split_interpreter(command_name);
+
+            if context.state.comment_depth > 0 {
+                match (command_name, content) {
+                    ("comment", "") => context.state.comment_depth += 1,
+                    ("endcomment", _) => context.state.comment_depth -= 1,
+                    _ => {}
+                }
+                return Ok(Cow::Borrowed(""));
+            }
+
+            let command = EXEC_COMMANDS
+                .iter()
+                .chain(COMMANDS)
+                .copied()
+                .find(|command| command.name == command_name);
+            match command {
+                Some(command) => {
+                    if command.requires_exec && !context.allow_exec && !context.dry_run {
+                        return Err(Error::ExecDisabled {
+                            command: command.name,
+                        });
+                    }
+                    #[cfg(target_family = "wasm")]
+                    if command.requires_exec && !context.dry_run {
+                        return Err(Error::Unsupported {
+                            command: command.name,
+                        });
+                    }
+
+                    let content = match &context.directive_comment {
+                        Some(marker) => strip_directive_comment(content, marker),
+                        None => content,
+                    };
+                    Line::Command(command, interpreter, content, trim_before, trim_after)
+                }
+                None if context.pass_unknown_commands => Line::Text(indent, &line[indent.len()..]),
+                None => {
+                    return Err(Error::InvalidCommand {
                         command_name: command_name.to_owned(),
-                    })?,
-                content,
-            )
+                    })
+                }
+            }
         }
     } else {
-        Line::Text(line)
+        Line::Text("", line)
     };
 
     let line = match line {
-        Line::Text(_)
+        Line::Text(..)
         | Line::Command(
             Command {
                 ignored_by_if: false,
                 ..
             },
-            _,
-        ) if context.inactive_stack > 0 => String::new(),
-        Line::Text(text) => {
-            let mut line = format!("{}\n", text);
+            ..,
+        ) if context.state.inactive_stack > 0 || context.state.comment_depth > 0 => {
+            Cow::Borrowed("")
+        }
+        Line::Text(indent, text) => {
+            let output_ending = line_ending(ending, context);
+            // Most lines are output as they are, so they are only copied if they change.
+            let line = if indent.is_empty()
+                && text.len() + ending.len() == input.len()
+                && output_ending == ending
+            {
+                Cow::Borrowed(input)
+            } else {
+                Cow::Owned(format!("{}{}{}", indent, text, output_ending))
+            };
 
-            while let Some(s) = replace_next_macro(&line, &context.macros) {
-                line = s;
+            apply_post_line(expand_macros(line, context), context)
+        }
+        Line::Command(command, interpreter, content, trim_before, trim_after) => {
+            if trim_before && context.state.inactive_stack == 0 {
+                trim_previous_line_ending(context);
+            }
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                command = command.name,
+                parameters = content,
+                "running command"
+            );
+            if let Some(stats) = &mut context.stats {
+                stats.commands += 1;
             }
+            let mut output = match interpreter {
+                Some(program) => process_interpreter(command.name, program, content, context)?,
+                None => (command.execute)(content, context)?,
+            };
+            if trim_after {
+                output.truncate(output.len() - line_ending_len(output.as_bytes()));
+            }
+            let output = if indent.is_empty() || !context.reindent {
+                output
+            } else {
+                output
+                    .split_inclusive('\n')
+                    .map(|line| match line {
+                        "\n" | "\r\n" => line.to_owned(),
+                        line => format!("{}{}", indent, line),
+                    })
+                    .collect()
+            };
+            // The lines of included files have already been passed to the hook.
+            if matches!(command.name, "include" | "include_isolated" | "import") {
+                Cow::Owned(output)
+            } else {
+                apply_post_line(Cow::Owned(output), context)
+            }
+        }
+    };
+
+    pipe_output(line, context)
+}
+
+/// The length of the line ending at the end of some output, if it has one.
+fn line_ending_len(output: &[u8]) -> usize {
+    if output.ends_with(b"\r\n") {
+        2
+    } else if output.ends_with(b"\n") {
+        1
+    } else {
+        0
+    }
+}
 
-            line
+/// Removes the line ending from the end of the output so far, for a command written as `#-name`.
+///
+/// Output that has been captured or collected for an #in is trimmed straight away, but output
+/// that has already been given to a running process can't be.
+fn trim_previous_line_ending(context: &mut Context) {
+    let depth = in_depth(context);
+    if let Some(capture) = context.state.capture_stack.last_mut() {
+        if capture.in_depth == depth {
+            capture
+                .output
+                .truncate(capture.output.len() - line_ending_len(&capture.output));
+            return;
+        }
+    }
+    #[cfg(feature = "exec")]
+    match context.state.in_stack.last_mut() {
+        Some(InBlock::Buffered { input, .. }) => {
+            input.truncate(input.len() - line_ending_len(input));
+            return;
         }
-        Line::Command(command, content) => (command.execute)(content, context)?,
+        #[cfg(not(target_family = "wasm"))]
+        Some(InBlock::Child { .. }) => return,
+        None => {}
+    }
+    context.state.trim_output = true;
+}
+
+/// Removes a trailing comment that starts with `marker` from a command's parameters.
+fn strip_directive_comment<'a>(content: &'a str, marker: &str) -> &'a str {
+    if marker.is_empty() {
+        return content;
+    }
+    let start = content.match_indices(marker).map(|(i, _)| i).find(|&i| {
+        content[..i]
+            .chars()
+            .next_back()
+            .is_none_or(char::is_whitespace)
+    });
+    match start {
+        Some(start) => content[..start].trim_end(),
+        None => content,
+    }
+}
+
+/// The output of processing a line, which is text or, for lines that aren't valid UTF-8, bytes.
+trait LineOutput: From<String> + Default {
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl LineOutput for Cow<'_, str> {
+    fn as_bytes(&self) -> &[u8] {
+        str::as_bytes(self)
+    }
+}
+
+impl LineOutput for Vec<u8> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Process a line inside a #raw block, which is output exactly as it is unless it ends the block.
+fn process_raw_line<T: LineOutput>(line: T, context: &mut Context) -> Result<T, Error> {
+    let bytes = line.as_bytes();
+    let command = find_command(bytes, context).map(|(_, range)| &bytes[range]);
+    if command.is_some_and(|command| command.trim_ascii() == b"endraw") {
+        context.state.in_raw = false;
+        return Ok(T::default());
+    }
+    if context.state.inactive_stack > 0 {
+        return Ok(T::default());
+    }
+    let line = apply_post_line(line, context);
+    pipe_output(line, context)
+}
+
+/// Passes the output of a line to the context's `post_line` hook if it has one.
+fn apply_post_line<T: LineOutput>(output: T, context: &mut Context) -> T {
+    let text = match std::str::from_utf8(output.as_bytes()) {
+        Ok(text) if !text.is_empty() => text,
+        _ => return output,
     };
+    match context.post_line.take() {
+        Some(hook) => {
+            let result = (hook.lock())(text, context);
+            context.post_line.get_or_insert(hook);
+            result.map_or_else(T::default, T::from)
+        }
+        None => output,
+    }
+}
 
-    Ok(if let Some(child) = context.in_stack.last_mut() {
-        let input = child.stdin.as_mut().ok_or(Error::PipeFailed)?;
-        input.write_all(line.as_bytes())?;
-        String::new()
+/// The length of the whitespace that may precede a command on this line.
+fn indent_len(line: &[u8], context: &Context) -> usize {
+    if context.indented_commands || context.reindent {
+        line.iter()
+            .take_while(|&&b| b == b' ' || b == b'\t')
+            .count()
     } else {
-        line
-    })
+        0
+    }
+}
+
+/// The line ending to output for a text line, given the line ending it had in the input.
+fn line_ending(ending: &'static str, context: &Context) -> &'static str {
+    match ending {
+        "\r\n" if context.preserve_line_endings => ending,
+        "" if context.preserve_missing_newline => ending,
+        _ => "\n",
+    }
+}
+
+/// The number of #in commands that are currently running.
+#[cfg_attr(not(feature = "exec"), allow(unused_variables))]
+fn in_depth(context: &Context) -> usize {
+    #[cfg(feature = "exec")]
+    let depth = context.state.in_stack.len();
+    #[cfg(not(feature = "exec"))]
+    let depth = 0;
+    depth
+}
+
+/// Gives processed output to the innermost #in command or #capture block if there is one,
+/// otherwise returns it.
+fn pipe_output<T: LineOutput>(output: T, context: &mut Context) -> Result<T, Error> {
+    let depth = in_depth(context);
+    if let Some(capture) = context.state.capture_stack.last_mut() {
+        if capture.in_depth == depth {
+            capture.output.extend_from_slice(output.as_bytes());
+            return Ok(T::default());
+        }
+    }
+
+    #[cfg(feature = "exec")]
+    match context.state.in_stack.last_mut() {
+        #[cfg(not(target_family = "wasm"))]
+        Some(InBlock::Child { child, .. }) => {
+            write_input(child, output.as_bytes())?;
+            return Ok(T::default());
+        }
+        Some(InBlock::Buffered { input, .. }) => {
+            input.extend_from_slice(output.as_bytes());
+            return Ok(T::default());
+        }
+        None => {}
+    }
+
+    Ok(output)
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || !b.is_ascii()
+}
+
+/// The byte equivalent of `replace_next_macro`. Non-ASCII bytes are treated as word characters.
+fn replace_next_macro_bytes(line: &[u8], context: &Context) -> Option<Vec<u8>> {
+    let (start, name, value) = find_macro(line, context, |start, end| {
+        !line[..start].last().copied().is_some_and(is_word_byte)
+            && !line.get(end).copied().is_some_and(is_word_byte)
+            && !is_escaped(&line[..start], context)
+    })?;
+    #[cfg(feature = "tracing")]
+    tracing::trace!(name, "expanding macro");
+    Some(
+        [
+            &line[..start],
+            value.as_bytes(),
+            &line[start + name.len()..],
+        ]
+        .concat(),
+    )
+}
+
+/// Whether a macro name found in text is part of the name of a namespaced macro that is written
+/// around it, so that `theme` isn't replaced in `theme.color` when `theme.color` is defined.
+fn in_namespaced_name(
+    before: &[u8],
+    after: &[u8],
+    name: &str,
+    macros: &HashMap<String, String>,
+) -> bool {
+    // Between namespaced names that overlap, such as `a.b` and `a.b.c`, the precedence decides.
+    if name.contains('.') {
+        return false;
+    }
+    let is_name_byte = |&&b: &&u8| is_word_byte(b) || b == b'.';
+    let offset = before.iter().rev().take_while(&is_name_byte).count();
+    let end = after.iter().take_while(&is_name_byte).count();
+    if offset == 0 && end == 0 {
+        return false;
+    }
+    // The dotted word that the name was found in, and where the name is in it.
+    let word = [
+        &before[before.len() - offset..],
+        name.as_bytes(),
+        &after[..end],
+    ]
+    .concat();
+    let found = offset..offset + name.len();
+    macros
+        .keys()
+        .filter(|key| *key != name && key.contains('.'))
+        .any(|key| {
+            memchr::memmem::find_iter(&word, key.as_bytes()).any(|start| {
+                let end = start + key.len();
+                start <= found.start
+                    && end >= found.end
+                    && (start == 0 || word[start - 1] == b'.')
+                    && (end == word.len() || word[end] == b'.')
+            })
+        })
+}
+
+/// Finds the next macro written between the delimiters in the line, and replaces it with its
+/// value, returning None when it can't find a macro.
+fn replace_next_delimited(
+    line: &[u8],
+    context: &Context,
+    delimiters: &Delimiters,
+) -> Option<Vec<u8>> {
+    let (prefix, suffix) = (delimiters.prefix.as_bytes(), delimiters.suffix.as_bytes());
+    let mut start = 0;
+    while let Some(position) = line.get(start..).and_then(|rest| find_bytes(rest, prefix)) {
+        let name_start = start + position + prefix.len();
+        let rest = &line[name_start..];
+        let name_len = if suffix.is_empty() {
+            rest.iter().take_while(|&&b| is_word_byte(b)).count()
+        } else {
+            find_bytes(rest, suffix)?
+        };
+        let macro_match = std::str::from_utf8(&rest[..name_len])
+            .ok()
+            .filter(|_| !is_escaped(&line[..start + position], context))
+            .and_then(|expansion| expand_delimited(expansion, context));
+        if let Some(value) = macro_match {
+            let after = &rest[name_len + suffix.len()..];
+            return Some([&line[..start + position], value.as_bytes(), after].concat());
+        }
+        start = name_start.max(start + 1);
+    }
+    None
+}
+
+/// Whether the text before a macro ends with the context's `macro_escape` marker, so the macro
+/// must not be replaced.
+fn is_escaped(before: &[u8], context: &Context) -> bool {
+    context
+        .macro_escape
+        .as_ref()
+        .is_some_and(|marker| before.ends_with(marker.as_bytes()))
+}
+
+/// Removes the `macro_escape` marker from before each macro that it stopped from being replaced,
+/// returning `None` if there are none.
+fn remove_macro_escapes(line: &[u8], context: &Context) -> Option<Vec<u8>> {
+    let marker = context.macro_escape.as_ref()?.as_bytes();
+    let mut output = Vec::new();
+    let mut copied = 0;
+    for position in memchr::memmem::find_iter(line, marker) {
+        if position >= copied && is_macro_start(&line[position + marker.len()..], context) {
+            output.extend_from_slice(&line[copied..position]);
+            copied = position + marker.len();
+        }
+    }
+    if copied == 0 {
+        return None;
+    }
+    output.extend_from_slice(&line[copied..]);
+    Some(output)
+}
+
+/// Whether some text starts with a macro that would be replaced.
+fn is_macro_start(text: &[u8], context: &Context) -> bool {
+    match &context.delimiters {
+        Some(delimiters) => {
+            let rest = match text.strip_prefix(delimiters.prefix.as_bytes()) {
+                Some(rest) => rest,
+                None => return false,
+            };
+            let name_len = if delimiters.suffix.is_empty() {
+                rest.iter().take_while(|&&b| is_word_byte(b)).count()
+            } else {
+                match find_bytes(rest, delimiters.suffix.as_bytes()) {
+                    Some(len) => len,
+                    None => return false,
+                }
+            };
+            std::str::from_utf8(&rest[..name_len])
+                .is_ok_and(|expansion| expand_delimited(expansion, context).is_some())
+        }
+        None => context.macros.keys().any(|name| {
+            !name.is_empty()
+                && text.starts_with(name.as_bytes())
+                && !text.get(name.len()).copied().is_some_and(is_word_byte)
+        }),
+    }
+}
+
+/// Gets the value of a macro written between delimiters, which is its name optionally followed by
+/// filters separated by `|`. Returns `None` if the macro or one of the filters doesn't exist.
+fn expand_delimited<'a>(expansion: &str, context: &'a Context) -> Option<Cow<'a, str>> {
+    let mut parts = expansion.split('|');
+    let name = parts.next().unwrap();
+    let mut value = Cow::Borrowed(context.macros.get(name)?.as_str());
+    for filter in parts {
+        value = Cow::Owned(filter::apply(filter, &value)?);
+    }
+    #[cfg(feature = "tracing")]
+    tracing::trace!(name, "expanding macro");
+    Some(value)
+}
+
+/// Finds the first position of `needle` in `haystack`.
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    memchr::memmem::find(haystack, needle)
+}
+
+/// Process a line of input that may not be valid UTF-8.
+///
+/// Lines that are valid UTF-8 are processed exactly as `process_line` does. Other lines are
+/// decoded lossily if they are commands, and otherwise have macros replaced byte by byte, so any
+/// invalid bytes are passed through unchanged.
+///
+/// # Examples
+///
+/// ```
+/// let mut context = gpp::Context::new();
+/// context.macros.insert("Foo".to_string(), "Bar".to_string());
+///
+/// assert_eq!(gpp::process_line_bytes(b"Foo \xE9", &mut context).unwrap(), b"Bar \xE9\n");
+/// ```
+pub fn process_line_bytes(line: &[u8], context: &mut Context) -> Result<Vec<u8>, Error> {
+    process_line_bytes_cow(line, context).map(Cow::into_owned)
+}
+
+/// The byte equivalent of `process_line_cow`.
+fn process_line_bytes_cow<'a>(
+    line: &'a [u8],
+    context: &mut Context,
+) -> Result<Cow<'a, [u8]>, Error> {
+    if let Ok(line) = std::str::from_utf8(line) {
+        return Ok(match process_line_cow(line, context)? {
+            Cow::Borrowed(output) => Cow::Borrowed(output.as_bytes()),
+            Cow::Owned(output) => Cow::Owned(output.into_bytes()),
+        });
+    }
+    if context.state.in_raw {
+        return process_raw_line(line.to_vec(), context).map(Cow::Owned);
+    }
+
+    let (line, ending) = if let Some(line) = line.strip_suffix(b"\r\n") {
+        (line, "\r\n")
+    } else if let Some(line) = line.strip_suffix(b"\n") {
+        (line, "\n")
+    } else {
+        (line, "")
+    };
+    let prefix = command_prefix(context).as_bytes();
+    let (indent, text) = match find_command(line, context) {
+        Some((indent_len, range)) if line[range.clone()].starts_with(prefix) => {
+            (&line[..indent_len], &line[indent_len + prefix.len()..])
+        }
+        Some(_) => {
+            return process_line(&String::from_utf8_lossy(line), context)
+                .map(|output| Cow::Owned(output.into_bytes()))
+        }
+        None => (&[][..], line),
+    };
+
+    if context.state.inactive_stack > 0 || context.state.comment_depth > 0 {
+        return Ok(Cow::Borrowed(&[]));
+    }
+
+    let mut line = [indent, text, line_ending(ending, context).as_bytes()].concat();
+    if let Some(delimiters) = &context.settings.delimiters {
+        while let Some(new_line) = replace_next_delimited(&line, context, delimiters) {
+            line = new_line;
+            if let Some(stats) = &mut context.settings.stats {
+                stats.expansions += 1;
+            }
+        }
+    } else if context
+        .settings
+        .macro_matcher
+        .may_match(&line, &context.settings.macros)
+    {
+        while let Some(new_line) = replace_next_macro_bytes(&line, context) {
+            line = new_line;
+            if let Some(stats) = &mut context.stats {
+                stats.expansions += 1;
+            }
+        }
+    }
+    if let Some(new_line) = remove_macro_escapes(&line, context) {
+        line = new_line;
+    }
+
+    pipe_output(line, context).map(Cow::Owned)
 }
 
 /// Process a multi-line string of text.
@@ -590,33 +4731,352 @@ pub fn process_str(s: &str, context: &mut Context) -> Result<String, Error> {
 
 /// Process a file.
 ///
-/// See `process_buf` for more details.
+/// The file, and any files it includes, are recorded in the context's `state.dependencies`. See
+/// `process_buf` for more details. It isn't available on WebAssembly, which has no filesystem.
+#[cfg(not(target_family = "wasm"))]
 pub fn process_file(filename: &str, context: &mut Context) -> Result<String, Error> {
     let file_raw = File::open(filename)?;
     let file = BufReader::new(file_raw);
+    context.add_dependency(Path::new(filename));
+
+    context.in_file(Path::new(filename), |context| {
+        process_buf(file, filename, context)
+    })
+}
 
-    process_buf(file, filename, context)
+/// Skips a UTF-8 byte order mark at the start of the buffer, returning whether there was one.
+fn skip_bom<T: BufRead>(buf: &mut T) -> io::Result<bool> {
+    let bom = buf.fill_buf()?.starts_with(b"\xEF\xBB\xBF");
+    if bom {
+        buf.consume(3);
+    }
+    Ok(bom)
 }
 
 /// Process a generic BufRead.
 ///
 /// This function is a wrapper around `process_line`. It splits up the input into lines (adding a
 /// newline on the end if there isn't one) and then processes each line.
+///
+/// A UTF-8 byte order mark at the start of the input is removed before processing, and is only
+/// output if the context's `preserve_bom` is set.
 pub fn process_buf<T: BufRead>(
+    mut buf: T,
+    buf_name: &str,
+    context: &mut Context,
+) -> Result<String, Error> {
+    let mut output = String::new();
+    if skip_bom(&mut buf)? && context.preserve_bom {
+        output.push('\u{feff}');
+    }
+    process_lines(buf, buf_name, None, context, &mut output)?;
+    if let Some(stats) = &mut context.stats {
+        stats.bytes += output.len() as u64;
+    }
+    Ok(output)
+}
+
+/// Processes the lines of a buffer. `excerpt` is as for `process_included`.
+fn process_lines<T: BufRead>(
     buf: T,
     buf_name: &str,
+    excerpt: Option<usize>,
+    context: &mut Context,
+    output: &mut String,
+) -> Result<(), Error> {
+    process_scoped(buf_name, context, |context| {
+        process_lines_unscoped(buf, buf_name, excerpt, context, output)
+    })
+}
+
+/// Processes a buffer with `process`, tracing it and timing it, and restores the settings changed
+/// by #pragma afterwards.
+fn process_scoped(
+    buf_name: &str,
+    context: &mut Context,
+    process: impl FnOnce(&mut Context) -> Result<(), Error>,
+) -> Result<(), Error> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("file", name = buf_name).entered();
+    trace(TraceEvent::Open { filename: buf_name }, context);
+    let start = context.stats.is_some().then(Instant::now);
+    let pragmas = Pragmas::save(context);
+    #[cfg(feature = "exec")]
+    let in_depth = context.state.in_stack.len();
+    let result = process(context);
+    pragmas.restore(context);
+    // Children of #in blocks that were left open by an error would otherwise never be waited for,
+    // and could block forever writing output that is never read.
+    #[cfg(feature = "exec")]
+    if result.is_err() {
+        kill_in_blocks(context, in_depth);
+    }
+    if let (Some(stats), Some(start)) = (&mut context.stats, start) {
+        stats.files.push(FileTime {
+            name: buf_name.to_owned(),
+            elapsed: start.elapsed(),
+        });
+    }
+    trace(TraceEvent::Close { filename: buf_name }, context);
+    result
+}
+
+/// Wraps an error from line `num` of a buffer in a `FileError`. If the context has an
+/// `error_marker`, the error is recorded instead and the marker to output in place of the line is
+/// returned.
+fn recover_line(
+    error: Error,
+    buf_name: &str,
+    num: usize,
+    line: &[u8],
     context: &mut Context,
 ) -> Result<String, Error> {
-    buf.lines()
-        .enumerate()
-        .map(|(num, line)| {
-            Ok({
-                process_line(&line?, context).map_err(|e| Error::FileError {
-                    filename: String::from(buf_name),
-                    line: num,
-                    error: Box::new(e),
-                })?
-            })
-        })
-        .collect()
+    let error = Error::FileError {
+        filename: PathBuf::from(buf_name),
+        line: num,
+        error: Box::new(error),
+    };
+    let marker = match &context.error_marker {
+        Some(marker) => marker.replace("{error}", &error.to_string()),
+        None => return Err(error),
+    };
+    context.state.recovered_errors.push(error);
+    let ending = if line.ends_with(b"\r\n") {
+        "\r\n"
+    } else if line.ends_with(b"\n") {
+        "\n"
+    } else {
+        ""
+    };
+    Ok(format!("{}{}", marker, line_ending(ending, context)))
+}
+
+/// Processes the lines of a buffer, without restoring the settings changed by #pragma afterwards.
+fn process_lines_unscoped<T: BufRead>(
+    mut buf: T,
+    buf_name: &str,
+    excerpt: Option<usize>,
+    context: &mut Context,
+    output: &mut String,
+) -> Result<(), Error> {
+    let mut line = String::new();
+    let mut num = excerpt.unwrap_or(0);
+    let mut open_blocks = OpenBlocks::default();
+    while buf.read_line(&mut line)? != 0 {
+        let mut joined: usize = 0;
+        while let Some(len) = continued_len(line.as_bytes(), context) {
+            line.truncate(len);
+            if buf.read_line(&mut line)? == 0 {
+                break;
+            }
+            joined += 1;
+        }
+        if let Some(stats) = &mut context.stats {
+            stats.lines += 1 + joined as u64;
+        }
+        let depths = block_depths(context);
+        let conditional = trace_command(context, buf_name, line.as_bytes(), num);
+        if let Err(e) = process_line_into(&line, context, output) {
+            let marker = recover_line(e, buf_name, num, line.as_bytes(), context)?;
+            output.push_str(&marker);
+        }
+        if conditional {
+            trace_conditional(context, buf_name, line.as_bytes(), num);
+        }
+        open_blocks.update(depths, context, line.as_bytes(), num);
+        line.clear();
+        num += 1 + joined;
+    }
+    match excerpt {
+        Some(_) => Ok(()),
+        None => open_blocks.check(buf_name),
+    }
+}
+
+/// If a line continues onto the next line, returns the length of the line without its trailing
+/// backslash and line ending.
+fn continued_len(line: &[u8], context: &Context) -> Option<usize> {
+    if !context.line_continuation || context.state.in_raw || command_name(line, context).is_some() {
+        return None;
+    }
+    line[..line.len() - line_ending_len(line)]
+        .strip_suffix(b"\\")
+        .map(<[u8]>::len)
+}
+
+/// The kinds of block that must be closed in the file that opens them, and the commands that open
+/// them. #raw blocks are not included, as they may run to the end of the file.
+const BLOCKS: [&str; 4] = ["ifdef", "in", "capture", "comment"];
+
+/// How many of each kind of block in `BLOCKS` the parser is in.
+fn block_depths(context: &Context) -> [usize; 4] {
+    [
+        context.state.if_depth as usize,
+        in_depth(context),
+        context.state.capture_stack.len(),
+        context.state.comment_depth as usize,
+    ]
+}
+
+/// The blocks opened by a buffer that haven't been closed yet, and the lines they were opened on.
+#[derive(Default)]
+struct OpenBlocks(Vec<(usize, &'static str, usize)>);
+
+impl OpenBlocks {
+    /// Updates the open blocks after a line has been processed, given how many blocks of each kind
+    /// the parser was in before the line.
+    fn update(&mut self, before: [usize; 4], context: &Context, line: &[u8], num: usize) {
+        let after = block_depths(context);
+        let command = command_name(line, context);
+        // Blocks left open by an included file are reported by that file, unless only some of its
+        // lines were included, in which case they are left open on purpose.
+        let included = matches!(command, Some("include" | "include_isolated" | "import"));
+        for kind in 0..BLOCKS.len() {
+            for _ in before[kind]..after[kind] {
+                if !included {
+                    self.0.push((kind, command.unwrap_or(BLOCKS[kind]), num));
+                }
+            }
+            for _ in after[kind]..before[kind] {
+                // A block opened by an including file can be closed here, in which case it won't
+                // be found.
+                if let Some(i) = self.0.iter().rposition(|&(k, ..)| k == kind) {
+                    self.0.remove(i);
+                }
+            }
+        }
+    }
+    /// Returns an error naming the innermost block that is still open, if there is one.
+    fn check(self, buf_name: &str) -> Result<(), Error> {
+        match self.0.last() {
+            Some(&(_, command, line)) => Err(Error::FileError {
+                filename: PathBuf::from(buf_name),
+                line,
+                error: Box::new(Error::UnclosedBlock { command }),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The prefix that commands start with.
+fn command_prefix(context: &Context) -> &str {
+    match &context.command_delimiters {
+        Some(delimiters) => &delimiters.prefix,
+        None => "#",
+    }
+}
+
+/// If a line is written as a command, returns the length of its indentation and the range of the
+/// text between the command prefix and suffix, without the whitespace before the suffix.
+fn find_command(line: &[u8], context: &Context) -> Option<(usize, Range<usize>)> {
+    let indent = indent_len(line, context);
+    let (prefix, suffix) = match &context.command_delimiters {
+        Some(delimiters) => (delimiters.prefix.as_bytes(), delimiters.suffix.as_bytes()),
+        None => (&b"#"[..], &b""[..]),
+    };
+    if !line[indent..].starts_with(prefix) {
+        return None;
+    }
+    let start = indent + prefix.len();
+    let end = match suffix {
+        [] => line.len(),
+        suffix => {
+            let inner = line.trim_ascii_end().strip_suffix(suffix)?;
+            if inner.len() < start {
+                return None;
+            }
+            inner.trim_ascii_end().len().max(start)
+        }
+    };
+    Some((indent, start..end))
+}
+
+/// Splits the program off the name of an `#exec:program` or `#in:program` command.
+fn split_interpreter(name: &str) -> (&str, Option<&str>) {
+    match name.split_once(':') {
+        Some((name @ ("exec" | "in"), program)) => (name, Some(program)),
+        _ => (name, None),
+    }
+}
+
+/// The name of the command on a line, if it is a known command.
+fn command_name(line: &[u8], context: &Context) -> Option<&'static str> {
+    let (_, range) = find_command(line, context)?;
+    let text = std::str::from_utf8(&line[range]).ok()?.trim_end();
+    let (name, _) = split_command(text, context);
+    let name = name.strip_prefix('-').unwrap_or(name);
+    let name = name.strip_suffix('-').unwrap_or(name);
+    let (name, _) = split_interpreter(name);
+    EXEC_COMMANDS
+        .iter()
+        .chain(COMMANDS)
+        .map(|command| command.name)
+        .find(|&command| command == name)
+}
+
+/// Process a generic BufRead that may not be valid UTF-8.
+///
+/// This is the same as `process_buf`, but uses `process_line_bytes` instead of `process_line`, so
+/// lines that aren't valid UTF-8 pass through unharmed. Files that are #included must still be
+/// valid UTF-8.
+pub fn process_buf_bytes<T: BufRead>(
+    mut buf: T,
+    buf_name: &str,
+    context: &mut Context,
+) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+    if skip_bom(&mut buf)? && context.preserve_bom {
+        output.extend_from_slice(b"\xEF\xBB\xBF");
+    }
+    process_scoped(buf_name, context, |context| {
+        process_lines_bytes(buf, buf_name, context, &mut output)
+    })?;
+    if let Some(stats) = &mut context.stats {
+        stats.bytes += output.len() as u64;
+    }
+    Ok(output)
+}
+
+/// The byte equivalent of `process_lines_unscoped`.
+fn process_lines_bytes<T: BufRead>(
+    mut buf: T,
+    buf_name: &str,
+    context: &mut Context,
+    output: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let mut line = Vec::new();
+    let mut num = 0;
+    let mut open_blocks = OpenBlocks::default();
+    while buf.read_until(b'\n', &mut line)? != 0 {
+        let mut joined: usize = 0;
+        while let Some(len) = continued_len(&line, context) {
+            line.truncate(len);
+            if buf.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            joined += 1;
+        }
+        if let Some(stats) = &mut context.stats {
+            stats.lines += 1 + joined as u64;
+        }
+        let depths = block_depths(context);
+        let conditional = trace_command(context, buf_name, &line, num);
+        let outer_trim = mem::take(&mut context.state.trim_output);
+        let line_output = match process_line_bytes_cow(&line, context) {
+            Ok(line_output) => line_output,
+            Err(e) => Cow::Owned(recover_line(e, buf_name, num, &line, context)?.into_bytes()),
+        };
+        if mem::replace(&mut context.state.trim_output, outer_trim) {
+            output.truncate(output.len() - line_ending_len(output));
+        }
+        output.extend_from_slice(&line_output);
+        if conditional {
+            trace_conditional(context, buf_name, &line, num);
+        }
+        open_blocks.update(depths, context, &line, num);
+        line.clear();
+        num += 1 + joined;
+    }
+    open_blocks.check(buf_name)
 }
@@ -16,6 +16,158 @@ fn substitution() {
     );
 }
 
+#[test]
+fn bytes() {
+    let mut context = crate::Context::new();
+    assert_eq!(
+        crate::process_buf_bytes(
+            &b"#define Foo Bar\r\nFoo \xFF Foo\xFF\n#ifdef Baz\n\xFF\n#endif\n##\xFF"[..],
+            "<bytes>",
+            &mut context
+        )
+        .unwrap(),
+        b"Bar \xFF Foo\xFF\n#\xFF\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "encoding")]
+fn encoding() {
+    let input: Vec<u8> = "\u{feff}#define A Ä\nA\n"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    let output =
+        crate::encoding::process_encoded(&input, "<utf16>", None, &mut crate::Context::new());
+    let expected: Vec<u8> = "\u{feff}Ä\n"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    assert_eq!(output.unwrap(), expected);
+}
+
+#[test]
+#[cfg(feature = "encoding")]
+fn include_encoding() {
+    let dir = std::env::temp_dir().join("gpp_include_encoding_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let utf16 = dir.join("utf16.txt");
+    let bytes: Vec<u8> = "\u{feff}#define A Ä\nA\n"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    std::fs::write(&utf16, bytes).unwrap();
+    let latin1 = dir.join("latin1.txt");
+    std::fs::write(&latin1, b"caf\xE9\n").unwrap();
+
+    let mut context = crate::Context::new();
+    assert_eq!(
+        crate::process_str(&format!("#include {}\n", utf16.display()), &mut context).unwrap(),
+        "Ä\n"
+    );
+    assert!(crate::process_str(&format!("#include {}\n", latin1.display()), &mut context).is_err());
+
+    let mut context = crate::Context::builder()
+        .encoding(crate::encoding::encoding_rs::WINDOWS_1252)
+        .include_cache(true)
+        .build()
+        .unwrap();
+    let include = format!("#include {}\n", latin1.display());
+    assert_eq!(
+        crate::process_str(&include.repeat(2), &mut context).unwrap(),
+        "café\ncafé\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "git")]
+fn git() {
+    use std::process::Command;
+
+    let git = |dir: &std::path::Path, args: &[&str]| {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=gpp", "-c", "user.email=gpp@example.com"])
+            .args(["-c", "commit.gpgsign=false"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .trim_end()
+            .to_owned()
+    };
+    let dir = std::env::temp_dir().join("gpp_test_git");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    git(&dir, &["init", "-q"]);
+    git(&dir, &["symbolic-ref", "HEAD", "refs/heads/trunk"]);
+
+    // A repository without commits has no macros.
+    assert!(crate::git::git_macros(&dir).unwrap().is_empty());
+
+    std::fs::write(dir.join("test.txt"), "test\n").unwrap();
+    git(&dir, &["add", "test.txt"]);
+    git(&dir, &["commit", "-q", "-m", "Test"]);
+    let macros = crate::git::git_macros(dir.join("test.txt")).unwrap();
+    assert_eq!(macros["__GIT_HASH__"], git(&dir, &["rev-parse", "HEAD"]));
+    assert!(macros["__GIT_HASH__"].starts_with(&macros["__GIT_SHORT_HASH__"]));
+    assert_eq!(macros["__GIT_BRANCH__"], "trunk");
+    assert!(!macros.contains_key("__GIT_DIRTY__"));
+
+    std::fs::write(dir.join("test.txt"), "changed\n").unwrap();
+    let macros = crate::git::git_macros(&dir).unwrap();
+    assert_eq!(macros["__GIT_DIRTY__"], "");
+}
+
+#[test]
+fn line_endings() {
+    let input = "#define Foo Bar\r\nFoo\r\nFoo\n";
+    assert_eq!(
+        crate::process_str(input, &mut crate::Context::new()).unwrap(),
+        "Bar\nBar\n"
+    );
+
+    let mut context = crate::Context::new();
+    context.preserve_line_endings = true;
+    assert_eq!(
+        crate::process_str(input, &mut context).unwrap(),
+        "Bar\r\nBar\n"
+    );
+}
+
+#[test]
+fn missing_newline() {
+    let mut context = crate::Context::new();
+    context.preserve_missing_newline = true;
+    assert_eq!(
+        crate::process_str("One\nTwo", &mut context).unwrap(),
+        "One\nTwo"
+    );
+    assert_eq!(
+        crate::process_str("One\nTwo\n", &mut context).unwrap(),
+        "One\nTwo\n"
+    );
+}
+
+#[test]
+fn bom() {
+    let input = "\u{feff}#define Foo Bar\nFoo\n";
+    assert_eq!(
+        crate::process_str(input, &mut crate::Context::new()).unwrap(),
+        "Bar\n"
+    );
+
+    let mut context = crate::Context::new();
+    context.preserve_bom = true;
+    assert_eq!(
+        crate::process_str(input, &mut context).unwrap(),
+        "\u{feff}Bar\n"
+    );
+}
+
 #[test]
 fn define() {
     assert_eq!(
@@ -32,6 +184,291 @@ fn define() {
     );
 }
 
+#[test]
+fn process_line_into() {
+    let mut context = crate::Context::new();
+    let mut output = String::from("start\n");
+    crate::process_line_into("#define A 1", &mut context, &mut output).unwrap();
+    crate::process_line_into("A 2", &mut context, &mut output).unwrap();
+    assert!(crate::process_line_into("#bad", &mut context, &mut output).is_err());
+    assert_eq!(output, "start\n1 2\n");
+}
+
+#[test]
+fn changing_macros() {
+    let mut context = crate::Context::new();
+    assert_eq!(
+        crate::process_str(
+            "#define A 1\nA B\n#undef A\n#define B 2\nA B\n",
+            &mut context
+        )
+        .unwrap(),
+        "1 B\nA 2\n"
+    );
+    context.macros.clear();
+    context.macros.insert("C".to_owned(), "3".to_owned());
+    assert_eq!(crate::process_str("B C", &mut context).unwrap(), "B 3\n");
+}
+
+#[test]
+fn namespaces() {
+    assert_eq!(
+        crate::process_str(
+            "#define theme.color blue\n#define theme.dark.color black\n#define theme light\n#define color red\ntheme.color theme.dark.color theme. color.\n#using theme\ncolor\n#undef theme.*\ntheme.color theme\n",
+            &mut crate::Context::new()
+        )
+        .unwrap(),
+        "blue black light. red.\nblue\nlight.blue light\n"
+    );
+    assert_eq!(
+        crate::process_str(
+            "#define a.b ab\n#define b B\n#define c C\na.b c.b b.c x.a.b.y\n",
+            &mut crate::Context::new()
+        )
+        .unwrap(),
+        "ab C.B B.C x.ab.y\n"
+    );
+}
+
+#[test]
+fn switch() {
+    let input = "#switch TARGET\n#case dev staging\ndebug\n#ifdef X\n#endif\n#case prod\nrelease\n#case dev\nagain\n#default\nother\n#endswitch\nend\n";
+    for (target, output) in [
+        (Some("staging"), "debug\nend\n"),
+        (Some("prod"), "release\nend\n"),
+        (Some("test"), "other\nend\n"),
+        (None, "other\nend\n"),
+    ] {
+        let mut context = crate::Context::new();
+        if let Some(target) = target {
+            context
+                .macros
+                .insert("TARGET".to_owned(), target.to_owned());
+        }
+        assert_eq!(crate::process_str(input, &mut context).unwrap(), output);
+    }
+
+    assert_eq!(
+        crate::process_str(
+            "#ifdef X\n#switch A\n#default\nno\n#endswitch\n#default B b\n#endif\nB\n",
+            &mut crate::Context::new()
+        )
+        .unwrap(),
+        "B\n"
+    );
+    assert!(matches!(
+        crate::process_str("#case a", &mut crate::Context::new()),
+        Err(crate::Error::FileError { error, .. })
+            if matches!(*error, crate::Error::UnexpectedCommand { command: "case" })
+    ));
+    assert!(crate::process_str("#switch A\n#case a\n", &mut crate::Context::new()).is_err());
+}
+
+#[test]
+fn default() {
+    let mut context = crate::Context::new();
+    context.macros.insert("Foo".to_string(), "Bar".to_string());
+    assert_eq!(
+        crate::process_str(
+            "#default Foo Baz\n#default Quux Quuz\n#default Empty\nFoo Quux Empty.\n",
+            &mut context
+        )
+        .unwrap(),
+        "Bar Quuz .\n"
+    );
+}
+
+#[test]
+fn append() {
+    assert_eq!(
+        crate::process_str(
+            "#define Foo b\n#append Foo \" c\"\n#prepend Foo a \n#ifdef Bar\n#append Foo d\n#endif\n#append Bar x\nFoo Bar",
+            &mut crate::Context::new()
+        )
+        .unwrap(),
+        "a b c x\n"
+    );
+}
+
+#[test]
+fn capture() {
+    assert_eq!(
+        crate::process_str(
+            "#define Foo Bar\n#capture Outer\n#capture Inner\nFoo\n#endcapture\n[Inner]\n#endcapture\nOuter Outer\n",
+            &mut crate::Context::new()
+        )
+        .unwrap(),
+        "[Bar] [Bar]\n"
+    );
+    assert!(matches!(
+        crate::process_line("#endcapture", &mut crate::Context::new()),
+        Err(crate::Error::UnexpectedCommand {
+            command: "endcapture"
+        })
+    ));
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn capture_input() {
+    assert_eq!(
+        crate::process_str(
+            "#capture Foo\n#in tr a-z A-Z\nhello\n#endin\n#endcapture\n#in cat\n#capture Bar\nworld\n#endcapture\nBar\n#endin\nFoo",
+            &mut crate::Context::new_exec()
+        )
+        .unwrap(),
+        "world\nHELLO\n"
+    );
+}
+
+#[test]
+fn date() {
+    let mut context = crate::Context::new();
+    context.date = Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(951_827_696));
+    assert_eq!(
+        crate::process_str(
+            "#date\n#date %a %e %B %Y, %I:%M:%S %p (day %j) %%\n",
+            &mut context
+        )
+        .unwrap(),
+        "2000-02-29\nTue 29 February 2000, 12:34:56 PM (day 060) %\n"
+    );
+    context.date = Some(std::time::UNIX_EPOCH - std::time::Duration::from_secs(86_400));
+    assert_eq!(
+        crate::process_line("#date %F %T %j %u", &mut context).unwrap(),
+        "1969-12-31 00:00:00 365 3\n"
+    );
+    assert!(matches!(
+        crate::process_line("#date %Q", &mut context),
+        Err(crate::Error::InvalidParameter { .. })
+    ));
+}
+
+#[test]
+fn directive_comment() {
+    let mut context = crate::Context::builder()
+        .directive_comment("//")
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str(
+            "#define Url http://example.com // the homepage\n#ifdef Url //\nUrl\n#endif  // Url\n",
+            &mut context
+        )
+        .unwrap(),
+        "http://example.com\n"
+    );
+    assert!(matches!(
+        crate::process_line("#endif x//y", &mut context),
+        Err(crate::Error::TooManyParameters { command: "endif" })
+    ));
+}
+
+#[test]
+fn pragma() {
+    let mut files = std::collections::HashMap::new();
+    files.insert(
+        "partial".to_owned(),
+        "#pragma delimiters @ @\n#pragma pass_unknown_commands on\nFoo @Foo@\n#unknown Foo\n"
+            .to_owned(),
+    );
+    let mut context = crate::Context::new();
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(files)));
+    assert_eq!(
+        crate::process_str(
+            "#define Foo Bar\n#include partial\nFoo @Foo@\n#pragma delimiters ${ }\nFoo ${Foo}",
+            &mut context
+        )
+        .unwrap(),
+        "Foo Bar\n#unknown Foo\nBar @Bar@\nFoo Bar\n"
+    );
+    assert_eq!(context.delimiters, None);
+
+    for pragma in [
+        "#pragma reindent maybe",
+        "#pragma nothing on",
+        "#pragma delimiters #",
+    ] {
+        assert!(crate::process_line(pragma, &mut context).is_err());
+    }
+}
+
+#[test]
+fn comment() {
+    assert_eq!(
+        crate::process_str(
+            "#comment This is a note\nText\n#// Another note\n",
+            &mut crate::Context::new()
+        )
+        .unwrap(),
+        "Text\n"
+    );
+}
+
+#[test]
+fn block_comment() {
+    assert_eq!(
+        crate::process_str(
+            "One
+#comment
+Two
+#comment
+#bad command
+#endcomment
+#ifdef A
+#endcomment
+Three
+#ifdef A
+#comment
+#endif
+#endcomment
+#endif
+Four",
+            &mut crate::Context::new()
+        )
+        .unwrap(),
+        "One\nThree\nFour\n"
+    );
+    assert!(matches!(
+        crate::process_line("#endcomment", &mut crate::Context::new()),
+        Err(crate::Error::UnexpectedCommand {
+            command: "endcomment"
+        })
+    ));
+}
+
+#[test]
+fn raw() {
+    assert_eq!(
+        crate::process_str(
+            "#define Foo Bar\r\nFoo\n#raw\nFoo\r\n#define Foo Baz\n##\n# endraw\nFoo\n#ifdef A\n#raw\n#endif\n#endraw\n#endif\n#raw\nFoo",
+            &mut crate::Context::new()
+        )
+        .unwrap(),
+        "Bar\nFoo\r\n#define Foo Baz\n##\nBar\nFoo"
+    );
+}
+
+#[test]
+fn unclosed_blocks() {
+    let error = crate::process_str(
+        "#ifdef A\n#endif\n#ifndef A\n#ifdef B\n#endif\n",
+        &mut crate::Context::new(),
+    )
+    .unwrap_err();
+    assert!(matches!(
+        error,
+        crate::Error::FileError { line: 2, error, .. }
+            if matches!(*error, crate::Error::UnclosedBlock { command: "ifndef" })
+    ));
+
+    let mut context = crate::Context::new();
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(
+        std::collections::HashMap::from([("open".to_owned(), "#comment\n".to_owned())]),
+    )));
+    assert!(crate::process_str("#include open\n#endcomment", &mut context).is_err());
+}
+
 #[test]
 fn context() {
     let mut context = crate::Context::new();
@@ -129,64 +566,1608 @@ fn include() {
 
 #[test]
 fn include_dir() {
+    let mut context = crate::Context::new();
     assert_eq!(
-        crate::process_str("#include tests/include.txt", &mut crate::Context::new()).unwrap(),
+        crate::process_str("#include tests/include.txt", &mut context).unwrap(),
         "some text\n"
     );
+    assert_eq!(
+        context.state.dependencies,
+        ["tests/include.txt", "tests/more.txt"].map(std::path::PathBuf::from)
+    );
 }
 
 #[test]
-fn exec() {
+fn include_quoted() {
     assert_eq!(
         crate::process_str(
-            "#exec echo 'Hello there!' | sed 's/there/world/'",
-            &mut crate::Context::new_exec()
+            "#include \"tests/with space.txt\"\n#include tests/with space.txt",
+            &mut crate::Context::new()
         )
         .unwrap(),
-        "Hello world!\n"
+        "spaced\nspaced\n"
     );
 }
 
 #[test]
-fn input() {
+fn include_lines() {
     assert_eq!(
         crate::process_str(
-            "#in sed 's/cat/dog/g'\nI love cats!\n#endin",
-            &mut crate::Context::new_exec()
+            "#include test.txt lines=6..6\n#include \"test.txt\" lines=..2",
+            &mut crate::Context::new()
         )
         .unwrap(),
-        "I love dogs!\n"
+        "no macro\n"
     );
+    assert!(matches!(
+        crate::process_line("#include test.txt lines=3..1", &mut crate::Context::new()),
+        Err(crate::Error::InvalidParameter { .. })
+    ));
 }
 
 #[test]
-fn nested_input() {
+fn include_lines_open_block() {
     assert_eq!(
         crate::process_str(
-            "
-#in sed 's/cat/dog/g'
-I love cats!
-# in head -c4
-cats are great.
-# endin
-#endin",
-            &mut crate::Context::new_exec()
+            "#include test.txt lines=6..\n#include test.txt lines=1..1\nhidden\n",
+            &mut crate::Context::new()
         )
         .unwrap(),
-        "
-I love dogs!
-dogs"
+        "no macro\n"
     );
 }
 
 #[test]
-fn literal_hash() {
+fn include_lines_errors() {
+    let mut context = crate::Context::new();
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(
+        std::collections::HashMap::from([("part".to_owned(), "a\nb\n#bad\nc\n".to_owned())]),
+    )));
+    let error = crate::process_str("#include part lines=2..4\n", &mut context).unwrap_err();
+    match error {
+        crate::Error::FileError { error, .. } => {
+            assert!(matches!(*error, crate::Error::FileError { line: 2, .. }))
+        }
+        _ => panic!("{:?}", error),
+    }
+}
+
+#[test]
+fn include_isolated() {
+    let mut files = std::collections::HashMap::new();
+    files.insert(
+        "partial".to_owned(),
+        "#undef Foo\n#define Baz 1\nFoo Baz\n".to_owned(),
+    );
+    let mut context = crate::Context::new();
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(files)));
     assert_eq!(
-        crate::process_str(" ## literal hash", &mut crate::Context::new()).unwrap(),
-        " ## literal hash\n"
+        crate::process_str(
+            "#define Foo Bar\n#include_isolated partial\nFoo Baz",
+            &mut context
+        )
+        .unwrap(),
+        "Foo 1\nBar Baz\n"
+    );
+}
+
+#[test]
+fn import() {
+    let mut files = std::collections::HashMap::new();
+    files.insert(
+        "lib".to_owned(),
+        "#define Helper h\n#define A a Helper\n#define B b\n#undef C\n#export A B C\n".to_owned(),
     );
+    let mut context = crate::Context::new();
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(files)));
     assert_eq!(
-        crate::process_str("## literal hash", &mut crate::Context::new()).unwrap(),
-        "# literal hash\n"
+        crate::process_str(
+            "#define C c\n#import lib A\nA B C Helper\n#include_isolated lib\nA B C Helper",
+            &mut context
+        )
+        .unwrap(),
+        "a Helper B c Helper\na Helper b C Helper\n"
+    );
+    assert!(matches!(
+        crate::process_line("#import lib Helper", &mut context),
+        Err(crate::Error::InvalidParameter {
+            command: "import",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn include_base64() {
+    let mut files = std::collections::HashMap::new();
+    files.insert("a".to_owned(), "".to_owned());
+    files.insert("b".to_owned(), "M".to_owned());
+    files.insert("c".to_owned(), "Ma".to_owned());
+    files.insert("d".to_owned(), "Man".to_owned());
+    files.insert("e".to_owned(), "hello world".to_owned());
+    let mut context = crate::Context::new();
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(files)));
+    assert_eq!(
+        crate::process_str(
+            "#include_base64 a\n#include_base64 b\n#include_base64 c\n#include_base64 d\n#include_base64 e",
+            &mut context
+        )
+        .unwrap(),
+        "\nTQ==\nTWE=\nTWFu\naGVsbG8gd29ybGQ=\n"
+    );
+}
+
+#[test]
+fn indented_commands() {
+    let mut context = crate::Context::new();
+    assert_eq!(
+        crate::process_str("  #define Foo Bar", &mut context).unwrap(),
+        "  #define Foo Bar\n"
+    );
+
+    context.indented_commands = true;
+    assert_eq!(
+        crate::process_str(
+            "<div>\n  #ifdef Foo\n  <p>Foo</p>\n  #endif\n  #include test.txt\n\t##x\n</div>",
+            &mut context
+        )
+        .unwrap(),
+        "<div>\nno macro\n\t#x\n</div>\n"
+    );
+}
+
+#[test]
+fn reindent() {
+    let mut context = crate::Context::new();
+    context.reindent = true;
+    assert_eq!(
+        crate::process_str(
+            "a:\n  #include test.txt\n\t# define Foo Bar\n  ##Foo\n",
+            &mut context
+        )
+        .unwrap(),
+        "a:\n  no macro\n  #Bar\n"
+    );
+}
+
+#[test]
+fn on_include() {
+    let mut context = crate::Context::new().on_include(|name, context| match name {
+        "generated" => Some(Ok(format!("{} files\n", context.state.dependencies.len()))),
+        "missing" => Some(Err(crate::Error::PipeFailed)),
+        _ => None,
+    });
+    assert_eq!(
+        crate::process_str(
+            "#include generated\n#include tests/more.txt\n#include generated",
+            &mut context
+        )
+        .unwrap(),
+        "0 files\nsome text\n1 files\n"
+    );
+    assert!(matches!(
+        crate::process_line("#include missing", &mut context),
+        Err(crate::Error::PipeFailed)
+    ));
+}
+
+#[test]
+fn trace() {
+    use std::sync::{Arc, Mutex};
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let hook_log = Arc::clone(&log);
+    let mut context = crate::Context::new().on_trace(move |event, _| {
+        hook_log.lock().unwrap().push(event.to_string());
+    });
+    crate::process_str(
+        "#ifdef A\n#ifdef B\n#endif\n#elifdef C\n#else\n#include tests/more.txt\n#endif\n",
+        &mut context,
+    )
+    .unwrap();
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "open <string>",
+            "<string>:0: #ifdef A (not taken)",
+            "<string>:3: #elifdef C (not taken)",
+            "<string>:4: #else (taken)",
+            "<string>:5: #include tests/more.txt",
+            "open tests/more.txt",
+            "close tests/more.txt",
+            "<string>:6: #endif",
+            "close <string>",
+        ]
+    );
+}
+
+#[test]
+fn stats() {
+    let mut context = crate::Context::builder().stats(true).build().unwrap();
+    crate::process_str(
+        "#define A 1\nA A\n#ifdef B\n#include missing.txt\n#endif\n#include tests/more.txt\n",
+        &mut context,
+    )
+    .unwrap();
+    let stats = context.settings.stats.unwrap();
+    assert_eq!(
+        (
+            stats.lines,
+            stats.expansions,
+            stats.includes,
+            stats.commands,
+            stats.bytes
+        ),
+        (7, 2, 1, 4, 14)
+    );
+    let files: Vec<&str> = stats.files.iter().map(|file| &*file.name).collect();
+    assert_eq!(files, ["tests/more.txt", "<string>"]);
+}
+
+#[test]
+fn include_paths() {
+    let mut context = crate::Context::new();
+    context.include_paths.push("tests".into());
+    assert_eq!(
+        crate::process_str("#include include.txt", &mut context).unwrap(),
+        "some text\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn exec() {
+    assert_eq!(
+        crate::process_str(
+            "#exec echo 'Hello there!' | sed 's/there/world/'",
+            &mut crate::Context::new_exec()
+        )
+        .unwrap(),
+        "Hello world!\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn child_failed() {
+    for (input, failed) in [
+        ("#exec true\n#exec exit 3\n", "exit 3"),
+        ("#in cat\nx\n#endin\n#in false\n#endin\n", "false"),
+    ] {
+        match crate::process_str(input, &mut crate::Context::new_exec()) {
+            Err(crate::Error::FileError { error, .. }) => match *error {
+                crate::Error::ChildFailed { command, .. } => assert_eq!(command, failed),
+                error => panic!("unexpected error {:?}", error),
+            },
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
+
+#[test]
+#[cfg(all(feature = "exec", not(target_os = "windows")))]
+fn child_stderr() {
+    for input in [
+        "#exec echo out; echo oops >&2; exit 1\n",
+        "#in cat; echo oops >&2; exit 1\nx\n#endin\n",
+    ] {
+        let error = crate::process_str(input, &mut crate::Context::new_exec()).unwrap_err();
+        assert!(error.to_string().ends_with(":\noops"), "{}", error);
+    }
+    match crate::process_str(
+        "#exec yes error | head -c 100000 >&2; exit 1\n",
+        &mut crate::Context::new_exec(),
+    ) {
+        Err(crate::Error::FileError { error, .. }) => match *error {
+            crate::Error::ChildFailed { stderr, .. } => {
+                assert!(stderr.starts_with("...") && stderr.len() <= 4096 + 3)
+            }
+            error => panic!("unexpected error {:?}", error),
+        },
+        result => panic!("unexpected result {:?}", result),
+    }
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn exec_disabled() {
+    assert!(matches!(
+        crate::process_line("#exec echo hi", &mut crate::Context::new()),
+        Err(crate::Error::ExecDisabled { command: "exec" })
+    ));
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn exec_hook() {
+    let mut context = crate::Context::new_exec().on_exec(|command, input, context| {
+        context.macros.insert("Ran".to_owned(), command.to_owned());
+        match command {
+            "greet" => Some(Ok(format!("Hello {}", input.unwrap_or("world\n")))),
+            _ => None,
+        }
+    });
+    assert_eq!(
+        crate::process_str(
+            "#exec greet\n#in greet\n#in sed 's/cat/dog/'\ncat\n#endin\n#endin\nRan",
+            &mut context
+        )
+        .unwrap(),
+        "Hello world\nHello dog\ngreet\n"
+    );
+}
+
+#[test]
+#[cfg(all(feature = "exec", not(target_os = "windows")))]
+fn interpreter() {
+    assert_eq!(
+        crate::process_str(
+            "#exec:sh echo \"$((1 + 2))\"\n#in:sed -e s/a/b/ -e s/c/d/\nac '$HOME'\n#endin\n",
+            &mut crate::Context::new_exec()
+        )
+        .unwrap(),
+        "3\nbd '$HOME'\n"
+    );
+
+    let mut context = crate::Context::new_exec().on_exec(|command, input, _| {
+        Some(Ok(format!("{}: {}", command, input.unwrap_or_default())))
+    });
+    assert_eq!(
+        crate::process_str(
+            "#exec:python print(1)\n#in:node -\nx\n#endin\n",
+            &mut context
+        )
+        .unwrap(),
+        "python: print(1)\nnode -: x\n"
+    );
+    assert!(crate::process_str("#define:x\n", &mut crate::Context::new_exec()).is_err());
+    assert!(crate::process_str("#exec:\n", &mut crate::Context::new_exec()).is_err());
+    assert!(crate::process_str("#exec:sh echo\n", &mut crate::Context::new()).is_err());
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn include_command() {
+    assert_eq!(
+        crate::process_str(
+            "#define A a\n#include !printf '#define B b\\nA B\\nskipped\\n' lines=..2\nB\n",
+            &mut crate::Context::new_exec()
+        )
+        .unwrap(),
+        "a b\nb\n"
+    );
+    assert!(matches!(
+        crate::process_line("#include !echo hi", &mut crate::Context::new()),
+        Err(crate::Error::ExecDisabled { command: "include" })
+    ));
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn max_exec_output() {
+    let mut context = crate::Context::builder()
+        .exec(true)
+        .max_exec_output(6)
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str("#exec echo hello", &mut context).unwrap(),
+        "hello\n"
+    );
+    for input in ["#exec yes", "#in cat\nlong line\n#endin"] {
+        match crate::process_str(input, &mut context) {
+            Err(crate::Error::FileError { error, .. }) => {
+                assert!(matches!(
+                    *error,
+                    crate::Error::ExecOutputTooLarge { limit: 6 }
+                ))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
+
+#[test]
+#[cfg(all(feature = "exec", not(target_os = "windows")))]
+fn in_large_output() {
+    // More than fits in a pipe, so the output must be read while the input is written.
+    let block = "0123456789\n".repeat(20_000);
+    let input = format!("#in cat\n{}#endin\n", block);
+    assert_eq!(
+        crate::process_str(&input, &mut crate::Context::new_exec()).unwrap(),
+        block
+    );
+    let input = format!("#in head -n 1\n{}#endin\n", block);
+    assert_eq!(
+        crate::process_str(&input, &mut crate::Context::new_exec()).unwrap(),
+        "0123456789\n"
+    );
+    let mut context = crate::Context::builder()
+        .exec(true)
+        .max_exec_output(100)
+        .build()
+        .unwrap();
+    let input = format!("#in cat\n{}#endin\n", block);
+    match crate::process_str(&input, &mut context) {
+        Err(crate::Error::FileError { error, .. }) => assert!(matches!(
+            *error,
+            crate::Error::ExecOutputTooLarge { limit: 100 }
+        )),
+        result => panic!("unexpected result {:?}", result),
+    }
+}
+
+#[test]
+#[cfg(all(feature = "exec", not(target_os = "windows")))]
+fn in_timeout() {
+    let mut context = crate::Context::builder()
+        .exec(true)
+        .in_timeout(std::time::Duration::from_millis(200))
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str("#in cat\nquick\n#endin\n", &mut context).unwrap(),
+        "quick\n"
+    );
+    let start = std::time::Instant::now();
+    match crate::process_str("#in cat; sleep 60\nslow\n#endin\n", &mut context) {
+        Err(crate::Error::FileError { error, .. }) => {
+            assert!(matches!(*error, crate::Error::ChildTimeout { .. }))
+        }
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert!(start.elapsed() < std::time::Duration::from_secs(30));
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn children_killed_on_error() {
+    let mut context = crate::Context::new_exec();
+    for input in [
+        "#in sleep 60\n#nonexistent\n#endin\n",
+        "#in cat\nunclosed\n",
+    ] {
+        assert!(crate::process_str(input, &mut context).is_err());
+        assert!(context.state.in_stack.is_empty());
+    }
+
+    crate::process_line("#in sleep 60", &mut context).unwrap();
+    context.kill_children();
+    assert!(context.state.in_stack.is_empty());
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn expand_exec() {
+    let input = "#define WORD there\n#exec echo 'Hello WORD'\n";
+    assert_eq!(
+        crate::process_str(input, &mut crate::Context::new_exec()).unwrap(),
+        "Hello WORD\n"
+    );
+    let mut context = crate::Context::new_exec();
+    context.expand_exec = true;
+    assert_eq!(
+        crate::process_str(input, &mut context).unwrap(),
+        "Hello there\n"
+    );
+}
+
+#[test]
+#[cfg(all(feature = "exec", not(target_os = "windows")))]
+fn shquote() {
+    let mut context = crate::Context::new_exec();
+    context.expand_exec = true;
+    context.delimiters = Some(crate::Delimiters::new("${", "}"));
+    context
+        .macros
+        .insert("Text".to_owned(), "it's $HOME; echo `id`".to_owned());
+    assert_eq!(
+        crate::process_str("#exec printf '%s' ${Text|shquote}", &mut context).unwrap(),
+        "it's $HOME; echo `id`"
+    );
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn windows_shell() {
+    let mut context = crate::Context::new_exec();
+    context.windows_shell = crate::WindowsShell::PowerShell;
+    let output = crate::process_str("#exec echo a b\n", &mut context).unwrap();
+    // PowerShell's echo writes each argument on its own line; other platforms ignore the setting.
+    if cfg!(target_os = "windows") {
+        assert_eq!(output.replace("\r\n", "\n"), "a\nb\n");
+    } else {
+        assert_eq!(output, "a b\n");
+    }
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn ifexec() {
+    assert_eq!(
+        crate::process_str(
+            "#ifexec true\nyes\n#else\nno\n#endif\n#ifexec exit 3\nyes\n#else\nno\n#endif\n",
+            &mut crate::Context::new_exec()
+        )
+        .unwrap(),
+        "yes\nno\n"
+    );
+    assert!(matches!(
+        crate::process_str("#ifexec true\n#endif\n", &mut crate::Context::new()),
+        Err(crate::Error::FileError { error, .. })
+            if matches!(*error, crate::Error::ExecDisabled { command: "ifexec" })
+    ));
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn dry_run() {
+    let mut context = crate::Context::new();
+    context.dry_run = true;
+    assert_eq!(
+        crate::process_str(
+            "#exec rm -rf /\n#in sed 's/a/b/'\na\n#exec false\n#endin\n#include test.txt\n#exec:sh rm -rf /\n#in:sed s/a/b/\na\n#endin\n",
+            &mut context
+        )
+        .unwrap(),
+        "no macro\n"
+    );
+    assert_eq!(
+        context.state.dry_run_commands,
+        [
+            "rm -rf /",
+            "sed 's/a/b/'",
+            "false",
+            "sh rm -rf /",
+            "sed s/a/b/"
+        ]
+    );
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn input() {
+    assert_eq!(
+        crate::process_str(
+            "#in sed 's/cat/dog/g'\nI love cats!\n#endin",
+            &mut crate::Context::new_exec()
+        )
+        .unwrap(),
+        "I love dogs!\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "exec")]
+fn nested_input() {
+    assert_eq!(
+        crate::process_str(
+            "
+#in sed 's/cat/dog/g'
+I love cats!
+# in head -c4
+cats are great.
+# endin
+#endin",
+            &mut crate::Context::new_exec()
+        )
+        .unwrap(),
+        "
+I love dogs!
+dogs"
+    );
+}
+
+#[test]
+fn literal_hash() {
+    assert_eq!(
+        crate::process_str(" ## literal hash", &mut crate::Context::new()).unwrap(),
+        " ## literal hash\n"
+    );
+    assert_eq!(
+        crate::process_str("## literal hash", &mut crate::Context::new()).unwrap(),
+        "# literal hash\n"
+    );
+}
+
+#[test]
+fn build() {
+    let output = std::env::temp_dir().join("gpp_build_test/output.txt");
+    let builder = crate::Context::builder()
+        .define("A", "defined")
+        .include_dir("tests");
+    let dependencies = crate::build::Gpp::from_builder(builder)
+        .rerun_if_changed(false)
+        .process("test.txt", &output);
+    assert_eq!(dependencies, ["test.txt"].map(std::path::PathBuf::from));
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        "a macro is defined\n"
+    );
+
+    // The settings are checked like those of any other context.
+    let builder = crate::Context::builder().expand_exec(true);
+    assert!(matches!(
+        crate::build::Gpp::from_builder(builder).try_process("test.txt", &output),
+        Err(crate::Error::InvalidSettings { .. })
+    ));
+}
+
+#[test]
+fn write_if_changed() {
+    let output = std::env::temp_dir().join("gpp_write_if_changed_test.txt");
+    let _ = std::fs::remove_file(&output);
+    assert!(crate::build::write_if_changed(&output, "one").unwrap());
+    assert!(!crate::build::write_if_changed(&output, "one").unwrap());
+    assert!(crate::build::write_if_changed(&output, "two").unwrap());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "two");
+}
+
+#[test]
+fn cache() {
+    let dir = std::env::temp_dir().join("gpp_cache_test");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let (input, included, output) = (dir.join("in.txt"), dir.join("inc.txt"), dir.join("out.txt"));
+    std::fs::write(&input, "#include inc.txt\nA\n").unwrap();
+    std::fs::write(&included, "included\n").unwrap();
+
+    let cache = crate::cache::Cache::new(dir.join("cache"));
+    let context = || crate::Context::builder().include_dir(&dir).build().unwrap();
+    assert!(cache.process_file(&input, &output, &mut context()).unwrap());
+    assert!(!cache.process_file(&input, &output, &mut context()).unwrap());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "included\nA\n");
+
+    std::fs::write(&included, "changed\n").unwrap();
+    assert!(cache.process_file(&input, &output, &mut context()).unwrap());
+    assert!(!cache.process_file(&input, &output, &mut context()).unwrap());
+
+    let mut defined = context();
+    defined.macros.insert("A".to_owned(), "B".to_owned());
+    assert!(cache.process_file(&input, &output, &mut defined).unwrap());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "changed\nB\n");
+
+    std::fs::remove_file(&output).unwrap();
+    assert!(cache.process_file(&input, &output, &mut context()).unwrap());
+
+    // Line hooks can change any line, so they stop the cache from skipping files.
+    let hooked = || {
+        crate::Context::builder()
+            .include_dir(&dir)
+            .post_line(|line, _| Some(line.to_uppercase()))
+            .build()
+            .unwrap()
+    };
+    assert!(cache.process_file(&input, &output, &mut hooked()).unwrap());
+    assert!(cache.process_file(&input, &output, &mut hooked()).unwrap());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "CHANGED\nA\n");
+}
+
+#[test]
+fn include_cache() {
+    let dir = std::env::temp_dir().join("gpp_include_cache_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("included.txt");
+    std::fs::write(&file, "VALUE\n").unwrap();
+    let include = format!("#include {}\n", file.display());
+
+    let mut context = crate::Context::builder()
+        .include_cache(true)
+        .build()
+        .unwrap();
+    context.macros.insert("VALUE".to_owned(), "one".to_owned());
+    assert_eq!(
+        crate::process_str(&include.repeat(2), &mut context).unwrap(),
+        "one\none\n"
+    );
+
+    // The file is only read once, so changing it has no effect until the cache is cleared.
+    std::fs::write(&file, "#define VALUE two\nVALUE\n").unwrap();
+    assert_eq!(
+        crate::process_str(
+            &format!("{}#define VALUE three\n{}", include, include),
+            &mut context
+        )
+        .unwrap(),
+        "one\nthree\n"
+    );
+    context.include_cache.as_mut().unwrap().clear();
+    assert_eq!(
+        crate::process_str(&include.repeat(2), &mut context).unwrap(),
+        "two\ntwo\n"
+    );
+    assert_eq!(context.macros["VALUE"], "two");
+
+    // Other paths to the same file share its cache entry.
+    let other = dir
+        .join("..")
+        .join("gpp_include_cache_test")
+        .join("included.txt");
+    std::fs::write(&file, "changed\n").unwrap();
+    assert_eq!(
+        crate::process_str(&format!("#include {}\n", other.display()), &mut context).unwrap(),
+        "two\n"
+    );
+    assert_eq!(context.settings.include_cache.unwrap().files.len(), 1);
+
+    // Each include of a file with an error records the error again.
+    let bad = dir.join("bad.txt");
+    std::fs::write(&bad, "#bad\n").unwrap();
+    let mut context = crate::Context::builder()
+        .include_cache(true)
+        .build()
+        .unwrap();
+    context.error_marker = Some("[{error}]".to_owned());
+    crate::process_str(
+        &format!("#include {}\n", bad.display()).repeat(2),
+        &mut context,
+    )
+    .unwrap();
+    assert_eq!(context.state.recovered_errors.len(), 2);
+}
+
+#[test]
+fn sha256() {
+    assert_eq!(
+        crate::sha256::hash(b""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        crate::sha256::hash(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+    );
+}
+
+#[test]
+fn make_depfile() {
+    let dependencies = ["a b.txt", "$x#1", "dir\\ x", "tab\there"].map(std::path::PathBuf::from);
+    assert_eq!(
+        crate::build::make_depfile("out.txt", &dependencies),
+        "out.txt: \\\n  a\\ b.txt \\\n  $$x\\#1 \\\n  dir\\\\\\ x \\\n  tab\\\there\n"
+    );
+}
+
+#[test]
+fn ninja_dyndep() {
+    let outputs = [
+        ("a.o".into(), vec!["a.c".into(), "c:/x $y".into()]),
+        ("b.o".into(), Vec::new()),
+    ];
+    assert_eq!(
+        crate::build::ninja_dyndep(&outputs),
+        "ninja_dyndep_version = 1\nbuild a.o: dyndep | a.c c$:/x$ $$y\nbuild b.o: dyndep\n"
+    );
+}
+
+#[test]
+fn delimiters() {
+    let mut context = crate::Context::new();
+    context.macros.insert("Foo".to_string(), "Bar".to_string());
+    context
+        .macros
+        .insert("Baz".to_string(), "${Foo}".to_string());
+    context.delimiters = Some(crate::Delimiters::new("${", "}"));
+    assert_eq!(
+        crate::process_str("Foo ${Foo} ${Quux} ${Baz}x ${Foo", &mut context).unwrap(),
+        "Foo Bar ${Quux} Barx ${Foo\n"
+    );
+
+    context.delimiters = Some(crate::Delimiters::new("@", ""));
+    assert_eq!(
+        crate::process_buf_bytes(&b"@Foo\xFF @Foo_ @Foo-@Quux"[..], "<bytes>", &mut context)
+            .unwrap(),
+        b"@Foo\xFF @Foo_ Bar-@Quux\n"
+    );
+}
+
+#[test]
+fn filters() {
+    let mut context = crate::Context::new();
+    context.delimiters = Some(crate::Delimiters::new("${", "}"));
+    context
+        .macros
+        .insert("Name".to_owned(), "  hello World ".to_owned());
+    assert_eq!(
+        crate::process_str(
+            "[${Name|trim|upper}] [${Name|lower}] [${Name|trim|capitalize}] ${Name|nope}",
+            &mut context
+        )
+        .unwrap(),
+        "[HELLO WORLD] [  hello world ] [Hello World] ${Name|nope}\n"
+    );
+    context
+        .macros
+        .insert("Query".to_owned(), "a b&c=d/é~".to_owned());
+    assert_eq!(
+        crate::process_str("/search?q=${Query|urlencode}", &mut context).unwrap(),
+        "/search?q=a%20b%26c%3Dd%2F%C3%A9~\n"
+    );
+}
+
+#[test]
+fn json_filter() {
+    let mut context = crate::Context::new();
+    context.delimiters = Some(crate::Delimiters::new("${", "}"));
+    context
+        .macros
+        .insert("Value".to_owned(), "say \"hi\"\\\n\tdone\u{1}é".to_owned());
+    assert_eq!(
+        crate::process_str("{\"value\": \"${Value|json}\"}", &mut context).unwrap(),
+        "{\"value\": \"say \\\"hi\\\"\\\\\\n\\tdone\\u0001é\"}\n"
+    );
+}
+
+#[test]
+fn escape_html() {
+    let mut context = crate::Context::new();
+    context.delimiters = Some(crate::Delimiters::new("${", "}"));
+    context
+        .macros
+        .insert("Title".to_owned(), "<b>\"Tom\" & 'Jerry'</b>".to_owned());
+    assert_eq!(
+        crate::process_str(
+            "<h1 title=\"${Title|escape_html}\">\n#escape html\n#capture A\n<i>\n#endcapture\nA & ${Title}\n#endescape\n",
+            &mut context
+        )
+        .unwrap(),
+        "<h1 title=\"&lt;b&gt;&quot;Tom&quot; &amp; &#39;Jerry&#39;&lt;/b&gt;\">\nA &amp; &lt;b&gt;&quot;Tom&quot; &amp; &#39;Jerry&#39;&lt;/b&gt;\n"
+    );
+    assert_eq!(context.macros["A"], "<i>");
+    assert!(crate::process_str("#escape html\n#endcapture\n", &mut crate::Context::new()).is_err());
+    assert!(matches!(
+        crate::process_line("#escape nope", &mut context),
+        Err(crate::Error::InvalidParameter {
+            command: "escape",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn line_hooks() {
+    let mut context = crate::Context::new()
+        .pre_line(|line, _| match line.trim_end() {
+            "skip" => None,
+            _ => Some(line.replace('%', "#")),
+        })
+        .post_line(|output, context| {
+            context
+                .macros
+                .insert("Last".to_owned(), output.trim_end().to_owned());
+            Some(format!("> {}", output))
+        });
+    assert_eq!(
+        crate::process_str(
+            "%define A a\nA\nskip\n#include test.txt\n#raw\nA\n#endraw\n",
+            &mut context
+        )
+        .unwrap(),
+        "> a\n> a macro is a\n> A\n"
+    );
+    assert_eq!(context.macros["Last"], "A");
+}
+
+#[test]
+fn whitespace_control() {
+    let input = "{\"name\": \"gpp\"\n#-ifdef VERSION\n, \"version\": \"VERSION\"\n#-endif\n}\n";
+    assert_eq!(
+        crate::process_str(input, &mut crate::Context::new()).unwrap(),
+        "{\"name\": \"gpp\"}\n"
+    );
+    let mut context = crate::Context::new();
+    context
+        .macros
+        .insert("VERSION".to_owned(), "1.0".to_owned());
+    assert_eq!(
+        crate::process_str(input, &mut context).unwrap(),
+        "{\"name\": \"gpp\", \"version\": \"1.0\"}\n"
+    );
+    assert_eq!(
+        crate::process_str(
+            "[\r\n#-include- test.txt\n]\n#capture A\nB\n#-endcapture\n(A)\n",
+            &mut crate::Context::new()
+        )
+        .unwrap(),
+        "[no macro]\n(B)\n"
+    );
+}
+
+#[test]
+fn longest_match() {
+    for _ in 0..10 {
+        let mut context = crate::Context::new();
+        for name in ["Foo", "Foo Bar", "Bar", "Baz", "Foo Bar Baz", "Qux"] {
+            context
+                .macros
+                .insert(name.to_owned(), name.to_lowercase().replace(' ', "-"));
+        }
+        assert_eq!(
+            crate::process_str("Bar Foo Bar, Foo Bar Baz FooQux Qux\n", &mut context).unwrap(),
+            "bar foo-bar, foo-bar-baz FooQux qux\n"
+        );
+        assert_eq!(
+            crate::process_buf_bytes(&b"Foo Bar\xFF Foo Bar"[..], "<bytes>", &mut context).unwrap(),
+            b"foo Bar\xFF foo-bar\n"
+        );
+    }
+}
+
+#[test]
+fn precedence() {
+    let input = "#define Foo one\n#define Foo-Bar two\nFoo-Bar\n";
+    assert_eq!(
+        crate::process_str(input, &mut crate::Context::new()).unwrap(),
+        "two\n"
+    );
+
+    let mut context = crate::Context::new();
+    context.precedence = crate::Precedence::DefinitionOrder;
+    assert_eq!(
+        crate::process_str(input, &mut context).unwrap(),
+        "one-Bar\n"
+    );
+    assert_eq!(
+        crate::process_str("#define Foo three\nFoo-Bar\n", &mut context).unwrap(),
+        "two\n"
+    );
+    assert_eq!(context.definition_order(), ["Foo-Bar", "Foo"]);
+
+    let mut context = crate::Context::new();
+    context.precedence = crate::Precedence::Priority([("Foo".to_owned(), 1)].into());
+    assert_eq!(
+        crate::process_str(input, &mut context).unwrap(),
+        "one-Bar\n"
+    );
+}
+
+#[test]
+fn let_expressions() {
+    use crate::expr::ExprError;
+
+    let mut context = crate::Context::new();
+    assert_eq!(
+        crate::process_str(
+            "#define WIDTH 80\n#define theme.name dark\n#let HALF = WIDTH / 2\n#let LABEL = \"w: \" + (HALF * 2 + -1) % 7 + \"px \\\"\" + theme.name\nHALF LABEL\n",
+            &mut context
+        )
+        .unwrap(),
+        "40 w: 2px \"dark\n"
+    );
+    for (invalid, expected) in [
+        ("X = 1 / 0", ExprError::DivisionByZero),
+        ("X = \"a\" * 2", ExprError::NotAnInteger { operator: "*" }),
+        (
+            "X = Undefined",
+            ExprError::UndefinedMacro("Undefined".to_owned()),
+        ),
+        ("X = (1", ExprError::UnexpectedEnd),
+        ("X = 1 2", ExprError::UnexpectedToken("2".to_owned())),
+        (
+            &format!("X = {}1", "(".repeat(200_000)),
+            ExprError::TooComplex,
+        ),
+        (
+            &format!("X = {}1", "-".repeat(200_000)),
+            ExprError::TooComplex,
+        ),
+        (
+            &format!("X = 1{}", " + 1".repeat(200_000)),
+            ExprError::TooComplex,
+        ),
+    ] {
+        assert!(matches!(
+            crate::process_line(&format!("#let {}", invalid), &mut context),
+            Err(crate::Error::InvalidExpression { command: "let", error }) if error == expected
+        ));
+    }
+    // The most deeply nested expression that can be evaluated.
+    crate::process_line(
+        &format!(
+            "#let X = {}0{}{}",
+            "-(".repeat(32),
+            " + 1".repeat(256),
+            ")".repeat(32)
+        ),
+        &mut context,
+    )
+    .unwrap();
+    assert_eq!(context.macros["X"], "256");
+    assert!(matches!(
+        crate::process_line(
+            &format!("#let X = {}0{}", "(".repeat(65), ")".repeat(65)),
+            &mut context
+        ),
+        Err(crate::Error::InvalidExpression {
+            error: ExprError::TooComplex,
+            ..
+        })
+    ));
+    for invalid in ["X 1", "= 1"] {
+        assert!(matches!(
+            crate::process_line(&format!("#let {}", invalid), &mut context),
+            Err(crate::Error::InvalidParameter { command: "let", .. })
+        ));
+    }
+}
+
+#[test]
+fn if_expressions() {
+    let input = "#if defined(WIDTH) && WIDTH >= 80\nwide\n#elif defined(WIDTH) && !(WIDTH % 2)\neven\n#elifdef WIDTH\nnarrow\n#else\nnone\n#endif\n";
+    for (width, expected) in [
+        (Some("100"), "wide\n"),
+        (Some("40"), "even\n"),
+        (Some("41"), "narrow\n"),
+        (None, "none\n"),
+    ] {
+        let mut context = crate::Context::new();
+        if let Some(width) = width {
+            context.macros.insert("WIDTH".to_owned(), width.to_owned());
+        }
+        assert_eq!(crate::process_str(input, &mut context).unwrap(), expected);
+    }
+
+    let mut context = crate::Context::new();
+    assert_eq!(
+        crate::process_str(
+            "#ifdef Undefined\n#if Undefined == 1\n#endif\n#endif\n#assert \"a\" < \"b\" || 1 / 0\n",
+            &mut context
+        )
+        .unwrap(),
+        ""
+    );
+    assert!(matches!(
+        crate::process_line("#assert 1 + 1 == 3", &mut context),
+        Err(crate::Error::AssertionFailed { expression }) if expression == "1 + 1 == 3"
+    ));
+}
+
+#[test]
+fn c_compat() {
+    let mut context = crate::Context::new();
+    context.c_compat = true;
+    context.include_paths.push("tests".into());
+    assert_eq!(
+        crate::process_str(
+            "#define MAJOR 2\n#define MINOR 1\n#define VERSION (MAJOR * 256 + MINOR)\n#define EMPTY\n#if VERSION >= 0x0200UL && !EMPTY && !UNDEFINED\n#include <include.txt>\n#elif defined MAJOR\nold\n#endif\n",
+            &mut context
+        )
+        .unwrap(),
+        "some text\n"
+    );
+    assert!(crate::process_str("#include <tests/include.txt>", &mut context).is_err());
+
+    let mut context = crate::Context::new();
+    assert!(crate::process_str("#if UNDEFINED\n#endif\n", &mut context).is_err());
+    assert_eq!(
+        crate::process_str(
+            "#pragma c_compat on\n#if !UNDEFINED\nyes\n#endif\n",
+            &mut context
+        )
+        .unwrap(),
+        "yes\n"
+    );
+    assert!(!context.c_compat);
+    assert_eq!(
+        crate::process_str(
+            "#pragma c_compat on\n#if 010 == 8 && 0 == 0 && 0x10 == 16\noctal\n#endif\n",
+            &mut context
+        )
+        .unwrap(),
+        "octal\n"
+    );
+    assert_eq!(
+        crate::process_str("#if 010 == 10\ndecimal\n#endif\n", &mut context).unwrap(),
+        "decimal\n"
+    );
+    assert!(crate::process_str("#pragma c_compat on\n#if 08\n#endif\n", &mut context).is_err());
+    assert!(crate::process_str("#pragma once\n", &mut context).is_err());
+
+    context.macros.insert("A".to_owned(), "A + 1".to_owned());
+    assert!(matches!(
+        crate::process_str("#pragma c_compat on\n#if A\n#endif\n", &mut context),
+        Err(crate::Error::FileError { error, .. }) if matches!(
+            *error,
+            crate::Error::InvalidExpression { error: crate::expr::ExprError::TooDeep, .. }
+        )
+    ));
+}
+
+#[test]
+fn c_compat_files() {
+    let dir = std::env::temp_dir().join("gpp_c_compat_test");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(
+        dir.join("sub/main.h"),
+        "#include \"local.h\"\n#include \"local.h\"\n#include <local.h>\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("sub/local.h"), "#pragma once\nnext to main.h\n").unwrap();
+    std::fs::write(dir.join("local.h"), "in the include path\n").unwrap();
+
+    for include_cache in [false, true] {
+        let mut context = crate::Context::builder()
+            .c_compat(true)
+            .include_dir(&dir)
+            .include_cache(include_cache)
+            .build()
+            .unwrap();
+        assert_eq!(
+            crate::process_file(dir.join("sub/main.h").to_str().unwrap(), &mut context).unwrap(),
+            "next to main.h\nin the include path\n"
+        );
+        assert!(context.state.file_stack.is_empty());
+    }
+}
+
+#[test]
+fn classic_gpp() {
+    let mut context = crate::Context::builder()
+        .classic_gpp(
+            Some(crate::Delimiters::new("<#", ">")),
+            crate::Delimiters::new("<#", ">"),
+        )
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str(
+            "<#define NAME World>\n  <#ifdef NAME>\n<#NAME>\n# <#NAME>\n  <#endif>\n",
+            &mut context
+        )
+        .unwrap(),
+        "World\n# World\n"
+    );
+    // GPP's commands that gpp doesn't have are output as text.
+    assert_eq!(
+        crate::process_str("<#ifeq NAME World>\n<#eval 1+1>\n", &mut context).unwrap(),
+        "<#ifeq NAME World>\n<#eval 1+1>\n"
+    );
+
+    let mut context = crate::Context::builder()
+        .classic_gpp(None, crate::Delimiters::new("#", ""))
+        .include_dir("tests")
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str(
+            "#define X 1\n#if X && !Y\n#include <include.txt>\n#endif\nX\n",
+            &mut context
+        )
+        .unwrap(),
+        "some text\n1\n"
+    );
+}
+
+#[test]
+fn builder_validation() {
+    let invalid = |builder: crate::ContextBuilder| match builder.build() {
+        Err(crate::Error::InvalidSettings { reason }) => reason,
+        result => panic!("{:?}", result),
+    };
+    let builder = crate::Context::builder;
+
+    assert_eq!(
+        invalid(builder().delimiters(crate::Delimiters::new("", "}"))),
+        "the macro delimiter prefix is empty"
+    );
+    assert_eq!(
+        invalid(builder().delimiters(crate::Delimiters::new("#", ""))),
+        "the macro delimiter prefix '#' would be read as a command"
+    );
+    assert_eq!(
+        invalid(
+            builder()
+                .command_delimiters(crate::Delimiters::new("@", ""))
+                .delimiters(crate::Delimiters::new("@", "@"))
+        ),
+        "the macro delimiter prefix '@' would be read as a command"
+    );
+    assert_eq!(
+        invalid(builder().command_delimiters(crate::Delimiters::new("", "-->"))),
+        "the command prefix is empty"
+    );
+    assert_eq!(
+        invalid(builder().directive_comment("")),
+        "the directive comment marker is empty"
+    );
+    assert_eq!(
+        invalid(builder().macro_escape("")),
+        "the macro escape marker is empty"
+    );
+    assert_eq!(
+        invalid(builder().expand_exec(true)),
+        "macros in exec commands are expanded but exec commands aren't allowed"
+    );
+    assert_eq!(
+        invalid(builder().in_timeout(std::time::Duration::ZERO)),
+        "the #in timeout is zero"
+    );
+    assert_eq!(
+        invalid(builder().include_alias("@root/docs", "docs")),
+        "the include alias '@root/docs' isn't a single path component"
+    );
+    assert_eq!(
+        invalid(builder().include_alias("", "docs")),
+        "the include alias '' isn't a single path component"
+    );
+
+    let context = builder()
+        .exec(true)
+        .expand_exec(true)
+        .in_timeout(std::time::Duration::from_secs(1))
+        .include_alias("@root", "docs")
+        .command_delimiters(crate::Delimiters::new("<!--#", "-->"))
+        .delimiters(crate::Delimiters::new("${", "}"))
+        .macro_escape("\\")
+        .build()
+        .unwrap();
+    assert!(context.expand_exec);
+    assert_eq!(context.include_aliases[0].0, "@root");
+}
+
+#[test]
+fn line_continuation() {
+    let mut context = crate::Context::builder()
+        .line_continuation(true)
+        .define("Foo", "Bar")
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str(
+            "a \\\nFoo \\\r\nb\n#define A \\\nA\n#raw\nc\\\n#endraw\n",
+            &mut context
+        )
+        .unwrap(),
+        "a Bar b\n\\\nc\\\n"
+    );
+    assert!(matches!(
+        crate::process_str("a\\\nb\n#bad\n", &mut context),
+        Err(crate::Error::FileError { line: 2, .. })
+    ));
+
+    let mut context = crate::Context::new();
+    assert_eq!(
+        crate::process_str("a\\\nb\n", &mut context).unwrap(),
+        "a\\\nb\n"
+    );
+}
+
+#[test]
+fn mode() {
+    let mut files = std::collections::HashMap::new();
+    files.insert(
+        "percent.txt".to_owned(),
+        "<!--#mode %-->\n%define B 2\n".to_owned(),
+    );
+    let mut context = crate::Context::new();
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(files)));
+    assert_eq!(
+        crate::process_str(
+            "#mode %\n# A\n%define A 1\n%%A\n%mode <!--# -->\n<!--#ifdef A -->\nA\n<!--#endif-->\n<!--#include percent.txt -->\n<!--#mode default-->\n#define C B\nC\n",
+            &mut context
+        )
+        .unwrap(),
+        "# A\n%1\n1\n2\n"
+    );
+    assert!(context.command_delimiters.is_none());
+    assert!(crate::process_str("#mode\n", &mut context).is_err());
+
+    let mut context = crate::Context::builder()
+        .command_delimiters(crate::Delimiters::new("%", ""))
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str("%define A 1\n#A\n", &mut context).unwrap(),
+        "#1\n"
+    );
+    assert!(crate::process_str("%ifdef A\n", &mut context).is_err());
+    assert!(crate::Context::builder()
+        .command_delimiters(crate::Delimiters::new("", ""))
+        .build()
+        .is_err());
+}
+
+#[test]
+fn command_spacing() {
+    let mut context = crate::Context::new();
+    assert_eq!(
+        crate::process_str("#\t define   A 1\nA\n", &mut context).unwrap(),
+        "1\n"
+    );
+    assert!(crate::process_str("#define\tB 2\n", &mut context).is_err());
+
+    context.command_spacing = crate::CommandSpacing::Tabs;
+    assert_eq!(
+        crate::process_str("#  define\tB 2\n#ifdef\tB\nB\n#endif\n", &mut context).unwrap(),
+        "2\n"
+    );
+
+    assert_eq!(
+        crate::process_str(
+            "#pragma command_spacing strict\n#define C 3\nC\n",
+            &mut context
+        )
+        .unwrap(),
+        "3\n"
+    );
+    assert!(
+        crate::process_str("#pragma command_spacing strict\n# define D\n", &mut context).is_err()
+    );
+    assert_eq!(context.command_spacing, crate::CommandSpacing::Tabs);
+}
+
+#[test]
+fn languages() {
+    let html = crate::lang::from_name("html").unwrap();
+    assert_eq!(crate::lang::from_extension(".htm"), Some(html));
+    assert_eq!(crate::lang::from_path("docs/index.HTML"), Some(html));
+    assert_eq!(
+        crate::lang::from_path("config.h.in"),
+        crate::lang::from_name("c")
+    );
+    assert_eq!(crate::lang::from_path("Makefile"), None);
+
+    let mut context = crate::Context::builder()
+        .command_delimiters(html.command_delimiters())
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str(
+            "<!--#define TITLE Home -->\n# TITLE\n<!--#<!--# comment -->\n",
+            &mut context
+        )
+        .unwrap(),
+        "# Home\n<!--# comment -->\n"
+    );
+
+    for language in crate::lang::LANGUAGES {
+        assert!(crate::Context::builder()
+            .command_delimiters(language.command_delimiters())
+            .build()
+            .is_ok());
+    }
+}
+
+#[test]
+fn macro_escape() {
+    let mut context = crate::Context::builder()
+        .macro_escape("\\")
+        .define("Foo", "Bar")
+        .define("Baz", "\\Foo")
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str("Foo \\Foo \\n Baz \\Fooo\n", &mut context).unwrap(),
+        "Bar Foo \\n Foo \\Fooo\n"
+    );
+    assert_eq!(
+        crate::process_line_bytes(b"\\Foo Foo \xE9", &mut context).unwrap(),
+        b"Foo Bar \xE9\n"
+    );
+
+    context.delimiters = Some(crate::Delimiters::new("${", "}"));
+    assert_eq!(
+        crate::process_str("${Foo} \\${Foo|upper} \\${Qux}\n", &mut context).unwrap(),
+        "Bar ${Foo|upper} \\${Qux}\n"
+    );
+
+    let mut context = crate::Context::new();
+    context.macros.insert("Foo".to_owned(), "Bar".to_owned());
+    assert_eq!(
+        crate::process_str("#pragma macro_escape ``\n``Foo\n", &mut context).unwrap(),
+        "Foo\n"
+    );
+    assert_eq!(
+        crate::process_str("\\Foo\n", &mut context).unwrap(),
+        "\\Bar\n"
+    );
+}
+
+#[test]
+fn macro_queries() {
+    let mut context = crate::Context::new();
+    crate::process_str(
+        "#define a.x 1\n#define a 0\n#define ab 2\n#define a.y\n",
+        &mut context,
+    )
+    .unwrap();
+    assert!(context.is_defined("a.y"));
+    assert!(!context.is_defined("b"));
+    assert_eq!(
+        context.macros_with_prefix("a.").collect::<Vec<_>>(),
+        [("a.x", "1"), ("a.y", "")]
+    );
+    assert_eq!(
+        context
+            .sorted_macros()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>(),
+        ["a", "a.x", "a.y", "ab"]
+    );
+}
+
+#[test]
+fn merge() {
+    let base = || {
+        let mut context = crate::Context::from_macros([
+            ("A".to_owned(), "1".to_owned()),
+            ("B".to_owned(), "2".to_owned()),
+        ]);
+        context.include_paths.push("tests".into());
+        context
+    };
+    let other = || {
+        let mut context = crate::Context::from_macros([
+            ("B".to_owned(), "3".to_owned()),
+            ("C".to_owned(), "4".to_owned()),
+        ]);
+        context.include_paths = vec!["tests".into(), "src".into()];
+        context
+    };
+
+    let mut context = base();
+    context.merge(other(), crate::MergePolicy::Keep).unwrap();
+    assert_eq!(
+        context.sorted_macros().collect::<Vec<_>>(),
+        [("A", "1"), ("B", "2"), ("C", "4")]
+    );
+    assert_eq!(
+        context.include_paths,
+        [std::path::Path::new("tests"), std::path::Path::new("src")]
+    );
+
+    let mut context = base();
+    context
+        .merge(other(), crate::MergePolicy::Overwrite)
+        .unwrap();
+    assert_eq!(context.macros["B"], "3");
+
+    let mut context = base();
+    assert!(matches!(
+        context.merge(other(), crate::MergePolicy::Error),
+        Err(crate::Error::MacroConflict { name }) if name == "B"
+    ));
+    assert!(!context.is_defined("C"));
+    context.merge(base(), crate::MergePolicy::Error).unwrap();
+}
+
+#[test]
+fn fork() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<crate::Context>();
+
+    let mut context = crate::Context::builder()
+        .define("A", "1")
+        .stats(true)
+        .post_line(|line, _| Some(line.to_uppercase()))
+        .build()
+        .unwrap();
+    crate::process_str("#ifdef A\n#capture B\na\n", &mut context).unwrap_err();
+    assert_eq!(context.state.if_depth, 1);
+
+    let mut forked = context.fork();
+    assert_eq!(forked.state.if_depth, 0);
+    assert!(forked.state.capture_stack.is_empty());
+    assert_eq!(forked.stats, Some(crate::Stats::default()));
+    assert_eq!(crate::process_str("A b\n", &mut forked).unwrap(), "1 B\n");
+}
+
+#[test]
+fn error_code() {
+    let error = crate::process_str("a\n#bad\n", &mut crate::Context::new()).unwrap_err();
+    assert_eq!(error.code(), "invalid_command");
+    assert_eq!(
+        crate::Error::AssertionFailed {
+            expression: "0".to_owned()
+        }
+        .code(),
+        "assertion_failed"
+    );
+}
+
+#[test]
+#[cfg(feature = "miette")]
+fn source_diagnostic() {
+    use miette::Diagnostic;
+
+    let mut context = crate::Context::new();
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(
+        std::collections::HashMap::from([("inner".to_owned(), "ok\n#bad\n".to_owned())]),
+    )));
+    let error = crate::process_str("a\n#include inner\n", &mut context).unwrap_err();
+    let diagnostic =
+        crate::diagnostic::SourceDiagnostic::new(&error, |filename| match filename.to_str() {
+            Some("inner") => Some("ok\n#bad\n".to_owned()),
+            _ => Some("a\n#include inner\n".to_owned()),
+        });
+    assert_eq!(diagnostic.to_string(), "Invalid command 'bad'");
+    assert_eq!(
+        diagnostic.code().unwrap().to_string(),
+        "gpp::invalid_command"
+    );
+    let label = diagnostic.labels().unwrap().next().unwrap();
+    assert_eq!((label.offset(), label.len()), (3, 4));
+    let included = diagnostic.related().unwrap().next().unwrap();
+    let label = included.labels().unwrap().next().unwrap();
+    assert_eq!((label.offset(), label.len()), (2, 14));
+
+    let diagnostic = crate::diagnostic::SourceDiagnostic::new(&error, |_| None);
+    assert!(diagnostic.labels().is_none());
+}
+
+#[test]
+fn error_marker() {
+    let mut context = crate::Context::new();
+    context.error_marker = Some("[{error}]".to_owned());
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(
+        std::collections::HashMap::from([("inner".to_owned(), "#bad\r\nok\n".to_owned())]),
+    )));
+    assert_eq!(
+        crate::process_str(
+            "#define A a\n#nope\nA\n#include inner\n#include missing\n",
+            &mut context
+        )
+        .unwrap(),
+        "[Error in <string>:1: Invalid command 'nope']\na\n[Error in inner:0: Invalid command 'bad']\nok\n[Error in <string>:4: I/O Error: missing not found]\n"
+    );
+    let codes: Vec<_> = context
+        .state
+        .recovered_errors
+        .iter()
+        .map(crate::Error::code)
+        .collect();
+    assert_eq!(codes, ["invalid_command", "invalid_command", "io_error"]);
+
+    assert_eq!(
+        crate::process_buf_bytes(&b"#bad\n\xff\n"[..], "<bytes>", &mut context).unwrap(),
+        b"[Error in <bytes>:0: Invalid command 'bad']\n\xff\n"
+    );
+}
+
+#[test]
+fn stdin_name() {
+    assert_eq!(crate::Context::new().stdin_name(), "<stdin>");
+    let context = crate::Context::builder()
+        .stdin_name("page.txt")
+        .build()
+        .unwrap();
+    assert_eq!(context.stdin_name(), "page.txt");
+    assert_eq!(context.fork().stdin_name(), "page.txt");
+}
+
+#[test]
+fn include_chain() {
+    let mut context = crate::Context::new();
+    context.resolver = Some(std::sync::Arc::new(std::sync::Mutex::new(
+        std::collections::HashMap::from([
+            ("b".to_owned(), "\n#include c\n".to_owned()),
+            ("c".to_owned(), "ok\n#bad\n".to_owned()),
+        ]),
+    )));
+    let error = crate::process_str("#include b\n", &mut context).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "In file included from <string>:0, from b:1: Error in c:1: Invalid command 'bad'"
+    );
+    match error {
+        crate::Error::FileError { filename, .. } => {
+            assert_eq!(filename, std::path::Path::new("<string>"))
+        }
+        _ => panic!("{:?}", error),
+    }
+}
+
+#[test]
+fn include_aliases() {
+    let dir = std::env::current_dir().unwrap().join("tests");
+    let mut context = crate::Context::builder()
+        .include_alias("@tests", &dir)
+        .build()
+        .unwrap();
+    assert_eq!(
+        crate::process_str("#include @tests/include.txt\n", &mut context).unwrap(),
+        crate::process_str("#include tests/include.txt\n", &mut crate::Context::new()).unwrap()
     );
+    assert_eq!(context.state.dependencies[0], dir.join("include.txt"));
+    assert!(crate::process_str("#include @other/include.txt\n", &mut context).is_err());
 }
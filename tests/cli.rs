@@ -0,0 +1,253 @@
+#![cfg(feature = "clap")]
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// Create an empty directory for a test to work in.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("gpp_cli_{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run gpp in `dir` with the given arguments and standard input.
+fn gpp(dir: &PathBuf, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_gpp"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+#[cfg(unix)]
+fn recursive_symlink_cycle() {
+    let dir = test_dir("recursive_symlink_cycle");
+    fs::create_dir(dir.join("src")).unwrap();
+    fs::write(dir.join("src/page.txt"), "page\n").unwrap();
+    std::os::unix::fs::symlink("..", dir.join("src/parent")).unwrap();
+
+    let output = gpp(&dir, &["-r", "--output-dir", "out", "src"], "");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(dir.join("out/page.txt")).unwrap(),
+        "page\n"
+    );
+    assert!(!dir.join("out/parent").exists());
+}
+
+#[test]
+fn encodings() {
+    let dir = test_dir("encodings");
+    let utf16: Vec<u8> = "\u{feff}#define A Ä\nA\n"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    fs::write(dir.join("utf16.txt"), &utf16).unwrap();
+    fs::write(dir.join("latin1.txt"), b"#include included.txt\ncaf\xE9\n").unwrap();
+    fs::write(dir.join("included.txt"), b"na\xEFve\n").unwrap();
+
+    // The byte order mark is noticed without --encoding.
+    let output = gpp(&dir, &["utf16.txt"], "");
+    assert!(output.status.success(), "{:?}", output);
+    let expected: Vec<u8> = "\u{feff}Ä\n"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    assert_eq!(output.stdout, expected);
+
+    let output = gpp(
+        &dir,
+        &["--encoding", "latin1.*=latin1", "utf16.txt", "latin1.txt"],
+        "",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert!(output.stdout.ends_with(b"na\xEFve\ncaf\xE9\n"));
+}
+
+#[test]
+#[cfg(feature = "git")]
+fn git_macros() {
+    let dir = test_dir("git_macros");
+    for (repo, branch) in [("a", "first"), ("b", "second")] {
+        let repo = dir.join(repo);
+        fs::create_dir(&repo).unwrap();
+        fs::write(repo.join("page.txt"), "__GIT_BRANCH__\n").unwrap();
+        for args in [
+            &["init", "-q"][..],
+            &["symbolic-ref", "HEAD", &format!("refs/heads/{}", branch)],
+            &["add", "page.txt"],
+            &["commit", "-q", "-m", "Page"],
+        ] {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(&repo)
+                .args(["-c", "user.name=gpp", "-c", "user.email=gpp@example.com"])
+                .args(["-c", "commit.gpgsign=false"])
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+    }
+
+    let output = gpp(&dir, &["--git-macros", "a/page.txt", "b/page.txt"], "");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"first\nsecond\n");
+}
+
+#[test]
+fn keep_going_output() {
+    let dir = test_dir("keep_going_output");
+    fs::write(dir.join("good.txt"), "good\n").unwrap();
+    fs::write(dir.join("out.txt"), "old\n").unwrap();
+
+    let output = gpp(
+        &dir,
+        &[
+            "-k",
+            "-o",
+            "out.txt",
+            "--depfile",
+            "out.d",
+            "--fingerprint",
+            "out.sha256",
+            "good.txt",
+            "missing.txt",
+        ],
+        "",
+    );
+    assert!(!output.status.success(), "{:?}", output);
+    assert_eq!(fs::read_to_string(dir.join("out.txt")).unwrap(), "old\n");
+    assert!(!dir.join("out.d").exists());
+    assert!(!dir.join("out.sha256").exists());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+}
+
+#[test]
+fn conflicting_outputs() {
+    let dir = test_dir("conflicting_outputs");
+    fs::create_dir(dir.join("a")).unwrap();
+    fs::create_dir(dir.join("b")).unwrap();
+    fs::write(dir.join("a/page.txt"), "a\n").unwrap();
+    fs::write(dir.join("b/page.txt"), "b\n").unwrap();
+    fs::write(dir.join("page.txt.in"), "in\n").unwrap();
+
+    let output = gpp(
+        &dir,
+        &["--output-dir", "out", "a/page.txt", "b/page.txt"],
+        "",
+    );
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("would both be written to"));
+    assert!(!dir.join("out").exists());
+
+    // The template extension is removed, so the output is not the input.
+    let output = gpp(&dir, &["--output-dir", ".", "page.txt.in"], "");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(fs::read_to_string(dir.join("page.txt")).unwrap(), "in\n");
+
+    let output = gpp(&dir, &["--output-dir", "a", "./a/page.txt"], "");
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("overwritten by its own output"));
+    assert_eq!(fs::read_to_string(dir.join("a/page.txt")).unwrap(), "a\n");
+}
+
+#[test]
+fn include_stdin() {
+    let dir = test_dir("include_stdin");
+    fs::write(dir.join("page.txt"), "<\n#include -\n>\n").unwrap();
+
+    let output = gpp(&dir, &["page.txt"], "#define A piped\nA\n");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"<\npiped\n>\n");
+
+    // When standard input is the main input too, the rest of it is included.
+    let output = gpp(&dir, &[], "first\n#include -\nrest\n");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"first\nrest\n");
+}
+
+#[test]
+#[cfg(all(feature = "exec", unix))]
+fn child_stderr() {
+    let dir = test_dir("child_stderr");
+    let output = gpp(
+        &dir,
+        &["--allow-exec"],
+        "#in sh -c 'echo warning >&2; cat'\ntext\n#endin\n#exec echo error >&2\n",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"text\n");
+    assert_eq!(output.stderr, b"warning\nerror\n");
+}
+
+#[test]
+fn json_error_input() {
+    let dir = test_dir("json_error_input");
+    let output = gpp(&dir, &["--error-format", "json", "missing.txt"], "");
+    assert!(!output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(r#""file":"missing.txt""#), "{}", stderr);
+    assert!(stderr.contains(r#""code":"io_error""#), "{}", stderr);
+}
+
+#[test]
+fn invalid_settings() {
+    let dir = test_dir("invalid_settings");
+    let output = gpp(&dir, &["--expand-exec"], "text\n");
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("exec commands aren't allowed"));
+}
+
+#[test]
+fn output_pattern() {
+    let dir = test_dir("output_pattern");
+    fs::create_dir(dir.join("src")).unwrap();
+    fs::write(dir.join("src/page.txt"), "#define A b\nA\n").unwrap();
+    fs::write(dir.join("top.md"), "top\n").unwrap();
+
+    let output = gpp(
+        &dir,
+        &[
+            "--output-pattern",
+            "{dir}/{stem}.out.{ext}",
+            "src/page.txt",
+            "top.md",
+        ],
+        "",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(dir.join("src/page.out.txt")).unwrap(),
+        "b\n"
+    );
+    // An input in the current directory has `.` as its directory.
+    assert_eq!(fs::read_to_string(dir.join("top.out.md")).unwrap(), "top\n");
+}
+
+#[test]
+fn files_from_stdin() {
+    let dir = test_dir("files_from_stdin");
+    fs::write(dir.join("a b.txt"), "a\n").unwrap();
+    fs::write(dir.join("c*.txt"), "c\n").unwrap();
+
+    // Names are separated by NUL bytes, so they can contain spaces, and aren't expanded as globs.
+    let output = gpp(&dir, &["--files-from", "-", "-0"], "a b.txt\0c*.txt\0\0");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"a\nc\n");
+}
@@ -0,0 +1,49 @@
+#![cfg(feature = "mdbook")]
+
+use std::process::{Command, Stdio};
+
+use serde_json::{json, Value};
+
+/// Run gpp-mdbook on a book with a single chapter, returning the chapter's processed content.
+fn process_chapter(content: &str) -> String {
+    let book_context = json!({ "root": ".", "config": { "book": {}, "preprocessor": {} } });
+    let book = json!({
+        "items": [{
+            "Chapter": {
+                "name": "Chapter",
+                "content": content,
+                "path": "chapter.md",
+                "sub_items": [],
+            },
+        }],
+    });
+    let mut child = Command::new(env!("CARGO_BIN_EXE_gpp-mdbook"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    serde_json::to_writer(child.stdin.take().unwrap(), &json!([book_context, book])).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let book: Value = serde_json::from_slice(&output.stdout).unwrap();
+    book["items"][0]["Chapter"]["content"]
+        .as_str()
+        .unwrap()
+        .to_owned()
+}
+
+#[test]
+fn headings() {
+    assert_eq!(
+        process_chapter("#define NAME gpp\n# About NAME\n## Usage\n#!/bin/sh\n"),
+        "# About gpp\n## Usage\n#!/bin/sh\n"
+    );
+}
+
+#[test]
+fn raw_headings() {
+    assert_eq!(
+        process_chapter("#raw\n# Heading\n#define A\n#endraw\n# After\n"),
+        "# Heading\n#define A\n# After\n"
+    );
+}